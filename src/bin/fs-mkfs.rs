@@ -0,0 +1,105 @@
+use file_sys::core::fs::{FormatOptions, System};
+use file_sys::core::hardware::{Hardware, BLOCK_SIZE, TOTAL_BLOCKS};
+use file_sys::core::superblock::SuperBlock;
+
+const USAGE: &str =
+    "用法: fs-mkfs [-f] [--extents] [--groups] [--blocks <块数>] [--checksums] [--data-checksums]
+              [--reserved <百分比>] [镜像文件]";
+
+fn main() {
+    let mut path = String::from("fs_data");
+    let mut force = false;
+    let mut options = FormatOptions::default();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-f" | "--force" => force = true,
+            "--extents" => options.extents = true,
+            "--checksums" => options.checksums = true,
+            "--data-checksums" => options.data_checksums = true,
+            "--groups" => {
+                options.blocks.get_or_insert(TOTAL_BLOCKS);
+            }
+            "--blocks" => match args.next().and_then(|x| x.parse().ok()) {
+                Some(blocks) => options.blocks = Some(blocks),
+                None => {
+                    eprintln!("--blocks 需要一个整数\n{}", USAGE);
+                    std::process::exit(2);
+                }
+            },
+            "--reserved" => match args.next().and_then(|x| x.parse().ok()) {
+                Some(percent) => options.reserved_percent = percent,
+                None => {
+                    eprintln!("--reserved 需要一个百分比\n{}", USAGE);
+                    std::process::exit(2);
+                }
+            },
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ if arg.starts_with('-') => {
+                eprintln!("未知选项 {}\n{}", arg, USAGE);
+                std::process::exit(2);
+            }
+            _ => path = arg,
+        }
+    }
+
+    let hardware = Hardware::load(&path).unwrap_or_else(|e| {
+        eprintln!("{}: {}", path, e);
+        std::process::exit(1);
+    });
+    let data = hardware.read_all().unwrap_or_else(|e| {
+        eprintln!("{}: {}", path, e);
+        std::process::exit(1);
+    });
+    // 主超级块损坏时镜像中仍可能有用户数据，因此只要不是全零就需要 -f
+    if !force && data.iter().any(|x| *x != 0) {
+        if SuperBlock::from_block_bytes(&data[..BLOCK_SIZE]).is_initialized() {
+            eprintln!("{} 已包含文件系统，使用 -f 强制重新格式化", path);
+        } else {
+            eprintln!("{} 不是空白镜像，使用 -f 强制重新格式化", path);
+        }
+        std::process::exit(1);
+    }
+
+    let mut fs = match System::format(hardware, options) {
+        Ok(fs) => fs,
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = fs.sync() {
+        eprintln!("{}: {}", path, e);
+        std::process::exit(1);
+    }
+
+    let mut mapping = String::from(if options.extents {
+        "区段映射"
+    } else {
+        "块指针映射"
+    });
+    if options.checksums {
+        mapping.push_str("，元数据校验和");
+    }
+    if options.data_checksums {
+        mapping.push_str("，数据块校验和");
+    }
+    if options.reserved_percent > 0 {
+        mapping.push_str(&format!("，保留 {}% 的块", options.reserved_percent));
+    }
+    if fs.layout.grouped {
+        println!(
+            "已格式化 {}（{}，{} 块，{} 个块组）",
+            path,
+            mapping,
+            fs.layout.total_blocks,
+            fs.layout.groups.len()
+        );
+    } else {
+        println!("已格式化 {}（{}）", path, mapping);
+    }
+}
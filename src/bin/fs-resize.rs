@@ -0,0 +1,53 @@
+use file_sys::core::error::FsError;
+use file_sys::core::fs::System;
+use file_sys::core::hardware::Hardware;
+
+const USAGE: &str = "用法: fs-resize <镜像文件> <块数>";
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (path, blocks) = match args.as_slice() {
+        [flag] if flag == "-h" || flag == "--help" => {
+            println!("{}", USAGE);
+            return;
+        }
+        [path, blocks] => match blocks.parse::<usize>() {
+            Ok(blocks) => (path, blocks),
+            Err(_) => {
+                eprintln!("块数需要是一个整数\n{}", USAGE);
+                std::process::exit(2);
+            }
+        },
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    if std::fs::metadata(path).is_err() {
+        eprintln!("找不到镜像文件 {}", path);
+        std::process::exit(1);
+    }
+
+    let fail = |e: &dyn std::fmt::Display| -> ! {
+        eprintln!("{}: {}", path, e);
+        std::process::exit(1);
+    };
+    let hardware = Hardware::load(path).unwrap_or_else(|e| fail(&e));
+    let mut fs = System::init(hardware).unwrap_or_else(|e| fail(&e));
+    // 退出时不会写回，空白镜像保持原样
+    if !fs.initialized {
+        fail(&FsError::Unformatted);
+    }
+    let old = fs.layout.total_blocks;
+    let total = fs.resize(blocks).unwrap_or_else(|e| fail(&e));
+    fs.sync().unwrap_or_else(|e| fail(&e));
+
+    println!(
+        "{}: {} 块 -> {} 块（{} 个块组）",
+        path,
+        old,
+        total,
+        fs.layout.groups.len()
+    );
+}
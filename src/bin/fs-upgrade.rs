@@ -0,0 +1,32 @@
+use file_sys::core::hardware::Hardware;
+use file_sys::core::superblock::FORMAT_VERSION;
+use file_sys::core::upgrade;
+
+fn main() {
+    let path = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| String::from("fs_data"));
+
+    if std::fs::metadata(&path).is_err() {
+        eprintln!("找不到镜像文件 {}", path);
+        std::process::exit(1);
+    }
+
+    let mut hardware = Hardware::load(&path).unwrap_or_else(|e| {
+        eprintln!("{}: {}", path, e);
+        std::process::exit(1);
+    });
+
+    match upgrade::upgrade(&mut hardware) {
+        Ok(version) if version == FORMAT_VERSION => {
+            println!("{} 已是最新格式 v{}", path, FORMAT_VERSION);
+        }
+        Ok(version) => {
+            println!("{} 已从 v{} 升级到 v{}", path, version, FORMAT_VERSION);
+        }
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,238 @@
+// AES-256 分组密码，以及加密文件内容用的 XTS 模式和加密文件名用的 CBC-CTS 模式
+const ROUNDS: usize = 14;
+
+const SBOX: [u8; 256] = {
+    let mut sbox = [0; 256];
+    let mut p: u8 = 1;
+    let mut q: u8 = 1;
+    // p 依次乘以3遍历乘法群，q 是 p 的逆元
+    loop {
+        p = p ^ (p << 1) ^ if p & 0x80 != 0 { 0x1b } else { 0 };
+        q ^= q << 1;
+        q ^= q << 2;
+        q ^= q << 4;
+        if q & 0x80 != 0 {
+            q ^= 0x09;
+        }
+        let x = q ^ q.rotate_left(1) ^ q.rotate_left(2) ^ q.rotate_left(3) ^ q.rotate_left(4);
+        sbox[p as usize] = x ^ 0x63;
+        if p == 1 {
+            break;
+        }
+    }
+    sbox[0] = 0x63;
+    sbox
+};
+
+const INV_SBOX: [u8; 256] = {
+    let mut inv = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        inv[SBOX[i] as usize] = i as u8;
+        i += 1;
+    }
+    inv
+};
+
+pub struct Aes256 {
+    round_keys: [[u8; 16]; ROUNDS + 1],
+}
+
+impl Aes256 {
+    pub fn new(key: &[u8; 32]) -> Self {
+        let mut words = [[0u8; 4]; 4 * (ROUNDS + 1)];
+        for (i, word) in key.chunks_exact(4).enumerate() {
+            words[i].copy_from_slice(word);
+        }
+        let mut rcon = 1;
+        for i in 8..words.len() {
+            let mut t = words[i - 1];
+            if i % 8 == 0 {
+                t = [t[1], t[2], t[3], t[0]].map(|x| SBOX[x as usize]);
+                t[0] ^= rcon;
+                rcon = mul(rcon, 2);
+            } else if i % 8 == 4 {
+                t = t.map(|x| SBOX[x as usize]);
+            }
+            for j in 0..4 {
+                words[i][j] = words[i - 8][j] ^ t[j];
+            }
+        }
+
+        let mut round_keys = [[0; 16]; ROUNDS + 1];
+        for (r, round_key) in round_keys.iter_mut().enumerate() {
+            for c in 0..4 {
+                round_key[4 * c..4 * c + 4].copy_from_slice(&words[4 * r + c]);
+            }
+        }
+        Self { round_keys }
+    }
+
+    pub fn encrypt_block(&self, block: &mut [u8]) {
+        xor(block, &self.round_keys[0]);
+        for r in 1..=ROUNDS {
+            for x in block.iter_mut() {
+                *x = SBOX[*x as usize];
+            }
+            shift_rows(block);
+            if r != ROUNDS {
+                mix_columns(block, [2, 3, 1, 1]);
+            }
+            xor(block, &self.round_keys[r]);
+        }
+    }
+
+    pub fn decrypt_block(&self, block: &mut [u8]) {
+        for r in (1..=ROUNDS).rev() {
+            xor(block, &self.round_keys[r]);
+            if r != ROUNDS {
+                mix_columns(block, [14, 11, 13, 9]);
+            }
+            inv_shift_rows(block);
+            for x in block.iter_mut() {
+                *x = INV_SBOX[*x as usize];
+            }
+        }
+        xor(block, &self.round_keys[0]);
+    }
+}
+
+// 每个数据块是一个加密单元，用逻辑块号作为调整值，密文与明文等长
+pub struct Xts {
+    data: Aes256,
+    tweak: Aes256,
+}
+
+impl Xts {
+    pub fn new(data_key: &[u8; 32], tweak_key: &[u8; 32]) -> Self {
+        Self {
+            data: Aes256::new(data_key),
+            tweak: Aes256::new(tweak_key),
+        }
+    }
+
+    pub fn encrypt(&self, unit: u64, data: &mut [u8]) {
+        self.process(unit, data, true);
+    }
+
+    pub fn decrypt(&self, unit: u64, data: &mut [u8]) {
+        self.process(unit, data, false);
+    }
+
+    fn process(&self, unit: u64, data: &mut [u8], encrypt: bool) {
+        let mut tweak = [0; 16];
+        tweak[..8].copy_from_slice(&unit.to_le_bytes());
+        self.tweak.encrypt_block(&mut tweak);
+        for block in data.chunks_exact_mut(16) {
+            xor(block, &tweak);
+            if encrypt {
+                self.data.encrypt_block(block);
+            } else {
+                self.data.decrypt_block(block);
+            }
+            xor(block, &tweak);
+            // 在 GF(2^128) 中乘以 x
+            let carry = tweak[15] >> 7;
+            for i in (1..16).rev() {
+                tweak[i] = (tweak[i] << 1) | (tweak[i - 1] >> 7);
+            }
+            tweak[0] = (tweak[0] << 1) ^ (carry * 0x87);
+        }
+    }
+}
+
+// 初始向量为0的 CBC，最后两块交换并截断（CS3），输入至少16字节
+pub fn cts_encrypt(aes: &Aes256, data: &[u8]) -> Vec<u8> {
+    let mut padded = data.to_vec();
+    padded.resize(data.len().next_multiple_of(16), 0);
+    let mut prev = [0; 16];
+    for block in padded.chunks_exact_mut(16) {
+        xor(block, &prev);
+        aes.encrypt_block(block);
+        prev.copy_from_slice(block);
+    }
+
+    let n = padded.len() / 16;
+    if n == 1 {
+        return padded;
+    }
+    let tail = data.len() - (n - 1) * 16;
+    let mut out = padded[..(n - 2) * 16].to_vec();
+    out.extend_from_slice(&padded[(n - 1) * 16..]);
+    out.extend_from_slice(&padded[(n - 2) * 16..(n - 2) * 16 + tail]);
+    out
+}
+
+pub fn cts_decrypt(aes: &Aes256, data: &[u8]) -> Vec<u8> {
+    let n = data.len().div_ceil(16);
+    let mut blocks = data.to_vec();
+    let mut last = Vec::new();
+    if n > 1 {
+        // 倒数第二块的密文被截断，缺少的部分在最后一块的解密结果中
+        let tail = data.len() - (n - 1) * 16;
+        let mut decrypted = data[(n - 2) * 16..(n - 1) * 16].to_vec();
+        aes.decrypt_block(&mut decrypted);
+        let mut prev = data[(n - 1) * 16..].to_vec();
+        prev.extend_from_slice(&decrypted[tail..]);
+        xor(&mut decrypted, &prev);
+        last = decrypted[..tail].to_vec();
+        blocks.truncate((n - 2) * 16);
+        blocks.extend_from_slice(&prev);
+    }
+
+    let mut prev = [0; 16];
+    for block in blocks.chunks_exact_mut(16) {
+        let cipher: [u8; 16] = block.try_into().unwrap();
+        aes.decrypt_block(block);
+        xor(block, &prev);
+        prev = cipher;
+    }
+    blocks.extend_from_slice(&last);
+    blocks
+}
+
+fn xor(block: &mut [u8], other: &[u8]) {
+    for (x, y) in block.iter_mut().zip(other) {
+        *x ^= y;
+    }
+}
+
+fn mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        a = (a << 1) ^ if a & 0x80 != 0 { 0x1b } else { 0 };
+        b >>= 1;
+    }
+    product
+}
+
+// 状态按列存放：第 c 列第 r 行是 block[r + 4 * c]
+fn shift_rows(block: &mut [u8]) {
+    let old: [u8; 16] = (&*block).try_into().unwrap();
+    for r in 1..4 {
+        for c in 0..4 {
+            block[r + 4 * c] = old[r + 4 * ((c + r) % 4)];
+        }
+    }
+}
+
+fn inv_shift_rows(block: &mut [u8]) {
+    let old: [u8; 16] = (&*block).try_into().unwrap();
+    for r in 1..4 {
+        for c in 0..4 {
+            block[r + 4 * ((c + r) % 4)] = old[r + 4 * c];
+        }
+    }
+}
+
+fn mix_columns(block: &mut [u8], m: [u8; 4]) {
+    for column in block.chunks_exact_mut(4) {
+        let a: [u8; 4] = (&*column).try_into().unwrap();
+        for (r, x) in column.iter_mut().enumerate() {
+            *x = (0..4).fold(0, |acc, i| acc ^ mul(a[(r + i) % 4], m[i]));
+        }
+    }
+}
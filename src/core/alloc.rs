@@ -0,0 +1,346 @@
+use std::collections::HashMap;
+
+pub const PREALLOC_BLOCKS: usize = 4;
+pub const MAX_REFS: u8 = u8::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Window {
+    start: usize,
+    len: usize,
+}
+
+// 每个块记录引用计数，0 表示空闲；快照与当前文件系统共享的块计数大于1。
+// 元数据所在的块在位图中同样标记为已用，分配器不需要区分。
+// 预分配窗口只是软预留：其他文件找不到空间时仍然可以使用其中的块；
+// 延迟分配的预留则是硬预留，保证刷新时一定能分配到块。
+#[derive(Debug)]
+pub struct Allocator {
+    refs: Vec<u8>,
+    windows: HashMap<usize, Window>,
+    reserved: usize,
+}
+
+impl Allocator {
+    pub fn new(refs: Vec<u8>) -> Self {
+        Self {
+            refs,
+            windows: HashMap::new(),
+            reserved: 0,
+        }
+    }
+
+    pub fn total_blocks(&self) -> usize {
+        self.refs.len()
+    }
+
+    // 调整块数时新增的块为空闲；缩小时超出末尾的预分配窗口一并丢弃
+    pub fn resize(&mut self, total_blocks: usize) {
+        self.refs.resize(total_blocks, 0);
+        self.windows
+            .retain(|_, window| window.start + window.len <= total_blocks);
+    }
+
+    pub fn is_used(&self, block_pos: usize) -> bool {
+        self.refs[block_pos] != 0
+    }
+
+    // 标记为已用时保留已有的引用计数
+    pub fn set_used(&mut self, block_pos: usize, used: bool) {
+        if !used {
+            self.refs[block_pos] = 0;
+        } else if self.refs[block_pos] == 0 {
+            self.refs[block_pos] = 1;
+        }
+    }
+
+    pub fn refcount(&self, block_pos: usize) -> u8 {
+        self.refs[block_pos]
+    }
+
+    pub fn add_ref(&mut self, block_pos: usize) -> bool {
+        match self.refs[block_pos] {
+            MAX_REFS => false,
+            _ => {
+                self.refs[block_pos] += 1;
+                true
+            }
+        }
+    }
+
+    // 返回该块是否因此变为空闲
+    pub fn drop_ref(&mut self, block_pos: usize) -> bool {
+        self.refs[block_pos] = self.refs[block_pos].saturating_sub(1);
+        self.refs[block_pos] == 0
+    }
+
+    pub fn refcounts(&self) -> &[u8] {
+        &self.refs
+    }
+
+    pub fn free_blocks(&self) -> usize {
+        self.refs.iter().filter(|x| **x == 0).count()
+    }
+
+    // 扣除延迟分配已预留的块之后，仍可用于新写入的块数
+    pub fn available(&self) -> usize {
+        self.free_blocks().saturating_sub(self.reserved)
+    }
+
+    pub fn reserve(&mut self, count: usize) -> bool {
+        if count > self.available() {
+            return false;
+        }
+        self.reserved += count;
+        true
+    }
+
+    pub fn unreserve(&mut self, count: usize) {
+        self.reserved = self.reserved.saturating_sub(count);
+    }
+
+    pub fn reserved(&self) -> usize {
+        self.reserved
+    }
+
+    pub fn free_runs(&self) -> Vec<(usize, usize)> {
+        let mut runs: Vec<(usize, usize)> = Vec::new();
+        for i in 0..self.refs.len() {
+            if self.refs[i] != 0 {
+                continue;
+            }
+            match runs.last_mut() {
+                Some((start, len)) if *start + *len == i => *len += 1,
+                _ => runs.push((i, 1)),
+            }
+        }
+        runs
+    }
+
+    // 分配 count 个块：goal 处正好放得下时（例如原地重写）直接使用，
+    // 否则先用 owner 的预分配窗口，再从 goal 开始寻找足够长的连续区间，
+    // 都不满足时从最长的区间开始拼凑。成功后在末尾为 owner 预留一个新窗口。
+    pub fn allocate(
+        &mut self,
+        count: usize,
+        goal: Option<usize>,
+        owner: Option<usize>,
+    ) -> Option<Vec<u32>> {
+        if count == 0 {
+            return Some(Vec::new());
+        }
+        if count > self.free_blocks() {
+            return None;
+        }
+
+        let mut chosen = Vec::with_capacity(count);
+
+        let window = owner.and_then(|x| self.windows.remove(&x));
+        if let Some(start) = goal.filter(|x| self.fits(*x, count, owner, false)) {
+            chosen.extend(start..start + count);
+        } else if let Some(window) = window {
+            for i in window.start..window.start + window.len {
+                if chosen.len() == count || self.refs[i] != 0 {
+                    break;
+                }
+                chosen.push(i);
+            }
+        }
+
+        let goal = chosen.last().map(|x| x + 1).or(goal).unwrap_or(0);
+        let remaining = count - chosen.len();
+        if remaining > 0 {
+            let found = self
+                .find_run(remaining, goal, owner, false)
+                .or_else(|| self.find_run(remaining, goal, owner, true));
+            match found {
+                Some(start) => chosen.extend(start..start + remaining),
+                None => self.gather(&mut chosen, count, owner),
+            }
+        }
+
+        for block_pos in chosen.iter() {
+            self.refs[*block_pos] = 1;
+        }
+        self.steal(&chosen, owner);
+
+        if let Some(owner) = owner {
+            let next = chosen.last().unwrap() + 1;
+            let len = (next..self.refs.len())
+                .take(PREALLOC_BLOCKS)
+                .take_while(|x| self.refs[*x] == 0 && !self.reserved_by_other(*x, Some(owner)))
+                .count();
+            if len > 0 {
+                self.windows.insert(owner, Window { start: next, len });
+            }
+        }
+
+        Some(chosen.into_iter().map(|x| x as u32).collect())
+    }
+
+    // 只接受一整段连续的空闲块，找不到时不分配；其他文件的预分配窗口也可以占用
+    pub fn allocate_run(&mut self, count: usize, goal: usize) -> Option<Vec<u32>> {
+        if count == 0 || count > self.free_blocks() {
+            return None;
+        }
+        let start = self.find_run(count, goal, None, true)?;
+        let chosen: Vec<usize> = (start..start + count).collect();
+        for block_pos in chosen.iter() {
+            self.refs[*block_pos] = 1;
+        }
+        self.steal(&chosen, None);
+        Some(chosen.into_iter().map(|x| x as u32).collect())
+    }
+
+    pub fn release(&mut self, owner: usize) {
+        self.windows.remove(&owner);
+    }
+
+    pub fn windows(&self) -> usize {
+        self.windows.len()
+    }
+
+    // 从 goal 向后（必要时回绕）寻找长度至少为 count 的空闲区间
+    fn find_run(
+        &self,
+        count: usize,
+        goal: usize,
+        owner: Option<usize>,
+        use_windows: bool,
+    ) -> Option<usize> {
+        let total = self.refs.len();
+        let goal = goal.min(total - 1);
+        let starts = (goal..total).chain(0..goal);
+
+        starts
+            .into_iter()
+            .find(|start| self.fits(*start, count, owner, use_windows))
+    }
+
+    fn fits(&self, start: usize, count: usize, owner: Option<usize>, use_windows: bool) -> bool {
+        start + count <= self.refs.len()
+            && (start..start + count)
+                .all(|x| self.refs[x] == 0 && (use_windows || !self.reserved_by_other(x, owner)))
+    }
+
+    fn gather(&self, chosen: &mut Vec<usize>, count: usize, owner: Option<usize>) {
+        let mut runs = self.free_runs();
+        runs.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+        for use_windows in [false, true] {
+            for (start, len) in runs.iter() {
+                for i in *start..*start + *len {
+                    if chosen.len() == count {
+                        return;
+                    }
+                    if chosen.contains(&i) || (!use_windows && self.reserved_by_other(i, owner)) {
+                        continue;
+                    }
+                    chosen.push(i);
+                }
+            }
+        }
+    }
+
+    fn reserved_by_other(&self, block_pos: usize, owner: Option<usize>) -> bool {
+        self.windows.iter().any(|(x, window)| {
+            Some(*x) != owner && (window.start..window.start + window.len).contains(&block_pos)
+        })
+    }
+
+    // 被占用的块从其他文件的预分配窗口中移除
+    fn steal(&mut self, chosen: &[usize], owner: Option<usize>) {
+        let stolen: Vec<usize> = self
+            .windows
+            .iter()
+            .filter(|(x, window)| {
+                Some(**x) != owner
+                    && chosen
+                        .iter()
+                        .any(|b| (window.start..window.start + window.len).contains(b))
+            })
+            .map(|(x, _)| *x)
+            .collect();
+        for x in stolen {
+            self.windows.remove(&x);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::fs::{FormatOptions, System};
+    use crate::core::hardware::{Hardware, BLOCK_SIZE};
+
+    #[test]
+    fn honour_goal() {
+        let mut allocator = Allocator::new(vec![0; 64]);
+        assert_eq!(allocator.allocate(3, Some(10), None).unwrap(), [10, 11, 12]);
+        // 目标位置已被占用时从它之后找连续区间
+        assert_eq!(allocator.allocate(2, Some(11), None).unwrap(), [13, 14]);
+        allocator.set_used(20, true);
+        assert_eq!(
+            allocator.allocate(4, Some(18), None).unwrap(),
+            [21, 22, 23, 24]
+        );
+        // 找不到连续区间时回绕到开头
+        assert_eq!(allocator.allocate(2, Some(63), None).unwrap(), [0, 1]);
+    }
+
+    #[test]
+    fn windows_reserved_for_owner() {
+        let mut allocator = Allocator::new(vec![0; 64]);
+        assert_eq!(allocator.allocate(2, Some(0), Some(7)).unwrap(), [0, 1]);
+        assert_eq!(allocator.windows(), 1);
+        // 其他文件不会占用窗口，窗口的主人接着往后写
+        assert_eq!(
+            allocator.allocate(1, None, Some(8)).unwrap(),
+            [2 + PREALLOC_BLOCKS as u32]
+        );
+        assert_eq!(allocator.allocate(1, None, Some(7)).unwrap(), [2]);
+        allocator.release(7);
+        allocator.release(8);
+        assert_eq!(allocator.windows(), 0);
+    }
+
+    fn has_window(fs: &System, path: &str) -> bool {
+        let inode_index = fs.lookup(path).unwrap();
+        fs.allocator.windows.contains_key(&inode_index)
+    }
+
+    #[test]
+    fn windows_released_on_close_and_truncate() {
+        let mut fs = System::format(Hardware::new(), FormatOptions::default()).unwrap();
+        let data = vec![b'x'; BLOCK_SIZE * 2];
+        fs.options.delayed_alloc = false;
+
+        // 通过 write_path 写入相当于打开、写入再关闭
+        fs.write_path("/a", &data).unwrap();
+        assert!(!has_window(&fs, "/a"));
+
+        let mut dir = fs.get_root_dir().unwrap();
+        let mut file = fs.open_file(&mut dir, "b").unwrap();
+        fs.write_file(&mut dir, &mut file, &data).unwrap();
+        assert!(has_window(&fs, "/b"));
+
+        // 截短
+        fs.write_file(&mut dir, &mut file, b"x").unwrap();
+        assert!(!has_window(&fs, "/b"));
+
+        fs.write_file(&mut dir, &mut file, &data).unwrap();
+        assert!(has_window(&fs, "/b"));
+        fs.close_file(file);
+        assert!(!has_window(&fs, "/b"));
+
+        // 延迟分配的数据在关闭之后才刷新，刷新后同样没有窗口
+        fs.options.delayed_alloc = true;
+        let mut file = fs.open_file(&mut dir, "c").unwrap();
+        fs.write_file(&mut dir, &mut file, &data).unwrap();
+        fs.sync().unwrap();
+        assert!(has_window(&fs, "/c"));
+        fs.write_file(&mut dir, &mut file, &data).unwrap();
+        fs.close_file(file);
+        fs.sync().unwrap();
+        assert!(!has_window(&fs, "/c"));
+    }
+}
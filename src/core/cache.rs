@@ -0,0 +1,278 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::core::hardware::{Hardware, BLOCK_SIZE};
+
+pub const DEFAULT_CACHE_BLOCKS: usize = 16;
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CacheStats {
+    pub capacity: usize,
+    pub cached: usize,
+    pub dirty: usize,
+    pub pinned: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub writebacks: u64,
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    data: Vec<u8>,
+    dirty: bool,
+    pinned: bool,
+    last_used: u64,
+}
+
+#[derive(Debug)]
+struct Inner {
+    device: Hardware,
+    entries: HashMap<usize, CacheEntry>,
+    tick: u64,
+    stats: CacheStats,
+}
+
+// 读操作也需要更新 LRU 顺序和命中统计，因此内部状态放在 RefCell 中
+#[derive(Debug)]
+pub struct BlockCache {
+    capacity: usize,
+    inner: RefCell<Inner>,
+}
+
+impl BlockCache {
+    pub fn new(device: Hardware, capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            inner: RefCell::new(Inner {
+                device,
+                entries: HashMap::new(),
+                tick: 0,
+                stats: CacheStats::default(),
+            }),
+        }
+    }
+
+    pub fn path(&self) -> String {
+        self.inner.borrow().device.path().to_string()
+    }
+
+    pub fn device_blocks(&self) -> usize {
+        self.inner.borrow().device.block_count()
+    }
+
+    // 调整设备大小，新的末尾之后的缓存块直接丢弃
+    pub fn set_device_blocks(&mut self, blocks: usize) -> std::io::Result<()> {
+        let inner = self.inner.get_mut();
+        inner.entries.retain(|block_pos, _| *block_pos < blocks);
+        inner.device.set_block_count(blocks)
+    }
+
+    pub fn read(&self, block_pos: usize) -> std::io::Result<Vec<u8>> {
+        let mut inner = self.inner.borrow_mut();
+        let entry = inner.entry(block_pos, self.capacity, true)?;
+        Ok(entry.data.clone())
+    }
+
+    // 只有内容真正发生变化的块才会被标记为脏块
+    pub fn write(&mut self, block_pos: usize, offset: usize, data: &[u8]) -> std::io::Result<()> {
+        assert!(offset + data.len() <= BLOCK_SIZE);
+        let inner = self.inner.get_mut();
+        // 整块覆盖时无需先从设备读入旧内容
+        let load = data.len() < BLOCK_SIZE;
+        let entry = inner.entry(block_pos, self.capacity, load)?;
+        let target = &mut entry.data[offset..offset + data.len()];
+        if target != data {
+            target.copy_from_slice(data);
+            entry.dirty = true;
+        }
+        Ok(())
+    }
+
+    pub fn pin(&mut self, block_pos: usize) -> std::io::Result<()> {
+        let inner = self.inner.get_mut();
+        inner.entry(block_pos, self.capacity, true)?.pinned = true;
+        Ok(())
+    }
+
+    pub fn unpin(&mut self, block_pos: usize) {
+        if let Some(entry) = self.inner.get_mut().entries.get_mut(&block_pos) {
+            entry.pinned = false;
+        }
+    }
+
+    pub fn flush(&mut self) -> std::io::Result<usize> {
+        let inner = self.inner.get_mut();
+
+        let mut dirty: Vec<usize> = inner
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.dirty)
+            .map(|(block_pos, _)| *block_pos)
+            .collect();
+        if dirty.is_empty() {
+            return Ok(0);
+        }
+        dirty.sort_unstable();
+
+        for block_pos in dirty.iter() {
+            inner.write_back(*block_pos)?;
+        }
+        inner.device.flush()?;
+
+        Ok(dirty.len())
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let inner = self.inner.borrow();
+        CacheStats {
+            capacity: self.capacity,
+            cached: inner.entries.len(),
+            dirty: inner.entries.values().filter(|x| x.dirty).count(),
+            pinned: inner.entries.values().filter(|x| x.pinned).count(),
+            ..inner.stats
+        }
+    }
+}
+
+impl Inner {
+    fn entry(
+        &mut self,
+        block_pos: usize,
+        capacity: usize,
+        load: bool,
+    ) -> std::io::Result<&mut CacheEntry> {
+        self.tick += 1;
+
+        if self.entries.contains_key(&block_pos) {
+            self.stats.hits += 1;
+        } else {
+            self.stats.misses += 1;
+            self.evict(capacity)?;
+
+            let mut data = vec![0; BLOCK_SIZE];
+            if load {
+                self.device.read_block(block_pos, &mut data)?;
+            }
+            self.entries.insert(
+                block_pos,
+                CacheEntry {
+                    data,
+                    dirty: !load,
+                    pinned: false,
+                    last_used: 0,
+                },
+            );
+        }
+
+        let entry = self.entries.get_mut(&block_pos).unwrap();
+        entry.last_used = self.tick;
+        Ok(entry)
+    }
+
+    // 固定的块不参与淘汰，因此缓存中的块数可能暂时超过容量
+    fn evict(&mut self, capacity: usize) -> std::io::Result<()> {
+        while self.entries.len() >= capacity {
+            let Some(victim) = self
+                .entries
+                .iter()
+                .filter(|(_, entry)| !entry.pinned)
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(block_pos, _)| *block_pos)
+            else {
+                break;
+            };
+
+            if self.entries[&victim].dirty {
+                self.write_back(victim)?;
+            }
+            self.entries.remove(&victim);
+            self.stats.evictions += 1;
+        }
+        Ok(())
+    }
+
+    fn write_back(&mut self, block_pos: usize) -> std::io::Result<()> {
+        let entry = self.entries.get_mut(&block_pos).unwrap();
+        self.device.write_block(block_pos, &entry.data)?;
+        entry.dirty = false;
+        self.stats.writebacks += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn on_device(cache: &BlockCache, block_pos: usize) -> Vec<u8> {
+        let mut data = vec![0; BLOCK_SIZE];
+        cache
+            .inner
+            .borrow()
+            .device
+            .read_block(block_pos, &mut data)
+            .unwrap();
+        data
+    }
+
+    fn cached(cache: &BlockCache, block_pos: usize) -> bool {
+        cache.inner.borrow().entries.contains_key(&block_pos)
+    }
+
+    #[test]
+    fn evict_least_recently_used() {
+        let cache = BlockCache::new(Hardware::new(), 2);
+        cache.read(0).unwrap();
+        cache.read(1).unwrap();
+        // 再次读取第0块后，最久未用的是第1块
+        cache.read(0).unwrap();
+        cache.read(2).unwrap();
+        assert!(cached(&cache, 0) && cached(&cache, 2) && !cached(&cache, 1));
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.evictions), (1, 3, 1));
+        assert_eq!(stats.cached, 2);
+    }
+
+    #[test]
+    fn pinned_blocks_survive_eviction() {
+        let mut cache = BlockCache::new(Hardware::new(), 2);
+        cache.pin(0).unwrap();
+        for block_pos in 1..5 {
+            cache.read(block_pos).unwrap();
+        }
+        assert!(cached(&cache, 0));
+        assert_eq!(cache.stats().pinned, 1);
+
+        cache.unpin(0);
+        cache.read(5).unwrap();
+        cache.read(6).unwrap();
+        assert!(!cached(&cache, 0));
+    }
+
+    #[test]
+    fn write_back_only_on_eviction_or_flush() {
+        let mut cache = BlockCache::new(Hardware::new(), 2);
+        cache.write(0, 0, b"first").unwrap();
+        cache.read(1).unwrap();
+        assert_eq!(cache.stats().writebacks, 0);
+        assert!(on_device(&cache, 0).iter().all(|x| *x == 0));
+
+        // 淘汰脏块时写回
+        cache.read(2).unwrap();
+        assert_eq!(&on_device(&cache, 0)[..5], b"first");
+        assert_eq!(cache.stats().writebacks, 1);
+
+        // 内容没有变化的写入不产生脏块
+        cache.write(2, 0, &[0; 8]).unwrap();
+        assert_eq!(cache.stats().dirty, 0);
+
+        cache.write(2, 0, b"second").unwrap();
+        assert!(on_device(&cache, 2).iter().all(|x| *x == 0));
+        assert_eq!(cache.flush().unwrap(), 1);
+        assert_eq!(&on_device(&cache, 2)[..6], b"second");
+        assert_eq!(cache.stats().writebacks, 2);
+        assert_eq!(cache.flush().unwrap(), 0);
+    }
+}
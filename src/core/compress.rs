@@ -0,0 +1,201 @@
+use crate::core::hardware::BLOCK_SIZE;
+
+// 压缩文件的数据按簇保存，每簇最多占4个块，从块边界开始。
+// 簇头部4字节：原始长度（u16）和保存的长度（u16），两者相等时数据未压缩。
+// 压缩后省不出一个块的簇按原样保存，这时一簇只放得下 CLUSTER_SIZE - CLUSTER_HEADER 字节。
+pub const CLUSTER_BLOCKS: usize = 4;
+pub const CLUSTER_SIZE: usize = CLUSTER_BLOCKS * BLOCK_SIZE;
+const CLUSTER_HEADER: usize = 4;
+
+// LZ77 序列的格式参考 LZ4：一个标记字节，高4位是字面量长度，低4位是匹配长度减4，
+// 取15时后面跟扩展长度（每个255字节表示继续）；然后是字面量和2字节的匹配距离。
+// 最后一个序列只有字面量。
+const MIN_MATCH: usize = 4;
+const HASH_BITS: u32 = 12;
+const MAX_DISTANCE: usize = u16::MAX as usize;
+
+pub fn encode(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let chunk = &data[pos..data.len().min(pos + CLUSTER_SIZE)];
+        let packed = compress(chunk);
+        if blocks(packed.len()) < blocks(chunk.len()) {
+            push_cluster(&mut out, chunk.len(), &packed);
+            pos += chunk.len();
+        } else {
+            let end = data.len().min(pos + CLUSTER_SIZE - CLUSTER_HEADER);
+            push_cluster(&mut out, end - pos, &data[pos..end]);
+            pos = end;
+        }
+    }
+    out
+}
+
+// 数据不完整或无法解压时返回 None
+pub fn decode(data: &[u8], size: usize) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(size);
+    let mut pos = 0;
+    while out.len() < size {
+        let header = data.get(pos..pos + CLUSTER_HEADER)?;
+        let raw_len = u16::from_le_bytes([header[0], header[1]]) as usize;
+        let stored_len = u16::from_le_bytes([header[2], header[3]]) as usize;
+        if raw_len == 0 || out.len() + raw_len > size {
+            return None;
+        }
+        let payload = data.get(pos + CLUSTER_HEADER..pos + CLUSTER_HEADER + stored_len)?;
+        if stored_len == raw_len {
+            out.extend_from_slice(payload);
+        } else {
+            out.extend(decompress(payload, raw_len)?);
+        }
+        pos += blocks(stored_len) * BLOCK_SIZE;
+    }
+    Some(out)
+}
+
+fn blocks(stored_len: usize) -> usize {
+    (CLUSTER_HEADER + stored_len).div_ceil(BLOCK_SIZE)
+}
+
+fn push_cluster(out: &mut Vec<u8>, raw_len: usize, payload: &[u8]) {
+    out.extend_from_slice(&(raw_len as u16).to_le_bytes());
+    out.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+    out.extend_from_slice(payload);
+    out.resize(out.len().next_multiple_of(BLOCK_SIZE), 0);
+}
+
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() / 2);
+    let mut table = vec![usize::MAX; 1 << HASH_BITS];
+    let mut anchor = 0;
+    let mut pos = 0;
+    while pos + MIN_MATCH <= data.len() {
+        let h = hash(&data[pos..pos + MIN_MATCH]);
+        let candidate = table[h];
+        table[h] = pos;
+        if candidate == usize::MAX
+            || pos - candidate > MAX_DISTANCE
+            || data[candidate..candidate + MIN_MATCH] != data[pos..pos + MIN_MATCH]
+        {
+            pos += 1;
+            continue;
+        }
+
+        let mut len = MIN_MATCH;
+        while pos + len < data.len() && data[candidate + len] == data[pos + len] {
+            len += 1;
+        }
+        push_sequence(&mut out, &data[anchor..pos], Some((pos - candidate, len)));
+        pos += len;
+        anchor = pos;
+    }
+    push_sequence(&mut out, &data[anchor..], None);
+    out
+}
+
+pub fn decompress(data: &[u8], size: usize) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(size);
+    let mut pos = 0;
+    loop {
+        let token = *data.get(pos)?;
+        pos += 1;
+        let mut literals = (token >> 4) as usize;
+        if literals == 15 {
+            literals += read_length(data, &mut pos)?;
+        }
+        if out.len() + literals > size {
+            return None;
+        }
+        out.extend_from_slice(data.get(pos..pos + literals)?);
+        pos += literals;
+        if pos == data.len() {
+            break;
+        }
+
+        let distance = u16::from_le_bytes([*data.get(pos)?, *data.get(pos + 1)?]) as usize;
+        pos += 2;
+        let mut len = (token & 0xf) as usize;
+        if len == 15 {
+            len += read_length(data, &mut pos)?;
+        }
+        len += MIN_MATCH;
+        if distance == 0 || distance > out.len() || out.len() + len > size {
+            return None;
+        }
+        // 匹配可能与正在输出的内容重叠，只能逐字节复制
+        let start = out.len() - distance;
+        for i in 0..len {
+            out.push(out[start + i]);
+        }
+    }
+    (out.len() == size).then_some(out)
+}
+
+fn hash(bytes: &[u8]) -> usize {
+    let word = u32::from_le_bytes(bytes.try_into().unwrap());
+    (word.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+}
+
+fn push_sequence(out: &mut Vec<u8>, literals: &[u8], matched: Option<(usize, usize)>) {
+    let extra = matched.map_or(0, |(_, len)| len - MIN_MATCH);
+    out.push(((literals.len().min(15) as u8) << 4) | extra.min(15) as u8);
+    if literals.len() >= 15 {
+        push_length(out, literals.len() - 15);
+    }
+    out.extend_from_slice(literals);
+    if let Some((distance, _)) = matched {
+        out.extend_from_slice(&(distance as u16).to_le_bytes());
+        if extra >= 15 {
+            push_length(out, extra - 15);
+        }
+    }
+}
+
+fn push_length(out: &mut Vec<u8>, mut len: usize) {
+    while len >= 255 {
+        out.push(255);
+        len -= 255;
+    }
+    out.push(len as u8);
+}
+
+fn read_length(data: &[u8], pos: &mut usize) -> Option<usize> {
+    let mut len = 0;
+    loop {
+        let byte = *data.get(*pos)?;
+        *pos += 1;
+        len += byte as usize;
+        if byte != 255 {
+            return Some(len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::fs::{FormatOptions, System};
+    use crate::core::hardware::Hardware;
+
+    #[test]
+    fn compress_roundtrip() {
+        let text: Vec<u8> = b"hello, compressed world. ".repeat(1000);
+        let stored = compress(&text);
+        assert_eq!(decompress(&stored, text.len()).unwrap(), text);
+
+        let mut fs = System::format(Hardware::new(), FormatOptions::default()).unwrap();
+        fs.create_dir_all("/z").unwrap();
+        fs.set_compression("/z", true).unwrap();
+        fs.write_path("/z/a", &text).unwrap();
+        fs.sync().unwrap();
+        let stat = fs.stat("/z/a").unwrap();
+        assert!(stat.compressed);
+        assert!(stat.blocks.len() < text.len().div_ceil(BLOCK_SIZE));
+        assert_eq!(fs.read_path("/z/a").unwrap(), text);
+
+        // 关闭压缩后按原样重写
+        fs.set_compression("/z/a", false).unwrap();
+        assert_eq!(fs.read_path("/z/a").unwrap(), text);
+    }
+}
@@ -0,0 +1,40 @@
+// CRC-32C（Castagnoli），反射多项式 0x82F63B78
+const POLY: u32 = 0x82F6_3B78;
+
+const TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+pub fn crc32c(data: &[u8]) -> u32 {
+    update(0, data)
+}
+
+// 在已有校验值的基础上继续计算，便于跳过结构中存放校验和的字段
+pub fn update(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for byte in data {
+        crc = TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+// 元数据的校验和同时覆盖所属的索引节点号或块号，写到错误位置的块也能被发现
+pub fn owned(owner: usize, data: &[u8]) -> u32 {
+    update(crc32c(&(owner as u32).to_le_bytes()), data)
+}
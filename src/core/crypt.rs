@@ -0,0 +1,336 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Read;
+
+use crate::core::aes::{self, Aes256, Xts};
+use crate::core::error::{FsError, Result};
+use crate::core::fs::System;
+use crate::core::hardware::BLOCK_SIZE;
+use crate::core::inode::INODE_FLAG_ENCRYPTED;
+use crate::core::sha256;
+use crate::core::superblock::FEATURE_INCOMPAT_ENCRYPTION;
+
+// 索引节点中的加密上下文：策略的盐值和口令校验值（同一策略下都相同），
+// 以及每个索引节点自己的随机数，用来派生文件内容密钥和目录的文件名密钥
+pub const CONTEXT_SIZE: usize = 32;
+// 加密后的文件名用 base64 保存，长度不能超过 MAX_NAME_LEN
+pub const MAX_ENCRYPTED_NAME_LEN: usize = 23;
+const PBKDF2_ITERATIONS: usize = 10_000;
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Context {
+    pub salt: [u8; 8],
+    pub check: [u8; 8],
+    pub nonce: [u8; 16],
+}
+
+impl Context {
+    pub fn from_bytes(data: &[u8]) -> Self {
+        Self {
+            salt: data[..8].try_into().unwrap(),
+            check: data[8..16].try_into().unwrap(),
+            nonce: data[16..32].try_into().unwrap(),
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; CONTEXT_SIZE] {
+        let mut data = [0; CONTEXT_SIZE];
+        data[..8].copy_from_slice(&self.salt);
+        data[8..16].copy_from_slice(&self.check);
+        data[16..].copy_from_slice(&self.nonce);
+        data
+    }
+
+    pub fn same_policy(&self, other: &Context) -> bool {
+        self.salt == other.salt && self.check == other.check
+    }
+
+    // 同一策略下新建的索引节点
+    pub fn child(&self) -> Self {
+        Self {
+            nonce: random(),
+            ..*self
+        }
+    }
+}
+
+// 策略由盐值和口令校验值确定
+type Policy = ([u8; 8], [u8; 8]);
+
+// 挂载时提供的口令，以及从它派生出的各个策略的主密钥
+#[derive(Default)]
+pub struct Keyring {
+    passphrase: Option<String>,
+    keys: RefCell<HashMap<Policy, Option<[u8; 32]>>>,
+}
+
+impl fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Keyring")
+            .field("passphrase", &self.passphrase.is_some())
+            .field("policies", &self.keys.borrow().len())
+            .finish()
+    }
+}
+
+impl Keyring {
+    pub fn new(passphrase: &str) -> Self {
+        Self {
+            passphrase: Some(passphrase.to_string()),
+            keys: RefCell::default(),
+        }
+    }
+
+    // 口令与策略不匹配时返回 None
+    fn master_key(&self, context: &Context) -> Option<[u8; 32]> {
+        let passphrase = self.passphrase.as_ref()?;
+        let policy = (context.salt, context.check);
+        if let Some(key) = self.keys.borrow().get(&policy) {
+            return *key;
+        }
+        let key = sha256::pbkdf2(passphrase.as_bytes(), &context.salt, PBKDF2_ITERATIONS);
+        let key = (check_value(&key) == context.check).then_some(key);
+        self.keys.borrow_mut().insert(policy, key);
+        key
+    }
+}
+
+impl System {
+    pub fn set_passphrase(&mut self, passphrase: &str) {
+        self.keys = Keyring::new(passphrase);
+        self.dentries.clear();
+    }
+
+    // 只能为空目录设置加密策略，之后在其中新建的文件、目录和符号链接都会加密
+    pub fn encrypt_dir(&mut self, path: &str) -> Result<()> {
+        self.check_writable()?;
+        let passphrase = self.keys.passphrase.clone().ok_or(FsError::NoPassphrase)?;
+        let dir = self.open_dir_path(path)?;
+        if self.inode(dir.inode_index)?.borrow().is_encrypted() {
+            return Err(FsError::AlreadyEncrypted(path.to_string()));
+        }
+        if dir
+            .items
+            .iter()
+            .any(|item| item.name != "." && item.name != "..")
+        {
+            return Err(FsError::NotEmpty(path.to_string()));
+        }
+
+        let salt = random();
+        let key = sha256::pbkdf2(passphrase.as_bytes(), &salt, PBKDF2_ITERATIONS);
+        let context = Context {
+            salt,
+            check: check_value(&key),
+            nonce: random(),
+        };
+        self.keys
+            .keys
+            .borrow_mut()
+            .insert((context.salt, context.check), Some(key));
+
+        let inode = self.inode_mut(dir.inode_index)?;
+        let mut inode = inode.borrow_mut();
+        inode.flags |= INODE_FLAG_ENCRYPTED;
+        inode.context = context;
+        inode.ctime = chrono::Utc::now().timestamp();
+
+        self.super_block.feature_incompat |= FEATURE_INCOMPAT_ENCRYPTION;
+        Ok(())
+    }
+
+    // 索引节点已加密且有正确的口令时返回 true，未加密时返回 false
+    pub fn is_unlocked(&self, inode_index: usize) -> Result<bool> {
+        let inode = self.inode(inode_index)?;
+        let inode = inode.borrow();
+        Ok(inode.is_encrypted() && self.keys.master_key(&inode.context).is_some())
+    }
+
+    // 目录未加密时返回 None，缺少密钥时返回 Locked
+    pub(crate) fn name_cipher(&self, dir_index: usize) -> Result<Option<Aes256>> {
+        Ok(self
+            .inode_key(dir_index, b"name")?
+            .map(|key| Aes256::new(&key)))
+    }
+
+    pub(crate) fn content_cipher(&self, inode_index: usize) -> Result<Option<Xts>> {
+        let Some(data_key) = self.inode_key(inode_index, b"data")? else {
+            return Ok(None);
+        };
+        let tweak_key = self.inode_key(inode_index, b"tweak")?.unwrap();
+        Ok(Some(Xts::new(&data_key, &tweak_key)))
+    }
+
+    // 目录项和索引节点中保存的名称
+    pub(crate) fn stored_name(&self, dir_index: usize, name: &str) -> Result<String> {
+        Ok(match self.name_cipher(dir_index)? {
+            Some(cipher) => encrypt_name(&cipher, name),
+            None => name.to_string(),
+        })
+    }
+
+    // 加密目录中只能放入同一策略加密的索引节点
+    pub(crate) fn check_policy(
+        &self,
+        dir_index: usize,
+        inode_index: usize,
+        name: &str,
+    ) -> Result<()> {
+        let dir = self.inode(dir_index)?;
+        let dir = dir.borrow();
+        if !dir.is_encrypted() {
+            return Ok(());
+        }
+        let inode = self.inode(inode_index)?;
+        let inode = inode.borrow();
+        if inode.is_encrypted() && inode.context.same_policy(&dir.context) {
+            Ok(())
+        } else {
+            Err(FsError::PolicyMismatch(name.to_string()))
+        }
+    }
+
+    fn inode_key(&self, inode_index: usize, label: &[u8]) -> Result<Option<[u8; 32]>> {
+        let inode = self.inode(inode_index)?;
+        let inode = inode.borrow();
+        if !inode.is_encrypted() {
+            return Ok(None);
+        }
+        let master = self
+            .keys
+            .master_key(&inode.context)
+            .ok_or_else(|| FsError::Locked(inode.name.clone()))?;
+        let message = [&inode.context.nonce[..], label].concat();
+        Ok(Some(sha256::hmac(&master, &message)))
+    }
+}
+
+// 每个数据块以逻辑块号作为调整值单独加密，最后一块补零
+pub fn encrypt_blocks(cipher: &Xts, data: &[u8]) -> Vec<u8> {
+    let mut data = data.to_vec();
+    data.resize(data.len().next_multiple_of(BLOCK_SIZE), 0);
+    for (i, block) in data.chunks_exact_mut(BLOCK_SIZE).enumerate() {
+        cipher.encrypt(i as u64, block);
+    }
+    data
+}
+
+pub fn decrypt_blocks(cipher: &Xts, data: &mut [u8]) {
+    for (i, block) in data.chunks_exact_mut(BLOCK_SIZE).enumerate() {
+        cipher.decrypt(i as u64, block);
+    }
+}
+
+// 名称补零到至少16字节后加密，密文与补齐后的名称等长
+pub fn encrypt_name(cipher: &Aes256, name: &str) -> String {
+    let mut data = name.as_bytes().to_vec();
+    data.resize(data.len().max(16), 0);
+    base64_encode(&aes::cts_encrypt(cipher, &data))
+}
+
+pub fn decrypt_name(cipher: &Aes256, stored: &str) -> Option<String> {
+    let data = base64_decode(stored)?;
+    if data.len() < 16 {
+        return None;
+    }
+    let mut data = aes::cts_decrypt(cipher, &data);
+    while data.last() == Some(&0) {
+        data.pop();
+    }
+    String::from_utf8(data).ok()
+}
+
+fn check_value(key: &[u8; 32]) -> [u8; 8] {
+    sha256::hmac(key, b"check")[..8].try_into().unwrap()
+}
+
+// 盐值和随机数必须来自操作系统的密码学安全随机数源，读不到时无法安全地加密，直接终止
+fn random<const N: usize>() -> [u8; N] {
+    let mut out = [0; N];
+    std::fs::File::open("/dev/urandom")
+        .and_then(|mut file| file.read_exact(&mut out))
+        .unwrap_or_else(|e| panic!("/dev/urandom: 读取随机数失败: {}", e));
+    out
+}
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let bits = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |acc, (i, x)| acc | (*x as u32) << (16 - 8 * i));
+        for i in 0..=chunk.len() {
+            out.push(BASE64[(bits >> (18 - 6 * i)) as usize & 0x3f] as char);
+        }
+    }
+    out
+}
+
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    for chunk in text.as_bytes().chunks(4) {
+        if chunk.len() == 1 {
+            return None;
+        }
+        let mut bits = 0u32;
+        for (i, c) in chunk.iter().enumerate() {
+            let value = BASE64.iter().position(|x| x == c)? as u32;
+            bits |= value << (18 - 6 * i);
+        }
+        for i in 0..chunk.len() - 1 {
+            out.push((bits >> (16 - 8 * i)) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::fs::FormatOptions;
+    use crate::core::hardware::Hardware;
+
+    #[test]
+    fn encrypt_roundtrip() {
+        let options = FormatOptions {
+            extents: true,
+            ..Default::default()
+        };
+        let mut fs = System::format(Hardware::new(), options).unwrap();
+        fs.set_passphrase("secret");
+        fs.create_dir_all("/sec").unwrap();
+        fs.encrypt_dir("/sec").unwrap();
+        let data: Vec<u8> = (0..6000).map(|x| (x % 251) as u8).collect();
+        fs.write_path("/sec/creds", &data).unwrap();
+        fs.sync().unwrap();
+        assert_eq!(fs.read_path("/sec/creds").unwrap(), data);
+
+        // 磁盘上既没有明文内容，也没有明文名称
+        let block = fs.stat("/sec/creds").unwrap().blocks[0] as usize;
+        assert_ne!(fs.read_block(block), data[..BLOCK_SIZE]);
+        let dir_block = fs.stat("/sec").unwrap().blocks[0] as usize;
+        let raw = fs.read_block(dir_block);
+        assert!(!raw.windows(5).any(|x| x == b"creds"));
+
+        fs.set_passphrase("wrong");
+        assert!(matches!(
+            fs.read_path("/sec/creds"),
+            Err(FsError::Locked(_))
+        ));
+        assert!(matches!(
+            fs.write_path("/sec/new", b"x"),
+            Err(FsError::Locked(_))
+        ));
+
+        fs.set_passphrase("secret");
+        assert_eq!(fs.read_path("/sec/creds").unwrap(), data);
+    }
+
+    #[test]
+    fn random_differs() {
+        assert_ne!(random::<16>(), random::<16>());
+    }
+}
@@ -0,0 +1,145 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::core::dir::DirItem;
+
+pub const DEFAULT_DENTRY_CACHE: usize = 128;
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DentryCacheStats {
+    pub capacity: usize,
+    pub cached: usize,
+    pub negative: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    item: Option<DirItem>,
+    last_used: u64,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    entries: HashMap<(usize, String), Entry>,
+    tick: u64,
+    hits: u64,
+    misses: u64,
+}
+
+// 缓存 (父目录, 名称) 到目录项的映射，None 表示该名称不存在
+#[derive(Debug)]
+pub struct DentryCache {
+    capacity: usize,
+    inner: RefCell<Inner>,
+}
+
+impl DentryCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            inner: RefCell::new(Inner::default()),
+        }
+    }
+
+    pub fn lookup(&self, parent: usize, name: &str) -> Option<Option<DirItem>> {
+        let mut inner = self.inner.borrow_mut();
+        inner.tick += 1;
+        let tick = inner.tick;
+
+        match inner.entries.get_mut(&(parent, name.to_string())) {
+            Some(entry) => {
+                entry.last_used = tick;
+                let item = entry.item.clone();
+                inner.hits += 1;
+                Some(item)
+            }
+            None => {
+                inner.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub fn insert(&self, parent: usize, name: &str, item: Option<DirItem>) {
+        let mut inner = self.inner.borrow_mut();
+
+        while inner.entries.len() >= self.capacity {
+            let Some(victim) = inner
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            inner.entries.remove(&victim);
+        }
+
+        let last_used = inner.tick;
+        inner
+            .entries
+            .insert((parent, name.to_string()), Entry { item, last_used });
+    }
+
+    pub fn invalidate_dir(&mut self, parent: usize) {
+        self.inner
+            .get_mut()
+            .entries
+            .retain(|(dir, _), _| *dir != parent);
+    }
+
+    pub fn clear(&mut self) {
+        self.inner.get_mut().entries.clear();
+    }
+
+    pub fn stats(&self) -> DentryCacheStats {
+        let inner = self.inner.borrow();
+        DentryCacheStats {
+            capacity: self.capacity,
+            cached: inner.entries.len(),
+            negative: inner.entries.values().filter(|x| x.item.is_none()).count(),
+            hits: inner.hits,
+            misses: inner.misses,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::core::error::FsError;
+    use crate::core::fs::{FormatOptions, System};
+    use crate::core::hardware::Hardware;
+
+    fn not_found(fs: &System, path: &str) -> bool {
+        matches!(fs.stat(path), Err(FsError::NotFound(_)))
+    }
+
+    #[test]
+    fn negative_entries_invalidated() {
+        let mut fs = System::format(Hardware::new(), FormatOptions::default()).unwrap();
+        fs.create_dir_all("/d").unwrap();
+
+        // 查找不存在的名称会留下负目录项
+        assert!(not_found(&fs, "/d/a"));
+        assert!(not_found(&fs, "/d/a"));
+        let stats = fs.dentry_cache_stats();
+        assert!(stats.negative > 0 && stats.hits > 0);
+
+        fs.write_path("/d/a", b"x").unwrap();
+        assert_eq!(fs.read_path("/d/a").unwrap(), b"x");
+
+        assert!(not_found(&fs, "/d/b"));
+        fs.rename("/d/a", "/d/b").unwrap();
+        assert_eq!(fs.read_path("/d/b").unwrap(), b"x");
+        assert!(not_found(&fs, "/d/a"));
+
+        // 移动到另一个目录时，两边的目录项都要失效
+        fs.create_dir_all("/e").unwrap();
+        assert!(not_found(&fs, "/e/b"));
+        fs.rename("/d/b", "/e/b").unwrap();
+        assert_eq!(fs.read_path("/e/b").unwrap(), b"x");
+        assert!(not_found(&fs, "/d/b"));
+    }
+}
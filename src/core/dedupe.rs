@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+
+use crate::core::alloc::MAX_REFS;
+use crate::core::crc32c;
+use crate::core::error::Result;
+use crate::core::extent;
+use crate::core::fs::System;
+use crate::core::inode::MAX_BLOCKS_PER_INODE;
+use crate::core::superblock::FEATURE_INCOMPAT_SHARED_BLOCKS;
+
+#[derive(Debug, Default)]
+pub struct DedupeReport {
+    pub files: usize,
+    pub blocks: usize,
+    pub merged: usize,
+    pub freed: usize,
+    // 区段叶子块不够用而跳过的文件
+    pub skipped: usize,
+}
+
+impl System {
+    // 比较所有文件和符号链接的数据块，内容相同的块只保留一份，由各个文件共享。
+    // 比较的是写入磁盘的内容，因此压缩和加密的文件同样适用；目录块的校验和与
+    // 所属目录有关，不参与合并。
+    pub fn dedupe(&mut self) -> Result<DedupeReport> {
+        self.check_writable()?;
+        self.flush_delayed()?;
+
+        let mut report = DedupeReport::default();
+        let mut known: HashMap<u32, Vec<u32>> = HashMap::new();
+        for index in 0..self.free_inodes.len() {
+            if !self.free_inodes[index] || self.inode(index)?.borrow().is_dir() {
+                continue;
+            }
+            report.files += 1;
+
+            let blocks = self.inode_blocks(index)?;
+            report.blocks += blocks.len();
+            let merged = self.find_duplicates(&blocks, &mut known);
+            if merged == blocks {
+                continue;
+            }
+            if !self.remap_blocks(index, &blocks, &merged, &mut report)? {
+                report.skipped += 1;
+            }
+        }
+
+        if report.merged > 0 {
+            self.super_block.feature_incompat |= FEATURE_INCOMPAT_SHARED_BLOCKS;
+            // 区段叶子块的数量可能变化
+            self.count_quota_usage()?;
+        }
+        Ok(report)
+    }
+
+    // 返回合并后每个逻辑块应指向的块；按校验和分桶，再逐字节比较
+    fn find_duplicates(&self, blocks: &[u32], known: &mut HashMap<u32, Vec<u32>>) -> Vec<u32> {
+        let mut added: HashMap<u32, usize> = HashMap::new();
+        let mut merged = Vec::with_capacity(blocks.len());
+        for block_pos in blocks.iter().copied() {
+            let data = self.read_block(block_pos as usize);
+            let candidates = known.entry(crc32c::crc32c(&data)).or_default();
+            let target = candidates.iter().copied().find(|x| {
+                *x == block_pos
+                    || (self.allocator.refcount(*x as usize) as usize + added.get(x).unwrap_or(&0)
+                        < MAX_REFS as usize
+                        && self.read_block(*x as usize) == data)
+            });
+            match target {
+                Some(target) => {
+                    if target != block_pos {
+                        *added.entry(target).or_default() += 1;
+                    }
+                    merged.push(target);
+                }
+                None => {
+                    candidates.push(block_pos);
+                    merged.push(block_pos);
+                }
+            }
+        }
+        merged
+    }
+
+    fn remap_blocks(
+        &mut self,
+        index: usize,
+        blocks: &[u32],
+        merged: &[u32],
+        report: &mut DedupeReport,
+    ) -> Result<bool> {
+        let uses_extents = self.inode(index)?.borrow().uses_extents();
+        let old_mapping = self.mapping_blocks(index)?;
+        // 合并后的块可能不再连续，需要更多的区段
+        if uses_extents {
+            let extents = extent::from_blocks(merged).len();
+            match extent::leaves_needed(extents) {
+                Some(leaves)
+                    if leaves
+                        <= self.allocator.available() + self.exclusive_blocks(&old_mapping) => {}
+                _ => return Ok(false),
+            }
+        }
+
+        for (old, new) in blocks.iter().zip(merged) {
+            if old != new {
+                self.allocator.add_ref(*new as usize);
+                self.release_block(*old as usize);
+                report.merged += 1;
+                if !self.allocator.is_used(*old as usize) {
+                    report.freed += 1;
+                }
+            }
+        }
+
+        let block_map = if uses_extents {
+            for block_pos in old_mapping {
+                self.release_block(block_pos as usize);
+            }
+            self.build_extent_map(index, merged)?
+        } else {
+            let mut block_map = merged.to_vec();
+            block_map.resize(MAX_BLOCKS_PER_INODE, 0);
+            block_map
+        };
+        self.inode_mut(index)?.borrow_mut().block_pos = block_map;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::fs::FormatOptions;
+    use crate::core::hardware::{Hardware, BLOCK_SIZE};
+
+    #[test]
+    fn dedupe_then_copy_on_write() {
+        let mut fs = System::format(Hardware::new(), FormatOptions::default()).unwrap();
+        let data: Vec<u8> = (0..BLOCK_SIZE * 2).map(|x| (x % 251) as u8).collect();
+        fs.write_path("/a", &data).unwrap();
+        fs.write_path("/b", &data).unwrap();
+        fs.sync().unwrap();
+        let free = fs.statfs().free_blocks;
+
+        let report = fs.dedupe().unwrap();
+        assert_eq!((report.merged, report.freed), (2, 2));
+        assert_eq!(fs.statfs().free_blocks, free + 2);
+        let blocks = fs.stat("/a").unwrap().blocks;
+        assert_eq!(fs.stat("/b").unwrap().blocks, blocks);
+        assert!(blocks
+            .iter()
+            .all(|x| fs.allocator.refcount(*x as usize) == 2));
+
+        // 合并后改写其中一个文件，另一个不受影响
+        let mut changed = data.clone();
+        changed[0] ^= 0xff;
+        fs.write_path("/b", &changed).unwrap();
+        fs.sync().unwrap();
+        assert_eq!(fs.read_path("/a").unwrap(), data);
+        assert_eq!(fs.read_path("/b").unwrap(), changed);
+        assert!(blocks
+            .iter()
+            .all(|x| fs.allocator.refcount(*x as usize) == 1));
+
+        // 改写后第二块仍然相同，可以再次合并
+        assert_eq!(fs.dedupe().unwrap().merged, 1);
+        assert_eq!(fs.dedupe().unwrap().merged, 0);
+        assert_eq!(fs.read_path("/b").unwrap(), changed);
+    }
+}
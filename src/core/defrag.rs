@@ -0,0 +1,367 @@
+use std::collections::HashSet;
+
+use crate::core::error::{FsError, Result};
+use crate::core::extent;
+use crate::core::fs::{join_path, System};
+use crate::core::inode::MAX_BLOCKS_PER_INODE;
+use crate::core::snapshot::SNAPSHOT_CHECKSUM_OFFSET;
+use crate::core::superblock::FEATURE_INCOMPAT_RECOVER;
+
+// 日志块以记录数开头，每条记录依次是索引节点号、新位置的起始块和块数、旧块数，
+// 随后是全部旧块（数据块和区段叶子块），都是 u32；块末尾是校验和
+const JOURNAL_WORDS: usize = SNAPSHOT_CHECKSUM_OFFSET / 4;
+const RECORD_HEADER: usize = 4;
+
+#[derive(Debug, Default)]
+pub struct DefragReport {
+    pub files: usize,
+    pub extents_before: usize,
+    pub extents_after: usize,
+    // 与快照或其他文件共享数据块、找不到足够长的空闲区间、或者旧块太多记不进日志而跳过的文件
+    pub skipped: usize,
+    // 整理过的文件及其整理前后的区段数
+    pub moved: Vec<(String, usize, usize)>,
+}
+
+// 一个文件的搬迁：数据已复制到 start 开始的 len 个块，old 是要释放的旧块
+#[derive(Debug)]
+struct Move {
+    index: usize,
+    start: u32,
+    len: u32,
+    old: Vec<u32>,
+}
+
+impl Move {
+    fn words(&self) -> usize {
+        RECORD_HEADER + self.old.len()
+    }
+}
+
+fn encode_journal(moves: &[Move]) -> Vec<u8> {
+    let mut words = vec![moves.len() as u32];
+    for mv in moves {
+        words.extend([mv.index as u32, mv.start, mv.len, mv.old.len() as u32]);
+        words.extend_from_slice(&mv.old);
+    }
+    words.iter().flat_map(|x| x.to_le_bytes()).collect()
+}
+
+fn decode_journal(data: &[u8]) -> Option<Vec<Move>> {
+    let words: Vec<u32> = data[..SNAPSHOT_CHECKSUM_OFFSET]
+        .chunks_exact(4)
+        .map(|x| u32::from_le_bytes(x.try_into().unwrap()))
+        .collect();
+    let count = *words.first()? as usize;
+    let mut pos = 1;
+    let mut moves = Vec::new();
+    for _ in 0..count {
+        let header = words.get(pos..pos + RECORD_HEADER)?;
+        let old = words.get(pos + RECORD_HEADER..pos + RECORD_HEADER + header[3] as usize)?;
+        moves.push(Move {
+            index: header[0] as usize,
+            start: header[1],
+            len: header[2],
+            old: old.to_vec(),
+        });
+        pos += RECORD_HEADER + old.len();
+    }
+    Some(moves)
+}
+
+impl System {
+    // 把 path 下（或 path 本身）有多个区段的文件搬到一段连续的空闲块中。
+    // 数据先复制到新位置，一批文件的搬迁记录写入日志块并在超级块中标记后写回，
+    // 再切换块指针、释放旧块；中途崩溃时挂载会按日志重做切换，见 replay_defrag_journal。
+    pub fn defrag(&mut self, path: &str) -> Result<DefragReport> {
+        self.check_writable()?;
+        self.flush_delayed()?;
+
+        let mut files = Vec::new();
+        let stat = self.stat(path)?;
+        if stat.typ == "dir" {
+            self.defrag_dir(path, &mut files, &mut HashSet::new())?;
+        } else {
+            files.push((path.to_string(), stat.inode_index));
+        }
+
+        let mut report = DefragReport::default();
+        let mut batch: Vec<Move> = Vec::new();
+        let mut used = 1;
+        for (path, index) in files {
+            let blocks = self.inode_blocks(index)?;
+            let before = extent::from_blocks(&blocks).len();
+            report.files += 1;
+            report.extents_before += before;
+            if before <= 1 {
+                report.extents_after += before;
+                continue;
+            }
+            let old_len = blocks.len() + self.mapping_blocks(index)?.len();
+            if 1 + RECORD_HEADER + old_len > JOURNAL_WORDS {
+                report.extents_after += before;
+                report.skipped += 1;
+                continue;
+            }
+            if used + RECORD_HEADER + old_len > JOURNAL_WORDS {
+                self.commit_moves(&batch)?;
+                batch.clear();
+                used = 1;
+            }
+
+            match self.prepare_move(index, &blocks) {
+                Ok(Some(mv)) => {
+                    used += mv.words();
+                    batch.push(mv);
+                    report.extents_after += 1;
+                    report.moved.push((path, before, 1));
+                }
+                Ok(None) => {
+                    report.extents_after += before;
+                    report.skipped += 1;
+                }
+                Err(e) => {
+                    // 已经准备好的搬迁照常完成，不让它们的目标块泄漏
+                    self.commit_moves(&batch)?;
+                    return Err(e);
+                }
+            }
+        }
+        self.commit_moves(&batch)?;
+
+        if !report.moved.is_empty() {
+            // 区段叶子块的数量变了
+            self.count_quota_usage()?;
+        }
+        Ok(report)
+    }
+
+    fn defrag_dir(
+        &self,
+        path: &str,
+        files: &mut Vec<(String, usize)>,
+        seen: &mut HashSet<usize>,
+    ) -> Result<()> {
+        let dir = self.open_dir_path(path)?;
+        for item in dir.items.iter() {
+            if item.name == "." || item.name == ".." {
+                continue;
+            }
+            let child = join_path(path, &item.name);
+            if item.typ == "dir" {
+                self.defrag_dir(&child, files, seen)?;
+            } else if seen.insert(item.inode_pos as usize) {
+                files.push((child, item.inode_pos as usize));
+            }
+        }
+        Ok(())
+    }
+
+    // 分配连续的目标块并复制数据，还不修改索引节点；
+    // 共享的块搬走后会多占一份空间，这样的文件不动，返回 None
+    fn prepare_move(&mut self, index: usize, blocks: &[u32]) -> Result<Option<Move>> {
+        if blocks
+            .iter()
+            .any(|x| self.allocator.refcount(*x as usize) > 1)
+        {
+            return Ok(None);
+        }
+        let goal = blocks[0] as usize;
+        let target = match self.allocator.allocate_run(blocks.len(), goal) {
+            Some(target) => target,
+            None => return Ok(None),
+        };
+        // 先确认新的块映射建得出来，失败时归还目标块
+        if let Err(e) = self.contiguous_map(index, &target) {
+            for block_pos in target.iter() {
+                self.release_block(*block_pos as usize);
+            }
+            return Err(e);
+        }
+
+        for (old, new) in blocks.iter().zip(target.iter()) {
+            let data = self.read_block(*old as usize);
+            self.write_into_block(*new as usize, &data);
+            self.data_csums[*new as usize] = self.data_csums[*old as usize];
+        }
+        let mut old = blocks.to_vec();
+        old.extend(self.mapping_blocks(index)?);
+        Ok(Some(Move {
+            index,
+            start: target[0],
+            len: target.len() as u32,
+            old,
+        }))
+    }
+
+    // 连续的块用一个区段（或直接块列表）表示，不需要分配叶子块
+    fn contiguous_map(&mut self, index: usize, target: &[u32]) -> Result<Vec<u32>> {
+        if self.inode(index)?.borrow().uses_extents() {
+            self.build_extent_map(index, target)
+        } else {
+            let mut block_map = target.to_vec();
+            block_map.resize(MAX_BLOCKS_PER_INODE, 0);
+            Ok(block_map)
+        }
+    }
+
+    // 三次写回：新数据和日志、切换后的元数据、清除日志标记。
+    // 超级块的日志标记在第二次写回完成之前一直保留
+    fn commit_moves(&mut self, moves: &[Move]) -> Result<()> {
+        if moves.is_empty() {
+            return Ok(());
+        }
+        let journal = match self.allocator.allocate(1, None, None) {
+            Some(blocks) => blocks[0] as usize,
+            None => {
+                for mv in moves {
+                    for block_pos in mv.start..mv.start + mv.len {
+                        self.release_block(block_pos as usize);
+                    }
+                }
+                return Err(FsError::NoFreeBlocks);
+            }
+        };
+        self.write_sealed(journal, encode_journal(moves));
+        self.super_block.journal_block = journal as u32;
+        self.super_block.feature_incompat |= FEATURE_INCOMPAT_RECOVER;
+        self.sync()?;
+
+        for mv in moves {
+            self.apply_move(mv)?;
+        }
+        self.finish_journal(journal)
+    }
+
+    // 重做时旧块可能已经释放过，release_block 对空闲块不起作用
+    fn apply_move(&mut self, mv: &Move) -> Result<()> {
+        let target: Vec<u32> = (mv.start..mv.start + mv.len).collect();
+        let block_map = self.contiguous_map(mv.index, &target)?;
+        self.inode_mut(mv.index)?.borrow_mut().block_pos = block_map;
+        for block_pos in mv.old.iter() {
+            self.release_block(*block_pos as usize);
+        }
+        Ok(())
+    }
+
+    fn finish_journal(&mut self, journal: usize) -> Result<()> {
+        self.sync()?;
+        self.super_block.feature_incompat &= !FEATURE_INCOMPAT_RECOVER;
+        self.super_block.journal_block = 0;
+        self.release_block(journal);
+        self.sync()?;
+        Ok(())
+    }
+
+    // 挂载时重做未完成的碎片整理：日志写回之后，新位置的数据已经完整
+    pub(crate) fn replay_defrag_journal(&mut self) -> Result<()> {
+        if self.super_block.feature_incompat & FEATURE_INCOMPAT_RECOVER == 0 {
+            return Ok(());
+        }
+        let journal = self.super_block.journal_block as usize;
+        let corrupted = || FsError::Corrupted {
+            what: String::from("碎片整理日志"),
+            block: journal,
+        };
+        let data = self.read_sealed(journal, "碎片整理日志")?;
+        let moves = decode_journal(&data).ok_or_else(corrupted)?;
+        let total = self.layout.total_blocks as u32;
+        for mv in moves.iter() {
+            let in_range = mv.len > 0
+                && mv.start.checked_add(mv.len).is_some_and(|end| end <= total)
+                && mv.old.iter().all(|x| *x < total);
+            if !in_range || self.free_inodes.get(mv.index) != Some(&true) {
+                return Err(corrupted());
+            }
+            for block_pos in mv.start..mv.start + mv.len {
+                self.allocator.set_used(block_pos as usize, true);
+            }
+            self.apply_move(mv)?;
+        }
+        self.finish_journal(journal)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::fs::{FormatOptions, MountOptions};
+    use crate::core::hardware::{Hardware, BLOCK_SIZE};
+
+    // 碎片整理的日志已写回、块指针还没切换时崩溃的镜像，返回镜像路径、大文件的索引节点和日志块
+    fn crashed_image(name: &str) -> (String, usize, usize) {
+        let options = FormatOptions {
+            extents: true,
+            ..Default::default()
+        };
+        let image = std::env::temp_dir().join(format!("file-sys-{}-{}", name, std::process::id()));
+        let image = image.to_str().unwrap().to_string();
+        let mut fs = System::format(Hardware::load(&image).unwrap(), options).unwrap();
+        // 占满磁盘后隔一个删一个，大文件只能分散在空洞里
+        let mut count = 0;
+        while fs.write_path(&format!("/f{}", count), b"x").is_ok() && fs.sync().is_ok() {
+            count += 1;
+        }
+        for i in (0..count).step_by(2) {
+            fs.remove_path(&format!("/f{}", i), false).unwrap();
+        }
+        fs.sync().unwrap();
+        fs.write_path("/big", &big()).unwrap();
+        fs.sync().unwrap();
+        for i in (1..count).step_by(2).skip(1) {
+            fs.remove_path(&format!("/f{}", i), false).unwrap();
+        }
+        fs.sync().unwrap();
+        let index = fs.stat("/big").unwrap().inode_index;
+        let blocks = fs.inode_blocks(index).unwrap();
+        assert!(extent::from_blocks(&blocks).len() > 1);
+
+        let mv = fs.prepare_move(index, &blocks).unwrap().unwrap();
+        let journal = fs.allocator.allocate(1, None, None).unwrap()[0] as usize;
+        fs.write_sealed(journal, encode_journal(&[mv]));
+        fs.super_block.journal_block = journal as u32;
+        fs.super_block.feature_incompat |= FEATURE_INCOMPAT_RECOVER;
+        fs.sync().unwrap();
+        // 不经过 drop，模拟崩溃
+        std::mem::forget(fs);
+        (image, index, journal)
+    }
+
+    fn big() -> Vec<u8> {
+        (0..BLOCK_SIZE * 3).map(|x| (x % 251) as u8).collect()
+    }
+
+    #[test]
+    fn replay_after_crash() {
+        let (image, index, journal) = crashed_image("defrag");
+        let fs = System::init(Hardware::load(&image).unwrap()).unwrap();
+        assert_eq!(
+            fs.super_block.feature_incompat & FEATURE_INCOMPAT_RECOVER,
+            0
+        );
+        assert!(!fs.allocator.is_used(journal));
+        let blocks = fs.inode_blocks(index).unwrap();
+        assert_eq!(extent::from_blocks(&blocks).len(), 1);
+        assert_eq!(fs.read_path("/big").unwrap(), big());
+        assert_eq!(fs.read_path("/f1").unwrap(), b"x");
+        drop(fs);
+        std::fs::remove_file(&image).unwrap();
+    }
+
+    #[test]
+    fn read_only_refuses_pending_journal() {
+        let (image, _, _) = crashed_image("defrag-ro");
+        let before = std::fs::read(&image).unwrap();
+        let options = MountOptions {
+            read_only: true,
+            ..Default::default()
+        };
+        let hardware = Hardware::load_read_only(&image).unwrap();
+        assert!(matches!(
+            System::mount(hardware, options),
+            Err(FsError::NeedsRecovery)
+        ));
+        assert_eq!(std::fs::read(&image).unwrap(), before);
+        std::fs::remove_file(&image).unwrap();
+    }
+}
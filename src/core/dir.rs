@@ -1,3 +1,9 @@
+use crate::core::crc32c;
+use crate::core::hardware::BLOCK_SIZE;
+
+// 启用元数据校验和时，目录数据按块切分，每块最后4字节保存校验和
+pub const DIR_BLOCK_PAYLOAD: usize = BLOCK_SIZE - 4;
+
 #[derive(Debug)]
 pub struct Dir {
     pub name: String,
@@ -9,44 +15,53 @@ impl Dir {
     pub fn new(name: &str, inode_index: usize) -> Self {
         Self {
             name: name.to_string(),
-            inode_index: inode_index,
+            inode_index,
             items: Vec::new(),
         }
     }
 
-    pub fn from_block_bytes(name: &str, inode_index: usize, data: &[u8]) -> Self {
+    // 数据被截断或名称不是合法的 UTF-8 时返回 None
+    pub fn from_block_bytes(name: &str, inode_index: usize, data: &[u8]) -> Option<Self> {
         let mut dir = Self::new(name, inode_index);
         let mut i = 0;
 
+        let word = |i: usize| -> Option<u32> {
+            Some(u32::from_le_bytes(data.get(i..i + 4)?.try_into().unwrap()))
+        };
+        let text = |i: usize, len: u32| -> Option<String> {
+            let bytes = data.get(i..i.checked_add(len as usize)?)?;
+            String::from_utf8(bytes.to_vec()).ok()
+        };
+
         while i < data.len() {
-            let inode_pos = u32::from_le_bytes(data[i..i + 4].try_into().unwrap());
+            let inode_pos = word(i)?;
             if inode_pos == 0xDEADBEAF {
                 break;
             }
             i += 4;
-            
-            let name_len = u32::from_le_bytes(data[i..i + 4].try_into().unwrap());
-            let name = String::from_utf8(data[i + 4..i + 4 + name_len as usize].to_vec()).unwrap();
+
+            let name_len = word(i)?;
+            let name = text(i + 4, name_len)?;
 
             i += 4 + name_len as usize;
 
-            let typ_len = u32::from_le_bytes(data[i ..i + 4 ].try_into().unwrap());
-            let typ = String::from_utf8(data[i + 4..i + 4 + typ_len as usize].to_vec()).unwrap();
-            
+            let typ_len = word(i)?;
+            let typ = text(i + 4, typ_len)?;
+
             i += 4 + typ_len as usize;
 
-            let size = u32::from_le_bytes(data[i..i + 4].try_into().unwrap());
+            let size = word(i)?;
             i += 4;
 
             dir.items.push(DirItem {
-                inode_pos: inode_pos,
-                name: name,
-                typ: typ,
-                size: size,
+                inode_pos,
+                name,
+                typ,
+                size,
             })
         }
 
-        dir
+        Some(dir)
     }
 
     pub fn to_block_bytes(&self) -> Vec<u8> {
@@ -77,7 +92,20 @@ impl Dir {
 
         data
     }
-    
+
+    // 把目录数据切分成带校验和的块，校验和覆盖目录的索引节点号和块在目录中的序号
+    pub fn to_checked_blocks(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        for (i, chunk) in self.to_block_bytes().chunks(DIR_BLOCK_PAYLOAD).enumerate() {
+            let mut block = chunk.to_vec();
+            block.resize(DIR_BLOCK_PAYLOAD, 0);
+            let checksum = block_checksum(self.inode_index, i, &block);
+            data.extend_from_slice(&block);
+            data.extend_from_slice(&checksum.to_le_bytes());
+        }
+        data
+    }
+
     pub fn show(&self) {
         if !self.items.is_empty() {
             println!("---Name---\t---Type---\t---Size---");
@@ -103,10 +131,15 @@ impl Dir {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct DirItem {
     pub inode_pos: u32,
     pub name: String,
     pub typ: String,
     pub size: u32,
-}
\ No newline at end of file
+}
+
+pub fn block_checksum(inode_index: usize, logical: usize, payload: &[u8]) -> u32 {
+    let crc = crc32c::owned(inode_index, &(logical as u32).to_le_bytes());
+    crc32c::update(crc, &payload[..DIR_BLOCK_PAYLOAD])
+}
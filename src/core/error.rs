@@ -0,0 +1,143 @@
+use std::fmt;
+
+use crate::core::fs::MAX_RESERVED_PERCENT;
+use crate::core::trash::MAX_TRASH_THRESHOLD;
+
+#[derive(Debug)]
+pub enum FsError {
+    Unformatted,
+    OutdatedVersion(u32),
+    UnsupportedVersion(u32),
+    UnsupportedFeatures { ro_compat: u32, incompat: u32 },
+    NeedsRecovery,
+    InvalidLayout(String),
+    BadSuperBlock,
+    NoSuperBlockCopy(usize),
+    Corrupted { what: String, block: usize },
+    TooManySnapshots(usize),
+    TooManyRefs(usize),
+    HasSnapshots(&'static str),
+    NoPassphrase,
+    Locked(String),
+    AlreadyEncrypted(String),
+    PolicyMismatch(String),
+    QuotaExceeded(String),
+    QuotaDisabled,
+    TooManyQuotas(usize),
+    NotPermitted(String),
+    InvalidReserve(u32),
+    TrashDisabled,
+    NoTrashEntry(u32),
+    TrashInUse(String),
+    InvalidThreshold(u32),
+    NotFound(String),
+    AlreadyExists(String),
+    NotADirectory(String),
+    IsADirectory(String),
+    NotAFile(String),
+    NotASymlink(String),
+    TooManyLinks(String),
+    InvalidName(String),
+    NameTooLong(String),
+    NotEmpty(String),
+    InvalidMove(String),
+    FileTooLarge(usize),
+    NoFreeBlocks,
+    NoFreeInodes,
+    ReadOnly,
+    InvalidArchive(&'static str),
+    Host(String, std::io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, FsError>;
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FsError::Unformatted => write!(f, "镜像尚未格式化"),
+            FsError::OutdatedVersion(version) => {
+                write!(f, "镜像格式版本 {} 过旧，请先运行 fs-upgrade", version)
+            }
+            FsError::UnsupportedVersion(version) => {
+                write!(f, "不支持的镜像格式版本 {}", version)
+            }
+            FsError::UnsupportedFeatures {
+                ro_compat,
+                incompat,
+            } => write!(
+                f,
+                "镜像包含未知特性 (ro_compat: {:#x}, incompat: {:#x})",
+                ro_compat, incompat
+            ),
+            FsError::NeedsRecovery => {
+                write!(f, "碎片整理没有完成，需要先以读写方式挂载一次重放日志")
+            }
+            FsError::InvalidLayout(msg) => write!(f, "无效的磁盘布局: {}", msg),
+            FsError::BadSuperBlock => write!(
+                f,
+                "超级块已损坏且没有可用的副本，可用 --superblock 指定副本位置"
+            ),
+            FsError::NoSuperBlockCopy(block_pos) => {
+                write!(f, "第{}块没有有效的超级块副本", block_pos)
+            }
+            FsError::Corrupted { what, block } => {
+                write!(f, "{}: 已损坏（第{}块）", what, block)
+            }
+            FsError::TooManySnapshots(max) => write!(f, "快照数量已达上限 ({})", max),
+            FsError::TooManyRefs(block_pos) => {
+                write!(f, "第{}块的引用计数已达上限", block_pos)
+            }
+            FsError::HasSnapshots(what) => {
+                write!(f, "存在快照时不能{}，请先删除所有快照", what)
+            }
+            FsError::NoPassphrase => write!(f, "挂载时没有提供口令（--passphrase）"),
+            FsError::Locked(name) => write!(f, "{}: 已加密，需要正确的口令才能访问", name),
+            FsError::AlreadyEncrypted(name) => write!(f, "{}: 已经加密", name),
+            FsError::PolicyMismatch(name) => {
+                write!(f, "{}: 与目标目录的加密策略不同", name)
+            }
+            FsError::QuotaExceeded(what) => write!(f, "{}配额已用完", what),
+            FsError::QuotaDisabled => write!(f, "未启用配额"),
+            FsError::TooManyQuotas(max) => write!(f, "配额记录数量已达上限 ({})", max),
+            FsError::NotPermitted(name) => write!(f, "{}: 需要 root 权限", name),
+            FsError::InvalidReserve(percent) => {
+                write!(
+                    f,
+                    "保留块比例 {}% 超过上限 ({}%)",
+                    percent, MAX_RESERVED_PERCENT
+                )
+            }
+            FsError::TrashDisabled => write!(f, "未启用回收站（trash on）"),
+            FsError::NoTrashEntry(id) => write!(f, "回收站中没有编号为 {} 的条目", id),
+            FsError::TrashInUse(name) => {
+                write!(f, "{}: 回收站启用时不能删除或移动（trash off）", name)
+            }
+            FsError::InvalidThreshold(percent) => {
+                write!(
+                    f,
+                    "回收站清理阈值 {}% 超过上限 ({}%)",
+                    percent, MAX_TRASH_THRESHOLD
+                )
+            }
+            FsError::NotFound(name) => write!(f, "{}: 不存在", name),
+            FsError::AlreadyExists(name) => write!(f, "{}: 已存在", name),
+            FsError::NotADirectory(name) => write!(f, "{}: 不是目录", name),
+            FsError::IsADirectory(name) => write!(f, "{}: 是目录", name),
+            FsError::NotAFile(name) => write!(f, "{}: 不是普通文件", name),
+            FsError::NotASymlink(name) => write!(f, "{}: 不是符号链接", name),
+            FsError::TooManyLinks(name) => write!(f, "{}: 符号链接层数过多", name),
+            FsError::InvalidName(name) => write!(f, "{:?}: 非法的名称", name),
+            FsError::NameTooLong(name) => write!(f, "{}: 名称过长", name),
+            FsError::NotEmpty(name) => write!(f, "{}: 目录不为空", name),
+            FsError::InvalidMove(name) => write!(f, "{}: 不能移动到自身的子目录中", name),
+            FsError::FileTooLarge(size) => write!(f, "文件过大 ({}B)", size),
+            FsError::NoFreeBlocks => write!(f, "没有空闲的数据块"),
+            FsError::NoFreeInodes => write!(f, "没有空闲的索引节点"),
+            FsError::ReadOnly => write!(f, "文件系统以只读方式挂载"),
+            FsError::InvalidArchive(msg) => write!(f, "无效的 tar 归档: {}", msg),
+            FsError::Host(path, e) => write!(f, "{}: {}", path, e),
+        }
+    }
+}
+
+impl std::error::Error for FsError {}
@@ -0,0 +1,122 @@
+use crate::core::crc32c;
+use crate::core::hardware::BLOCK_SIZE;
+
+// 区段映射的索引节点复用7个块指针的位置：第1个字为头部（低16位为条目数，高16位为深度），
+// 其余6个字存放3个条目。深度为0时条目就是区段，深度为1时条目指向存放区段的叶子块。
+pub const INLINE_ENTRIES: usize = 3;
+pub const LEAF_ENTRIES: usize = (BLOCK_SIZE - 4) / 8;
+// 叶子块最后4字节保存校验和
+pub const LEAF_CHECKSUM_OFFSET: usize = 4 + LEAF_ENTRIES * 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    pub start: u32,
+    pub len: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExtentIndex {
+    pub first_block: u32,
+    pub leaf: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExtentRoot {
+    Leaf(Vec<Extent>),
+    Index(Vec<ExtentIndex>),
+}
+
+impl ExtentRoot {
+    pub fn decode(words: &[u32]) -> Self {
+        let entries = (words[0] & 0xFFFF) as usize;
+        let depth = words[0] >> 16;
+        let pairs = words[1..]
+            .chunks_exact(2)
+            .take(entries.min(INLINE_ENTRIES))
+            .map(|x| (x[0], x[1]));
+
+        if depth == 0 {
+            ExtentRoot::Leaf(pairs.map(|(start, len)| Extent { start, len }).collect())
+        } else {
+            ExtentRoot::Index(
+                pairs
+                    .map(|(first_block, leaf)| ExtentIndex { first_block, leaf })
+                    .collect(),
+            )
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u32> {
+        let (depth, pairs): (u32, Vec<(u32, u32)>) = match self {
+            ExtentRoot::Leaf(extents) => (0, extents.iter().map(|x| (x.start, x.len)).collect()),
+            ExtentRoot::Index(indexes) => {
+                (1, indexes.iter().map(|x| (x.first_block, x.leaf)).collect())
+            }
+        };
+        assert!(pairs.len() <= INLINE_ENTRIES);
+
+        let mut words = vec![pairs.len() as u32 | depth << 16];
+        for (a, b) in pairs {
+            words.push(a);
+            words.push(b);
+        }
+        words.resize(1 + INLINE_ENTRIES * 2, 0);
+        words
+    }
+}
+
+pub fn decode_leaf(data: &[u8]) -> Vec<Extent> {
+    let entries = u32::from_le_bytes(data[0..4].try_into().unwrap()) as usize;
+    data[4..]
+        .chunks_exact(8)
+        .take(entries.min(LEAF_ENTRIES))
+        .map(|x| Extent {
+            start: u32::from_le_bytes(x[0..4].try_into().unwrap()),
+            len: u32::from_le_bytes(x[4..8].try_into().unwrap()),
+        })
+        .collect()
+}
+
+pub fn encode_leaf(extents: &[Extent]) -> Vec<u8> {
+    assert!(extents.len() <= LEAF_ENTRIES);
+    let mut data = (extents.len() as u32).to_le_bytes().to_vec();
+    for extent in extents {
+        data.extend_from_slice(&extent.start.to_le_bytes());
+        data.extend_from_slice(&extent.len.to_le_bytes());
+    }
+    data
+}
+
+pub fn leaf_checksum(inode_index: usize, data: &[u8]) -> u32 {
+    crc32c::owned(inode_index, &data[..LEAF_CHECKSUM_OFFSET])
+}
+
+pub fn from_blocks(blocks: &[u32]) -> Vec<Extent> {
+    let mut extents: Vec<Extent> = Vec::new();
+    for block in blocks {
+        match extents.last_mut() {
+            Some(last) if last.start + last.len == *block => last.len += 1,
+            _ => extents.push(Extent {
+                start: *block,
+                len: 1,
+            }),
+        }
+    }
+    extents
+}
+
+pub fn to_blocks(extents: &[Extent]) -> Vec<u32> {
+    extents
+        .iter()
+        .flat_map(|x| x.start..x.start + x.len)
+        .collect()
+}
+
+// 保存这些区段需要的叶子块数，超出区段树的容量时返回 None
+pub fn leaves_needed(extents: usize) -> Option<usize> {
+    if extents <= INLINE_ENTRIES {
+        return Some(0);
+    }
+    let leaves = extents.div_ceil(LEAF_ENTRIES);
+    (leaves <= INLINE_ENTRIES).then_some(leaves)
+}
@@ -1,4 +1,3 @@
-
 #[derive(Debug)]
 pub struct File {
     pub name: String,
@@ -11,7 +10,7 @@ impl File {
     pub fn new(name: &str, inode_index: usize) -> Self {
         Self {
             name: name.to_string(),
-            inode_index: inode_index,
+            inode_index,
             size: 0,
             content: "".to_string(),
         }
@@ -20,13 +19,8 @@ impl File {
     pub fn from_block_bytes(name: &str, inode_index: usize, data: &[u8]) -> Self {
         let mut file = Self::new(name, inode_index);
 
-        let data: Vec<u8> = data
-            .iter()
-            .filter(|x| **x != 0)
-            .map(|x| *x)
-            .collect();
-
-        let content = String::from_utf8(data.to_vec()).unwrap();
+        // data 已经按文件长度截断，其中的0字节也是内容的一部分
+        let content = String::from_utf8_lossy(data).to_string();
 
         let size = data.len() as u32;
 
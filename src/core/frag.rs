@@ -0,0 +1,74 @@
+use std::collections::HashSet;
+
+use crate::core::error::Result;
+use crate::core::extent;
+use crate::core::fs::{join_path, System};
+
+const WORST_FILES: usize = 5;
+
+#[derive(Debug, Default)]
+pub struct FragReport {
+    pub files: usize,
+    pub fragmented: usize,
+    pub extents: usize,
+    pub blocks: usize,
+    pub free_blocks: usize,
+    pub free_runs: usize,
+    pub largest_free_run: usize,
+    pub worst: Vec<(String, usize)>,
+}
+
+impl System {
+    // 尚未刷新的延迟写入还没有分配数据块，不计入统计
+    pub fn fragmentation(&self, path: &str) -> Result<FragReport> {
+        self.open_dir_path(path)?;
+
+        let mut report = FragReport::default();
+        let mut seen = HashSet::new();
+        self.frag_dir(path, &mut report, &mut seen)?;
+
+        report
+            .worst
+            .sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        report.worst.truncate(WORST_FILES);
+
+        let runs = self.allocator.free_runs();
+        report.free_blocks = runs.iter().map(|(_, len)| len).sum();
+        report.free_runs = runs.len();
+        report.largest_free_run = runs.iter().map(|(_, len)| *len).max().unwrap_or(0);
+        Ok(report)
+    }
+
+    fn frag_dir(
+        &self,
+        path: &str,
+        report: &mut FragReport,
+        seen: &mut HashSet<usize>,
+    ) -> Result<()> {
+        let dir = self.open_dir_path(path)?;
+        for item in dir.items.iter() {
+            if item.name == "." || item.name == ".." {
+                continue;
+            }
+            let child = join_path(path, &item.name);
+            if item.typ == "dir" {
+                self.frag_dir(&child, report, seen)?;
+                continue;
+            }
+            if !seen.insert(item.inode_pos as usize) {
+                continue;
+            }
+
+            let stat = self.stat(&child)?;
+            let extents = extent::from_blocks(&stat.blocks).len();
+            report.files += 1;
+            report.blocks += stat.blocks.len();
+            report.extents += extents;
+            if extents > 1 {
+                report.fragmented += 1;
+                report.worst.push((child, extents));
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,91 +1,307 @@
-use crate::core::dir::Dir;
-use crate::core::dir::DirItem;
+use std::borrow::Cow;
+use std::collections::{HashMap, VecDeque};
+
+use crate::core::alloc::{Allocator, MAX_REFS};
+
+use crate::core::cache::{BlockCache, CacheStats, DEFAULT_CACHE_BLOCKS};
+use crate::core::compress;
+use crate::core::crc32c;
+use crate::core::crypt::{self, Keyring, MAX_ENCRYPTED_NAME_LEN};
+use crate::core::dcache::{DentryCache, DentryCacheStats, DEFAULT_DENTRY_CACHE};
+use crate::core::dir::{self, Dir, DirItem, DIR_BLOCK_PAYLOAD};
+use crate::core::error::{FsError, Result};
+use crate::core::extent::{self, Extent, ExtentIndex, ExtentRoot};
 use crate::core::file::File;
+use crate::core::group::{
+    self, GroupDesc, Layout, BITMAP_CHECKSUM_OFFSET, DATA_CSUM_OFFSET, GROUP_BLOCKS,
+};
 use crate::core::hardware;
 use crate::core::hardware::Hardware;
-use crate::core::inode::Inode;
+use crate::core::icache::{InodeCache, InodeCacheStats, InodeRef, DEFAULT_INODE_CACHE};
+use crate::core::inode::{
+    self, Inode, DEFAULT_DIR_MODE, DEFAULT_FILE_MODE, DEFAULT_SYMLINK_MODE, INODE_CHECKSUM_OFFSET,
+    INODE_FLAG_COMPRESS, INODE_FLAG_ENCRYPTED, INODE_FLAG_EXTENTS, INODE_SIZE,
+    MAX_BLOCKS_PER_INODE, MAX_NAME_LEN, S_IFMT,
+};
+use crate::core::quota::Quotas;
+use crate::core::snapshot::{Snapshot, View};
+use crate::core::superblock::{
+    SuperBlock, FEATURE_COMPAT_RESERVED_BLOCKS, FEATURE_COMPAT_SB_BACKUP,
+    FEATURE_INCOMPAT_COMPRESSION, FEATURE_INCOMPAT_EXTENTS, FEATURE_INCOMPAT_GROUPS,
+    FEATURE_INCOMPAT_LINKS, FEATURE_INCOMPAT_METADATA_CSUM, FEATURE_INCOMPAT_SHARED_BLOCKS,
+    FEATURE_RO_COMPAT_DATA_CSUM,
+};
+
+const MAX_SYMLINK_DEPTH: usize = 8;
+pub const MAX_RESERVED_PERCENT: u32 = 50;
 
-const INIT_MAGIC: u32 = 0xDEADBEEF_u32;
+#[derive(Debug)]
+pub struct Stat {
+    pub inode_index: usize,
+    pub name: String,
+    pub typ: String,
+    pub size: u32,
+    pub mode: u16,
+    pub links: u16,
+    pub uid: u16,
+    pub project: u16,
+    pub compressed: bool,
+    pub encrypted: bool,
+    pub mtime: i64,
+    pub ctime: i64,
+    pub blocks: Vec<u32>,
+    pub extents: Vec<Extent>,
+    // 延迟分配的数据还没有写入时为它预留的块数，此时 blocks 为空
+    pub delayed_blocks: usize,
+}
+
+#[derive(Debug)]
+pub struct StatFs {
+    pub block_size: usize,
+    pub name_max: usize,
+    pub total_blocks: usize,
+    // 位图中的空闲块，与各块组的空闲块数之和一致
+    pub free_blocks: usize,
+    // 空闲块中已经预留给延迟分配写入的部分
+    pub delayed_blocks: usize,
+    // 当前用户可以分配的块：扣除延迟分配的预留，普通用户还不能使用保留块
+    pub available_blocks: usize,
+    pub reserved_blocks: usize,
+    pub reserved_percent: u32,
+    pub total_inodes: usize,
+    pub free_inodes: usize,
+    // 当前用户可以分配的索引节点，保留比例同样适用于索引节点
+    pub available_inodes: usize,
+    pub reserved_inodes: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MountOptions {
+    pub read_only: bool,
+    pub cache_blocks: usize,
+    pub delayed_alloc: bool,
+    // 指定从某个块的超级块副本挂载
+    pub super_block: Option<usize>,
+    // 当前用户，新建的索引节点归其所有并计入其配额，0 是 root
+    pub uid: u16,
+}
+
+impl Default for MountOptions {
+    fn default() -> Self {
+        Self {
+            read_only: false,
+            cache_blocks: DEFAULT_CACHE_BLOCKS,
+            delayed_alloc: true,
+            super_block: None,
+            uid: 0,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FormatOptions {
+    pub extents: bool,
+    // 启用块组并把设备调整为指定的块数，None 表示使用原来的单组布局
+    pub blocks: Option<usize>,
+    // 为索引节点、位图、目录块和区段叶子块加上校验和
+    pub checksums: bool,
+    // 为文件数据块加上校验和
+    pub data_checksums: bool,
+    // 只有 root 可以使用的块的百分比
+    pub reserved_percent: u32,
+}
 
 #[derive(Debug)]
 pub struct System {
     pub initialized: bool,
+    pub options: MountOptions,
     pub root_inode_index: usize,
     pub free_inodes: Vec<bool>,
-    pub free_blocks: Vec<bool>,
-    pub inodes: Vec<Inode>,
-    pub hardware: Hardware,
+    pub allocator: Allocator,
+    pub inodes: InodeCache,
+    pub dentries: DentryCache,
+    pub super_block: SuperBlock,
+    pub layout: Layout,
+    // 主超级块损坏、从副本恢复时记录副本所在的块
+    pub recovered_from: Option<usize>,
+    pub cache: BlockCache,
+    // 每个块的数据校验和，只在启用数据块校验和时读写
+    pub data_csums: Vec<u32>,
+    pub snapshots: Vec<Snapshot>,
+    pub(crate) view: Option<View>,
+    pub(crate) keys: Keyring,
+    pub(crate) quotas: Quotas,
+    delayed: HashMap<usize, Delayed>,
+}
+
+// 延迟分配的写入：数据暂存在内存中，刷新时才分配数据块
+#[derive(Debug)]
+struct Delayed {
+    data: Vec<u8>,
+    reserved: usize,
+    goal: Option<usize>,
+    // 文件已经关闭或被截短，刷新后不再保留预分配窗口
+    release_window: bool,
 }
 
 impl System {
-    pub fn init(hardware: Hardware) -> Self {
-        let mut instance = Self {
-            initialized: false,
-            root_inode_index: 0,
-            free_inodes: Vec::new(),
-            free_blocks: Vec::new(),
-            inodes: Vec::new(),
-            hardware: hardware,
+    pub fn init(hardware: Hardware) -> Result<Self> {
+        Self::mount(hardware, MountOptions::default())
+    }
+
+    pub fn mount(hardware: Hardware, options: MountOptions) -> Result<Self> {
+        let mut instance = Self::with_device(hardware, options)?;
+        if let Err(e) = instance.load() {
+            // 挂载失败时状态不完整，释放时不能写回
+            instance.options.read_only = true;
+            return Err(e);
+        }
+        Ok(instance)
+    }
+
+    fn load(&mut self) -> Result<()> {
+        self.load_super_block()?;
+        if self.cache.device_blocks() < self.layout.total_blocks {
+            return Err(FsError::InvalidLayout(format!(
+                "设备只有 {} 块，文件系统需要 {} 块",
+                self.cache.device_blocks(),
+                self.layout.total_blocks
+            )));
+        }
+        self.pin_metadata()?;
+
+        self.load_free_blocks()?;
+        self.load_free_inodes()?;
+        self.load_data_csums();
+        self.load_snapshots()?;
+        self.replay_defrag_journal()?;
+        self.load_quotas()?;
+
+        if !self.initialized {
+            if self.options.read_only {
+                return Err(FsError::Unformatted);
+            }
+            self.init_root_dir()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn format(mut hardware: Hardware, format: FormatOptions) -> Result<Self> {
+        if format.reserved_percent > MAX_RESERVED_PERCENT {
+            return Err(FsError::InvalidReserve(format.reserved_percent));
+        }
+        let layout = match format.blocks {
+            Some(blocks) => Layout::grouped(blocks)?,
+            None => Layout::flat(),
         };
+        if hardware.block_count() != layout.total_blocks {
+            hardware
+                .set_block_count(layout.total_blocks)
+                .map_err(|e| FsError::Host(hardware.path().to_string(), e))?;
+        }
 
-        instance.load_super_block();
+        let mut instance = Self::with_device(hardware, MountOptions::default())?;
+        instance.layout = layout;
+        instance.pin_metadata()?;
+        instance.data_csums = vec![0; instance.layout.total_blocks];
 
-        instance.load_inodes();
-        instance.load_free_blocks();
-        instance.load_free_inodes();
+        for block_pos in 0..instance.layout.total_blocks {
+            instance.clean_block_data(block_pos);
+        }
 
-        if !instance.initialized {
-            instance.init_root_dir();
+        instance.root_inode_index = 0;
+        instance.super_block = SuperBlock::new(instance.root_inode_index as u32);
+        if format.extents {
+            instance.super_block.feature_incompat |= FEATURE_INCOMPAT_EXTENTS;
+        }
+        if format.checksums {
+            instance.super_block.feature_incompat |= FEATURE_INCOMPAT_METADATA_CSUM;
+        }
+        if format.data_checksums {
+            instance.super_block.feature_ro_compat |= FEATURE_RO_COMPAT_DATA_CSUM;
+        }
+        instance.apply_reserved_percent(format.reserved_percent);
+        if instance.layout.grouped {
+            instance.super_block.feature_incompat |= FEATURE_INCOMPAT_GROUPS;
+            instance.super_block.blocks_count = instance.layout.total_blocks as u32;
+            instance.super_block.blocks_per_group = instance.layout.blocks_per_group as u32;
+            instance.super_block.inodes_per_group = instance.layout.inodes_per_group as u32;
         }
+        instance.allocator = Allocator::new(vec![0; instance.layout.total_blocks]);
+        instance.mark_metadata_used();
+        instance.free_inodes = vec![false; instance.layout.inode_count()];
+        instance.init_root_dir()?;
+        instance.initialized = true;
 
-        instance
+        Ok(instance)
     }
 
-    pub fn get_root_dir(&self) -> Dir {
-        let root_dir_inode = &self.inodes[self.root_inode_index];
-        let inode_data = self.read_inode_data(self.root_inode_index);
-        let root_dir =
-            Dir::from_block_bytes(&root_dir_inode.name, self.root_inode_index, &inode_data);
-        root_dir
+    fn with_device(hardware: Hardware, options: MountOptions) -> Result<Self> {
+        let instance = Self {
+            initialized: false,
+            options,
+            root_inode_index: 0,
+            free_inodes: Vec::new(),
+            allocator: Allocator::new(Vec::new()),
+            delayed: HashMap::new(),
+            inodes: InodeCache::new(DEFAULT_INODE_CACHE),
+            dentries: DentryCache::new(DEFAULT_DENTRY_CACHE),
+            super_block: SuperBlock::default(),
+            layout: Layout::flat(),
+            recovered_from: None,
+            cache: BlockCache::new(hardware, options.cache_blocks),
+            data_csums: Vec::new(),
+            snapshots: Vec::new(),
+            view: None,
+            keys: Keyring::default(),
+            quotas: Quotas::default(),
+        };
+
+        Ok(instance)
     }
 
-    pub fn open_dir(&self, dir: &Dir, name: &str) -> Dir {
-        let root_dir_inode = &self.inodes[dir.inode_index];
-        let inode_data = self.read_inode_data(dir.inode_index);
-        let root_dir = Dir::from_block_bytes(&root_dir_inode.name, dir.inode_index, &inode_data);
+    pub(crate) fn pin_metadata(&mut self) -> Result<()> {
+        for block_pos in self.layout.metadata_blocks() {
+            self.cache
+                .pin(block_pos)
+                .map_err(|e| FsError::Host(self.cache.path(), e))?;
+        }
+        Ok(())
+    }
 
-        let target_inode_index = root_dir
-            .items
-            .iter()
-            .filter(|item| item.name == name)
-            .next()
-            .unwrap()
-            .inode_pos as usize;
-        let target_inode = &self.inodes[target_inode_index];
-        let inode_data = self.read_inode_data(target_inode_index);
-        Dir::from_block_bytes(&target_inode.name, target_inode_index, &inode_data)
+    pub fn get_root_dir(&self) -> Result<Dir> {
+        self.read_dir(self.root_inode_index)
     }
 
-    pub fn create_dir(&mut self, dir: &mut Dir, name: &str) -> Dir {
-        for item in dir.items.iter() {
-            if item.name == name {
-                todo!("Dir already exists");
-            }
+    pub fn open_dir(&self, dir: &Dir, name: &str) -> Result<Dir> {
+        let target = self
+            .lookup_child(dir.inode_index, name)?
+            .ok_or_else(|| FsError::NotFound(name.to_string()))?;
+        if target.typ != "dir" {
+            return Err(FsError::NotADirectory(name.to_string()));
         }
 
-        let free_inode_index = self.get_next_free_inode() as usize;
-        self.inodes[free_inode_index].init(name);
+        self.read_dir(target.inode_pos as usize)
+    }
+
+    pub fn create_dir(&mut self, dir: &mut Dir, name: &str) -> Result<Dir> {
+        self.check_writable()?;
+        self.check_new_name(dir, name)?;
+
+        let free_inode_index = self.get_next_free_inode(dir.inode_index, true)? as usize;
+        self.init_inode(free_inode_index, name, DEFAULT_DIR_MODE)?;
+        self.inherit_attrs(dir.inode_index, free_inode_index)?;
         self.set_free_inode_used(free_inode_index, true);
 
-        let mut target_dir = Dir::new(
-            self.inodes[free_inode_index].name.as_str(),
-            free_inode_index,
-        );
+        let mut target_dir = Dir::new(name, free_inode_index);
         target_dir.init_dir(dir.inode_index);
 
-        let data = target_dir.to_block_bytes();
-
-        self.write_with_inode(free_inode_index, &data);
+        if let Err(e) = self.write_dir(&target_dir) {
+            let _ = self.remove_inode_data(free_inode_index);
+            return Err(e);
+        }
+        self.count_dir(free_inode_index, true);
 
         dir.items.push(DirItem {
             inode_pos: free_inode_index as u32,
@@ -94,52 +310,64 @@ impl System {
             size: 0,
         });
 
-        let root_data = dir.to_block_bytes();
-        self.write_with_inode(dir.inode_index, &root_data);
+        self.write_dir(dir)?;
 
-        target_dir
+        Ok(target_dir)
     }
 
-    pub fn remove_dir(&mut self, root: &mut Dir, name: &str) {
-        let mut target_dir = root
+    pub fn remove_dir(&mut self, root: &mut Dir, name: &str) -> Result<()> {
+        self.check_writable()?;
+        if name == "." || name == ".." {
+            return Err(FsError::InvalidName(name.to_string()));
+        }
+
+        let item = root
             .items
             .iter()
-            .filter(|item| item.name == name && item.typ == "dir")
-            .next()
-            .map(|item| {
-                let inode_data = self.read_inode_data(item.inode_pos as usize);
-                Dir::from_block_bytes(item.name.as_str(), item.inode_pos as usize, &inode_data)
-            })
-            .unwrap();
+            .find(|item| item.name == name)
+            .ok_or_else(|| FsError::NotFound(name.to_string()))?;
+        if item.typ != "dir" {
+            return Err(FsError::NotADirectory(name.to_string()));
+        }
 
-        root.items
-            .retain(|item| item.inode_pos != target_dir.inode_index as u32);
+        let mut target_dir = self.read_dir(item.inode_pos as usize)?;
 
-        let root_data = root.to_block_bytes();
-        self.write_with_inode(root.inode_index, &root_data);
+        root.items.retain(|item| item.name != name);
 
-        for i in 0..target_dir.items.len() {
-            let item_name = target_dir.items[i].name.clone();
-            let item_type = target_dir.items[i].typ.clone();
+        self.write_dir(root)?;
 
+        let children: Vec<(String, String)> = target_dir
+            .items
+            .iter()
+            .map(|item| (item.name.clone(), item.typ.clone()))
+            .collect();
+
+        for (item_name, item_type) in children {
             if item_name == "." || item_name == ".." {
                 continue;
             }
 
             if item_type == "dir" {
-                self.remove_dir(&mut target_dir, &item_name);
-            } else if item_type == "file" {
-                self.remove_file(&mut target_dir, &item_name);
+                self.remove_dir(&mut target_dir, &item_name)?;
+            } else {
+                self.remove_file(&mut target_dir, &item_name)?;
             }
         }
 
-        self.remove_inode_data(target_dir.inode_index);
+        self.remove_inode_data(target_dir.inode_index)?;
+        self.count_dir(target_dir.inode_index, false);
+
+        Ok(())
     }
 
-    pub fn create_file(&mut self, dir: &mut Dir, name: &str) -> File {
-        let free_inode_index = self.get_next_free_inode() as usize;
+    pub fn create_file(&mut self, dir: &mut Dir, name: &str) -> Result<File> {
+        self.check_writable()?;
+        self.check_new_name(dir, name)?;
+
+        let free_inode_index = self.get_next_free_inode(dir.inode_index, false)? as usize;
         self.set_free_inode_used(free_inode_index, true);
-        self.inodes[free_inode_index].init(name);
+        self.init_inode(free_inode_index, name, DEFAULT_FILE_MODE)?;
+        self.inherit_attrs(dir.inode_index, free_inode_index)?;
 
         let target_file = File::new(name, free_inode_index);
         dir.items.push(DirItem {
@@ -149,15 +377,24 @@ impl System {
             size: 0,
         });
 
-        let root_data = dir.to_block_bytes();
-        self.write_with_inode(dir.inode_index, &root_data);
+        if let Err(e) = self.write_dir(dir) {
+            dir.items.pop();
+            let _ = self.remove_inode_data(free_inode_index);
+            return Err(e);
+        }
 
-        target_file
+        Ok(target_file)
     }
 
-    pub fn write_file(&mut self, dir: &mut Dir, file: &mut File, data: &[u8]) {
-        self.write_with_inode(file.inode_index, data);
-        file.content = String::from_utf8(data.to_vec()).unwrap();
+    pub fn write_file(&mut self, dir: &mut Dir, file: &mut File, data: &[u8]) -> Result<()> {
+        self.check_writable()?;
+        let goal = self.data_goal(file.inode_index, dir.inode_index);
+        if self.options.delayed_alloc {
+            self.write_delayed(file.inode_index, data, goal)?;
+        } else {
+            self.write_with_inode(file.inode_index, data, goal)?;
+        }
+        file.content = String::from_utf8_lossy(data).to_string();
         file.size = data.len() as u32;
 
         for i in 0..dir.items.len() {
@@ -166,216 +403,1949 @@ impl System {
             }
         }
 
-        let root_data = dir.to_block_bytes();
-        self.write_with_inode(dir.inode_index, &root_data);
+        self.write_dir(dir)
     }
 
-    pub fn read_file(&self, file: &File) -> Vec<u8> {
-        self.read_inode_data(file.inode_index)
-            .iter()
-            .filter(|x| **x != 0)
-            .map(|x| *x)
-            .collect()
+    // 释放文件的预分配窗口；延迟分配的数据在刷新后释放
+    pub fn close_file(&mut self, file: File) {
+        match self.delayed.get_mut(&file.inode_index) {
+            Some(pending) => pending.release_window = true,
+            None => self.allocator.release(file.inode_index),
+        }
     }
 
-    pub fn remove_file(&mut self, dir: &mut Dir, name: &str) {
-        let target_inode_index = dir
+    pub fn read_file(&self, file: &File) -> Result<Vec<u8>> {
+        self.read_inode_content(file.inode_index)
+    }
+
+    pub fn remove_file(&mut self, dir: &mut Dir, name: &str) -> Result<()> {
+        self.check_writable()?;
+        let item = dir
             .items
             .iter()
-            .filter(|item| item.name == name && item.typ == "file")
-            .next()
-            .map(|item| item.inode_pos as usize)
-            .unwrap();
-
-        self.remove_inode_data(target_inode_index);
-        self.set_free_inode_used(target_inode_index, false);
-        dir.items.retain(|item| item.inode_pos != target_inode_index as u32);
+            .find(|item| item.name == name)
+            .ok_or_else(|| FsError::NotFound(name.to_string()))?;
+        if item.typ == "dir" {
+            return Err(FsError::IsADirectory(name.to_string()));
+        }
+        let target_inode_index = item.inode_pos as usize;
+
+        let links = self.inode(target_inode_index)?.borrow().links;
+        if links > 1 {
+            let inode = self.inode_mut(target_inode_index)?;
+            let mut inode = inode.borrow_mut();
+            inode.links -= 1;
+            inode.ctime = chrono::Utc::now().timestamp();
+        } else {
+            self.remove_inode_data(target_inode_index)?;
+        }
+        dir.items.retain(|item| item.name != name);
 
-        let root_data = dir.to_block_bytes();
-        self.write_with_inode(dir.inode_index, &root_data);
+        self.write_dir(dir)
     }
 
-    pub fn open_file(&mut self, dir: &mut Dir, name: &str) -> File {
-        if let Some(item) = dir.items.iter().filter(|item| item.name == name).next() {
-            let inode_data = self.read_inode_data(item.inode_pos as usize);
-            File::from_block_bytes(item.name.as_str(), item.inode_pos as usize, &inode_data)
-        }else {
+    pub fn open_file(&mut self, dir: &mut Dir, name: &str) -> Result<File> {
+        if let Some(item) = dir.items.iter().find(|item| item.name == name) {
+            if item.typ == "dir" {
+                return Err(FsError::IsADirectory(name.to_string()));
+            }
+            if item.typ != "file" {
+                return Err(FsError::NotAFile(name.to_string()));
+            }
+            let inode_data = self.read_inode_content(item.inode_pos as usize)?;
+            Ok(File::from_block_bytes(
+                item.name.as_str(),
+                item.inode_pos as usize,
+                &inode_data,
+            ))
+        } else {
             self.create_file(dir, name)
         }
     }
 
-    fn load_super_block(&mut self) {
-        let magic = u32::from_le_bytes(self.hardware.data[0..4].try_into().unwrap());
+    pub fn lookup(&self, path: &str) -> Result<usize> {
+        Ok(self.lookup_item(path)?.inode_pos as usize)
+    }
 
-        self.initialized = magic == INIT_MAGIC;
+    pub fn open_dir_path(&self, path: &str) -> Result<Dir> {
+        let (_, item) = self.resolve(path, true)?;
+        if item.typ != "dir" {
+            return Err(FsError::NotADirectory(path.to_string()));
+        }
+        self.read_dir(item.inode_pos as usize)
+    }
 
-        self.root_inode_index =
-            u32::from_le_bytes(self.hardware.data[4..8].try_into().unwrap()) as usize;
+    pub fn read_path(&self, path: &str) -> Result<Vec<u8>> {
+        let (_, item) = self.resolve(path, true)?;
+        if item.typ == "dir" {
+            return Err(FsError::IsADirectory(path.to_string()));
+        }
+        self.read_inode_content(item.inode_pos as usize)
+    }
 
+    // 空闲块不足时先清理回收站再重试，下同
+    pub fn write_path(&mut self, path: &str, data: &[u8]) -> Result<()> {
+        self.retry_after_purge(path, |fs| fs.write_path_once(path, data))
     }
 
-    fn init_root_dir(&mut self) {
-        assert!(self.inodes.len() > 0);
-        self.set_free_inode_used(self.root_inode_index, true);
+    fn write_path_once(&mut self, path: &str, data: &[u8]) -> Result<()> {
+        self.check_writable()?;
+        let path = match self.resolve(path, true) {
+            Ok((real_path, _)) => real_path,
+            Err(FsError::NotFound(_)) => path.to_string(),
+            Err(e) => return Err(e),
+        };
+        let (parent, name) = split_path(&path)?;
+        let mut dir = self.open_dir_path(parent)?;
+        let existing = dir.items.iter().find(|item| item.name == name);
+        let created = existing.is_none();
+        // 旧内容会被整个替换，不需要读出来（也不要求是合法的 UTF-8）
+        let mut file = match existing {
+            Some(item) if item.typ == "dir" => return Err(FsError::IsADirectory(name.to_string())),
+            Some(item) if item.typ != "file" => return Err(FsError::NotAFile(name.to_string())),
+            Some(item) => File::new(name, item.inode_pos as usize),
+            None => self.create_file(&mut dir, name)?,
+        };
+        let result = self.write_file(&mut dir, &mut file, data);
+        if result.is_err() && created {
+            self.remove_file(&mut dir, name)?;
+            return result;
+        }
+        self.close_file(file);
+        result
+    }
 
-        self.inodes[self.root_inode_index].name = String::from("/");
+    pub fn create_dir_all(&mut self, path: &str) -> Result<Dir> {
+        self.retry_after_purge(path, |fs| fs.create_dir_all_once(path))
+    }
 
-        let mut root_dir = Dir::new(
-            self.inodes[self.root_inode_index].name.as_str(),
-            self.root_inode_index,
-        );
-        root_dir.init_dir(self.root_inode_index);
+    fn create_dir_all_once(&mut self, path: &str) -> Result<Dir> {
+        let mut dir = self.get_root_dir()?;
+        for name in path.split('/').filter(|x| !x.is_empty()) {
+            dir = match dir.items.iter().find(|item| item.name == name) {
+                Some(_) => self.open_dir(&dir, name)?,
+                None => self.create_dir(&mut dir, name)?,
+            };
+        }
+        Ok(dir)
+    }
 
-        let data = root_dir.to_block_bytes();
+    pub fn remove_path(&mut self, path: &str, recursive: bool) -> Result<()> {
+        self.check_writable()?;
+        let (parent, name) = split_path(path)?;
+        let mut dir = self.open_dir_path(parent)?;
+        let item = dir
+            .items
+            .iter()
+            .find(|item| item.name == name)
+            .ok_or_else(|| FsError::NotFound(path.to_string()))?;
 
-        self.write_with_inode(self.root_inode_index, &data);
+        if item.typ == "dir" {
+            if !recursive {
+                return Err(FsError::IsADirectory(path.to_string()));
+            }
+            self.remove_dir(&mut dir, name)
+        } else {
+            self.remove_file(&mut dir, name)
+        }
     }
 
-    fn load_free_blocks(&mut self) {
-        let start_i = hardware::BLOCK_SIZE;
-        self.free_blocks = self.hardware.data[start_i..start_i + hardware::BLOCK_SIZE - 3]
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<()> {
+        self.check_writable()?;
+        self.check_trash_storage(from)?;
+        self.check_trash_storage(to)?;
+        let (src_parent, src_name) = split_path(from)?;
+        if src_name == "." || src_name == ".." {
+            return Err(FsError::InvalidName(src_name.to_string()));
+        }
+        let src_dir = self.open_dir_path(src_parent)?;
+        let src = src_dir
+            .items
             .iter()
-            .map(|x| *x == 1)
-            .collect();
+            .find(|item| item.name == src_name)
+            .cloned()
+            .ok_or_else(|| FsError::NotFound(from.to_string()))?;
+
+        let (dst_dir_index, dst_name) = match self.lookup_item(to) {
+            Ok(item) if item.inode_pos == src.inode_pos => return Ok(()),
+            Ok(item) if item.typ == "dir" => (item.inode_pos as usize, src_name),
+            Ok(_) if src.typ == "dir" => return Err(FsError::NotADirectory(to.to_string())),
+            Ok(_) => {
+                self.remove_path(to, false)?;
+                let (dst_parent, dst_name) = split_path(to)?;
+                (self.lookup(dst_parent)?, dst_name)
+            }
+            Err(FsError::NotFound(_)) => {
+                let (dst_parent, dst_name) = split_path(to)?;
+                let dst_dir = self.open_dir_path(dst_parent)?;
+                (dst_dir.inode_index, dst_name)
+            }
+            Err(e) => return Err(e),
+        };
+
+        if src.typ == "dir" && self.is_ancestor(src.inode_pos as usize, dst_dir_index)? {
+            return Err(FsError::InvalidMove(to.to_string()));
+        }
+
+        let src_dir_index = src_dir.inode_index;
+        let mut dst_dir = self.read_dir(dst_dir_index)?;
+        self.check_new_name(&dst_dir, dst_name)?;
+        self.check_policy(dst_dir_index, src.inode_pos as usize, to)?;
+        let stored_name = self.stored_name(dst_dir_index, dst_name)?;
+
+        let mut src_dir = self.read_dir(src_dir_index)?;
+        src_dir.items.retain(|item| item.name != src_name);
+        self.write_dir(&src_dir)?;
+
+        if dst_dir_index == src_dir_index {
+            dst_dir = src_dir;
+        }
+        dst_dir.items.push(DirItem {
+            name: dst_name.to_string(),
+            ..src.clone()
+        });
+        self.write_dir(&dst_dir)?;
+
+        let inode_index = src.inode_pos as usize;
+        self.inode_mut(inode_index)?.borrow_mut().name = stored_name;
+
+        if src.typ == "dir" && dst_dir_index != src_dir_index {
+            let mut moved = self.read_dir(inode_index)?;
+            for item in moved.items.iter_mut() {
+                if item.name == ".." {
+                    item.inode_pos = dst_dir.inode_index as u32;
+                }
+            }
+            self.write_dir(&moved)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn symlink(&mut self, target: &str, path: &str) -> Result<()> {
+        self.retry_after_purge(path, |fs| fs.symlink_once(target, path))
+    }
+
+    fn symlink_once(&mut self, target: &str, path: &str) -> Result<()> {
+        self.check_writable()?;
+        let (parent, name) = split_path(path)?;
+        let mut dir = self.open_dir_path(parent)?;
+        self.check_new_name(&dir, name)?;
+
+        let free_inode_index = self.get_next_free_inode(dir.inode_index, false)? as usize;
+        self.set_free_inode_used(free_inode_index, true);
+        self.init_inode(free_inode_index, name, DEFAULT_SYMLINK_MODE)?;
+        self.inherit_attrs(dir.inode_index, free_inode_index)?;
+
+        let goal = self.data_goal(free_inode_index, dir.inode_index);
+        if let Err(e) = self.write_with_inode(free_inode_index, target.as_bytes(), goal) {
+            let _ = self.remove_inode_data(free_inode_index);
+            return Err(e);
+        }
+
+        dir.items.push(DirItem {
+            inode_pos: free_inode_index as u32,
+            name: name.to_string(),
+            typ: "symlink".to_string(),
+            size: target.len() as u32,
+        });
+
+        if let Err(e) = self.write_dir(&dir) {
+            let _ = self.remove_inode_data(free_inode_index);
+            return Err(e);
+        }
+
+        self.super_block.feature_incompat |= FEATURE_INCOMPAT_LINKS;
+
+        Ok(())
+    }
+
+    pub fn read_link(&self, path: &str) -> Result<String> {
+        let item = self.lookup_item(path)?;
+        if item.typ != "symlink" {
+            return Err(FsError::NotASymlink(path.to_string()));
+        }
+        let target = self.read_inode_content(item.inode_pos as usize)?;
+        Ok(String::from_utf8_lossy(&target).to_string())
+    }
+
+    pub fn link(&mut self, existing: &str, path: &str) -> Result<()> {
+        self.check_writable()?;
+        let item = self.lookup_item(existing)?;
+        if item.typ == "dir" {
+            return Err(FsError::IsADirectory(existing.to_string()));
+        }
+
+        let (parent, name) = split_path(path)?;
+        let mut dir = self.open_dir_path(parent)?;
+        self.check_new_name(&dir, name)?;
+        self.check_policy(dir.inode_index, item.inode_pos as usize, path)?;
+
+        dir.items.push(DirItem {
+            name: name.to_string(),
+            ..item.clone()
+        });
+        self.write_dir(&dir)?;
+
+        let inode = self.inode_mut(item.inode_pos as usize)?;
+        let mut inode = inode.borrow_mut();
+        inode.links = inode.links.max(1) + 1;
+        inode.ctime = chrono::Utc::now().timestamp();
+
+        self.super_block.feature_incompat |= FEATURE_INCOMPAT_LINKS;
+
+        Ok(())
+    }
+
+    // 新文件与源文件共享数据块，之后任何一方写入时都会分配新块
+    pub fn clone_file(&mut self, src: &str, dst: &str) -> Result<()> {
+        self.retry_after_purge(dst, |fs| fs.clone_file_once(src, dst))
     }
 
-    fn load_free_inodes(&mut self) {
-        assert!(self.inodes.len() > 0);
-        let start_i = hardware::BLOCK_SIZE * 2;
-        self.free_inodes = self.hardware.data[start_i..start_i + self.inodes.len()]
+    fn clone_file_once(&mut self, src: &str, dst: &str) -> Result<()> {
+        self.check_writable()?;
+        let item = self.copy_source(src)?;
+        let Some(path) = self.copy_target(&item, src, dst)? else {
+            return Ok(());
+        };
+        // 延迟分配的数据还没有可以共享的数据块
+        self.flush_delayed()?;
+
+        let src_index = item.inode_pos as usize;
+        let blocks = self.inode_blocks(src_index)?;
+        if let Some(block_pos) = blocks
             .iter()
-            .map(|x| *x == 1)
-            .collect();
+            .find(|x| self.allocator.refcount(**x as usize) == MAX_REFS)
+        {
+            return Err(FsError::TooManyRefs(*block_pos as usize));
+        }
+        let (size, mode, flags, context) = {
+            let inode = self.inode(src_index)?;
+            let inode = inode.borrow();
+            let flags = inode.flags & (INODE_FLAG_COMPRESS | INODE_FLAG_ENCRYPTED);
+            (inode.size, inode.mode, flags, inode.context)
+        };
+
+        let (parent, name) = split_path(&path)?;
+        let mut dir = self.open_dir_path(parent)?;
+        self.check_new_name(&dir, name)?;
+        self.check_policy(dir.inode_index, src_index, &path)?;
+        let index = self.get_next_free_inode(dir.inode_index, false)? as usize;
+        self.set_free_inode_used(index, true);
+        self.init_inode(index, name, mode)?;
+        self.inherit_attrs(dir.inode_index, index)?;
+        // 共享的数据块按源文件的方式压缩和加密，也使用同样的密钥
+        {
+            let inode = self.inode_mut(index)?;
+            let mut inode = inode.borrow_mut();
+            inode.flags = (inode.flags & !(INODE_FLAG_COMPRESS | INODE_FLAG_ENCRYPTED)) | flags;
+            inode.context = context;
+        }
+        if let Err(e) = self.share_blocks(index, &blocks, size) {
+            let _ = self.remove_inode_data(index);
+            return Err(e);
+        }
+
+        dir.items.push(DirItem {
+            inode_pos: index as u32,
+            name: name.to_string(),
+            typ: "file".to_string(),
+            size,
+        });
+        if let Err(e) = self.write_dir(&dir) {
+            let _ = self.remove_inode_data(index);
+            return Err(e);
+        }
+
+        self.super_block.feature_incompat |= FEATURE_INCOMPAT_SHARED_BLOCKS;
+
+        Ok(())
+    }
+
+    pub fn copy_file(&mut self, src: &str, dst: &str) -> Result<()> {
+        self.check_writable()?;
+        let item = self.copy_source(src)?;
+        let Some(path) = self.copy_target(&item, src, dst)? else {
+            return Ok(());
+        };
+        let data = self.read_inode_content(item.inode_pos as usize)?;
+        let mode = self.inode(item.inode_pos as usize)?.borrow().mode;
+        self.write_path(&path, &data)?;
+        self.set_mode(&path, mode)
+    }
+
+    pub fn stat(&self, path: &str) -> Result<Stat> {
+        let item = self.lookup_item(path)?;
+        let inode = self.inode(item.inode_pos as usize)?;
+        let inode = inode.borrow();
+
+        Ok(Stat {
+            inode_index: item.inode_pos as usize,
+            name: item.name,
+            typ: item.typ,
+            size: inode.size,
+            mode: inode.mode,
+            links: inode.links.max(1),
+            uid: inode.uid,
+            project: inode.project,
+            compressed: inode.flags & INODE_FLAG_COMPRESS != 0,
+            encrypted: inode.is_encrypted(),
+            mtime: inode.mtime,
+            ctime: inode.ctime,
+            blocks: self.inode_blocks(item.inode_pos as usize)?,
+            extents: self.extents(item.inode_pos as usize)?,
+            delayed_blocks: self
+                .delayed
+                .get(&(item.inode_pos as usize))
+                .map_or(0, |x| x.reserved),
+        })
     }
 
-    fn load_inodes(&mut self) {
-        let r = hardware::BLOCK_SIZE * 3;
-        let l = r + hardware::BLOCK_SIZE;
-        self.inodes = Inode::from_block_bytes(&self.hardware.data[r..l]);
+    pub fn set_mode(&mut self, path: &str, mode: u16) -> Result<()> {
+        self.check_writable()?;
+        let inode_index = self.lookup(path)?;
+        let inode = self.inode_mut(inode_index)?;
+        let mut inode = inode.borrow_mut();
+        inode.mode = (inode.mode & S_IFMT) | (mode & !S_IFMT);
+        inode.ctime = chrono::Utc::now().timestamp();
+        Ok(())
     }
 
-    fn get_next_free_block(&mut self) -> u32 {
-        for i in 4..self.free_blocks.len() {
-            if !self.free_blocks[i] {
-                return i as u32;
+    // 文件立即按新的方式重写数据；目录只影响之后在其中新建的文件和子目录
+    pub fn set_compression(&mut self, path: &str, enabled: bool) -> Result<()> {
+        self.check_writable()?;
+        let item = self.lookup_item(path)?;
+        if item.typ != "dir" && item.typ != "file" {
+            return Err(FsError::NotAFile(path.to_string()));
+        }
+        let index = item.inode_pos as usize;
+        let (flags, mtime) = {
+            let inode = self.inode(index)?;
+            let inode = inode.borrow();
+            (inode.flags, inode.mtime)
+        };
+        if (flags & INODE_FLAG_COMPRESS != 0) == enabled {
+            return Ok(());
+        }
+        if self.delayed.contains_key(&index) {
+            self.flush_delayed()?;
+        }
+
+        let data = if item.typ == "file" {
+            Some(self.read_inode_content(index)?)
+        } else {
+            None
+        };
+        self.inode_mut(index)?.borrow_mut().flags = flags ^ INODE_FLAG_COMPRESS;
+        if let Some(data) = data {
+            if let Err(e) = self.write_with_inode(index, &data, None) {
+                self.inode_mut(index)?.borrow_mut().flags = flags;
+                return Err(e);
             }
         }
-        todo!()
+        let inode = self.inode_mut(index)?;
+        let mut inode = inode.borrow_mut();
+        inode.mtime = mtime;
+        inode.ctime = chrono::Utc::now().timestamp();
+
+        if enabled {
+            self.super_block.feature_incompat |= FEATURE_INCOMPAT_COMPRESSION;
+        }
+        Ok(())
     }
 
-    fn get_next_free_inode(&mut self) -> u32 {
-        for i in 0..self.free_inodes.len() {
-            if !self.free_inodes[i] {
-                return i as u32;
-            }
+    pub fn set_mtime(&mut self, path: &str, mtime: i64) -> Result<()> {
+        self.check_writable()?;
+        let inode_index = self.lookup(path)?;
+        self.inode_mut(inode_index)?.borrow_mut().mtime = mtime;
+        Ok(())
+    }
+
+    // 空闲块不包括延迟分配预留的块
+    pub fn statfs(&self) -> StatFs {
+        StatFs {
+            block_size: hardware::BLOCK_SIZE,
+            name_max: MAX_NAME_LEN,
+            total_blocks: self.layout.total_blocks,
+            free_blocks: self.allocator.free_blocks(),
+            delayed_blocks: self.allocator.reserved(),
+            available_blocks: self.usable_blocks(),
+            reserved_blocks: self.reserved_blocks(),
+            reserved_percent: self.super_block.reserved_percent,
+            total_inodes: self.free_inodes.len(),
+            free_inodes: self.free_inode_count(),
+            available_inodes: self.usable_inodes(),
+            reserved_inodes: self.reserved_inodes(),
         }
-        todo!()
     }
 
-    fn set_free_inode_used(&mut self, inode_pos: usize, used: bool) {
-        self.free_inodes[inode_pos] = used;
+    pub fn set_reserved_percent(&mut self, percent: u32) -> Result<()> {
+        self.check_writable()?;
+        self.check_root("保留块")?;
+        if percent > MAX_RESERVED_PERCENT {
+            return Err(FsError::InvalidReserve(percent));
+        }
+        self.apply_reserved_percent(percent);
+        Ok(())
+    }
+
+    fn apply_reserved_percent(&mut self, percent: u32) {
+        self.super_block.reserved_percent = percent;
+        if percent == 0 {
+            self.super_block.feature_compat &= !FEATURE_COMPAT_RESERVED_BLOCKS;
+        } else {
+            self.super_block.feature_compat |= FEATURE_COMPAT_RESERVED_BLOCKS;
+        }
     }
 
-    fn set_free_block_used(&mut self, block_pos: usize, used: bool) {
-        self.free_blocks[block_pos] = used;
+    pub fn reserved_blocks(&self) -> usize {
+        self.layout.total_blocks * self.super_block.reserved_percent as usize / 100
     }
 
-    fn write_with_inode(&mut self, inode_pos: usize, data: &[u8]) {
-        for i in 0..self.inodes[inode_pos].block_pos.len() {
-            self.clean_block_data(self.inodes[inode_pos].block_pos[i] as usize);
-            self.set_free_block_used(self.inodes[inode_pos].block_pos[i] as usize, false);
+    // 普通用户分配时要给 root 留出保留块
+    pub(crate) fn usable_blocks(&self) -> usize {
+        let available = self.allocator.available();
+        if self.options.uid == 0 {
+            available
+        } else {
+            available.saturating_sub(self.reserved_blocks())
         }
+    }
 
-        let size = data.len();
-        let mut positions: Vec<u32> = data
-            .chunks(hardware::BLOCK_SIZE)
-            .map(|chunk| {
-                let free_block_index = self.get_next_free_block();
-                self.set_free_block_used(free_block_index as usize, true);
-                self.write_into_block(free_block_index as usize, chunk);
-                free_block_index
-            })
-            .collect();
+    pub fn reserved_inodes(&self) -> usize {
+        self.free_inodes.len() * self.super_block.reserved_percent as usize / 100
+    }
 
-        if positions.len() > 7 {
-            todo!()
+    fn free_inode_count(&self) -> usize {
+        self.free_inodes.iter().filter(|x| !**x).count()
+    }
+
+    fn usable_inodes(&self) -> usize {
+        let free = self.free_inode_count();
+        if self.options.uid == 0 {
+            free
         } else {
-            positions.resize(7, 0);
+            free.saturating_sub(self.reserved_inodes())
         }
+    }
+
+    // 按位图计算各块组当前的空闲块数和空闲索引节点数
+    pub fn group_stats(&self) -> Vec<GroupDesc> {
+        let refs = self.allocator.refcounts();
+        (0..self.layout.groups.len())
+            .map(|g| GroupDesc {
+                free_blocks: self.layout.blocks(g).filter(|x| refs[*x] == 0).count() as u32,
+                free_inodes: self
+                    .layout
+                    .inodes(g)
+                    .filter(|x| !self.free_inodes[*x])
+                    .count() as u32,
+                ..self.layout.groups[g]
+            })
+            .collect()
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    pub fn inode_cache_stats(&self) -> InodeCacheStats {
+        self.inodes.stats()
+    }
+
+    pub fn dentry_cache_stats(&self) -> DentryCacheStats {
+        self.dentries.stats()
+    }
+
+    pub fn checksums(&self) -> bool {
+        self.super_block.feature_incompat & FEATURE_INCOMPAT_METADATA_CSUM != 0
+    }
+
+    pub fn data_checksums(&self) -> bool {
+        self.super_block.feature_ro_compat & FEATURE_RO_COMPAT_DATA_CSUM != 0
+    }
+
+    // 未启用数据块校验和时总是返回 true
+    pub(crate) fn data_block_ok(&self, block_pos: usize) -> bool {
+        !self.data_checksums()
+            || crc32c::crc32c(&self.read_block(block_pos)) == self.data_csums[block_pos]
+    }
 
-        self.inodes[inode_pos].size = size as u32;
-        self.inodes[inode_pos].block_pos = positions;
+    pub fn is_read_only(&self) -> bool {
+        self.options.read_only || self.view.is_some()
     }
 
-    fn write_into_block(&mut self, block_pos: usize, data: &[u8]) {
-        if data.len() > hardware::BLOCK_SIZE {
-            todo!()
+    pub(crate) fn check_root(&self, what: &str) -> Result<()> {
+        if self.options.uid != 0 {
+            return Err(FsError::NotPermitted(what.to_string()));
         }
-        let r = block_pos * hardware::BLOCK_SIZE;
-        let l = r + data.len();
-        self.hardware.data[r..l].copy_from_slice(data);
+        Ok(())
     }
 
-    fn read_inode_data(&self, inode_pos: usize) -> Vec<u8> {
-        let mut data = Vec::new();
-        for block_pos in self.inodes[inode_pos].block_pos.iter() {
-            if block_pos == &0 {
-                break;
-            }
-            let r = *block_pos as usize * hardware::BLOCK_SIZE;
-            let l = r + hardware::BLOCK_SIZE;
-            data.extend_from_slice(&self.hardware.data[r..l]);
+    pub(crate) fn check_writable(&self) -> Result<()> {
+        if self.options.read_only || self.view.is_some() {
+            return Err(FsError::ReadOnly);
         }
-        data
+        Ok(())
+    }
+
+    fn lookup_item(&self, path: &str) -> Result<DirItem> {
+        Ok(self.resolve(path, false)?.1)
     }
 
-    fn remove_inode_data(&mut self, inode_pos: usize) {
-        for i in 0..self.inodes[inode_pos].block_pos.len() {
-            if self.inodes[inode_pos].block_pos[i] == 0 {
-                break;
+    fn resolve(&self, path: &str, follow_last: bool) -> Result<(String, DirItem)> {
+        let mut parts: Vec<String> = Vec::new();
+        let mut items = vec![DirItem {
+            inode_pos: self.root_inode_index as u32,
+            name: String::from("/"),
+            typ: String::from("dir"),
+            size: 0,
+        }];
+        let mut pending: VecDeque<String> = path
+            .split('/')
+            .filter(|x| !x.is_empty())
+            .map(|x| x.to_string())
+            .collect();
+        let mut expansions = 0;
+
+        while let Some(name) = pending.pop_front() {
+            let current = items.last().unwrap();
+            if current.typ != "dir" {
+                return Err(FsError::NotADirectory(path.to_string()));
+            }
+
+            match name.as_str() {
+                "." => continue,
+                ".." => {
+                    if items.len() > 1 {
+                        items.pop();
+                        parts.pop();
+                    }
+                    continue;
+                }
+                _ => {}
             }
-            let block_pos = self.inodes[inode_pos].block_pos[i];
-            self.clean_block_data(block_pos as usize);
-            self.set_free_block_used(block_pos as usize, false);
+
+            let item = self
+                .lookup_child(current.inode_pos as usize, &name)?
+                .ok_or_else(|| FsError::NotFound(path.to_string()))?;
+
+            if item.typ == "symlink" && (follow_last || !pending.is_empty()) {
+                expansions += 1;
+                if expansions > MAX_SYMLINK_DEPTH {
+                    return Err(FsError::TooManyLinks(path.to_string()));
+                }
+
+                let target = self.read_inode_content(item.inode_pos as usize)?;
+                let target = String::from_utf8_lossy(&target);
+                if target.starts_with('/') {
+                    items.truncate(1);
+                    parts.clear();
+                }
+                for name in target.split('/').filter(|x| !x.is_empty()).rev() {
+                    pending.push_front(name.to_string());
+                }
+                continue;
+            }
+
+            parts.push(name);
+            items.push(item);
         }
 
-        self.set_free_inode_used(inode_pos, false);
+        Ok((format!("/{}", parts.join("/")), items.pop().unwrap()))
+    }
+
+    pub(crate) fn read_dir(&self, inode_index: usize) -> Result<Dir> {
+        let name = self.inode(inode_index)?.borrow().name.clone();
+        let blocks = self.inode_blocks(inode_index)?;
+        let corrupted = |block: usize| FsError::Corrupted {
+            what: format!("目录 {}", name),
+            block,
+        };
+
+        let data = if self.checksums() {
+            let mut data = Vec::new();
+            for (i, block_pos) in blocks.iter().enumerate() {
+                let block = self.read_block(*block_pos as usize);
+                let stored = u32::from_le_bytes(block[DIR_BLOCK_PAYLOAD..].try_into().unwrap());
+                if dir::block_checksum(inode_index, i, &block) != stored {
+                    return Err(corrupted(*block_pos as usize));
+                }
+                data.extend_from_slice(&block[..DIR_BLOCK_PAYLOAD]);
+            }
+            data
+        } else {
+            self.read_inode_data(inode_index)?
+        };
 
-        self.inodes[inode_pos].clean();
+        let first_block = blocks.first().map_or(0, |x| *x as usize);
+        let mut dir = Dir::from_block_bytes(&name, inode_index, &data)
+            .ok_or_else(|| corrupted(first_block))?;
+        match self.name_cipher(inode_index) {
+            Ok(Some(cipher)) => {
+                for item in dir.items.iter_mut() {
+                    if item.name != "." && item.name != ".." {
+                        item.name = crypt::decrypt_name(&cipher, &item.name)
+                            .ok_or_else(|| corrupted(first_block))?;
+                    }
+                }
+            }
+            // 没有密钥时列出加密后的名称
+            Ok(None) | Err(FsError::Locked(_)) => {}
+            Err(e) => return Err(e),
+        }
+        Ok(dir)
     }
 
-    fn clean_block_data(&mut self, block_pos: usize) {
-        self.hardware.data
-            [block_pos * hardware::BLOCK_SIZE..(block_pos + 1) * hardware::BLOCK_SIZE]
-            .copy_from_slice(&[0; hardware::BLOCK_SIZE]);
+    fn lookup_child(&self, dir_index: usize, name: &str) -> Result<Option<DirItem>> {
+        if let Some(item) = self.dentries.lookup(dir_index, name) {
+            return Ok(item);
+        }
+
+        let item = self
+            .read_dir(dir_index)?
+            .items
+            .into_iter()
+            .find(|item| item.name == name);
+        // 没有密钥时目录项只有加密后的名称，找不到明文名称不代表不存在
+        if item.is_none() {
+            if let Err(e @ FsError::Locked(_)) = self.name_cipher(dir_index) {
+                return Err(e);
+            }
+        }
+        self.dentries.insert(dir_index, name, item.clone());
+        Ok(item)
     }
 
-    pub fn save(&mut self, name: &str) {
-        let free_block_data = self
-            .free_blocks
+    // 目录内容变化时，该目录下缓存的所有目录项（包括不存在的名称）一并失效
+    pub(crate) fn write_dir(&mut self, dir: &Dir) -> Result<()> {
+        self.dentries.invalidate_dir(dir.inode_index);
+        let encrypted;
+        let dir = match self.name_cipher(dir.inode_index) {
+            Ok(Some(cipher)) => {
+                let items = dir.items.iter().map(|item| DirItem {
+                    name: if item.name == "." || item.name == ".." {
+                        item.name.clone()
+                    } else {
+                        crypt::encrypt_name(&cipher, &item.name)
+                    },
+                    ..item.clone()
+                });
+                encrypted = Dir {
+                    name: dir.name.clone(),
+                    inode_index: dir.inode_index,
+                    items: items.collect(),
+                };
+                &encrypted
+            }
+            // 没有密钥时只能删除目录项，留下的名称本来就是加密后的形式
+            Ok(None) | Err(FsError::Locked(_)) => dir,
+            Err(e) => return Err(e),
+        };
+        let parent = dir
+            .items
             .iter()
-            .map(|x| if *x { 0x01 } else { 0x00 })
-            .collect::<Vec<u8>>();
-        self.write_into_block(1, &free_block_data);
+            .find(|item| item.name == "..")
+            .map_or(self.root_inode_index, |item| item.inode_pos as usize);
+        let goal = self.data_goal(dir.inode_index, parent);
+        let data = if self.checksums() {
+            dir.to_checked_blocks()
+        } else {
+            dir.to_block_bytes()
+        };
+        self.write_with_inode(dir.inode_index, &data, goal)
+    }
 
-        let free_inode_data = self
-            .free_inodes
-            .iter()
-            .map(|x| if *x { 0x01 } else { 0x00 })
-            .collect::<Vec<u8>>();
-        self.write_into_block(2, &free_inode_data);
+    // 优先靠近文件原来的位置，新文件靠近同一块组中的所在目录，否则从索引节点所在块组的数据区开始
+    fn data_goal(&self, inode_index: usize, parent: usize) -> Option<usize> {
+        if let Some(block_pos) = self.inode_blocks(inode_index).unwrap_or_default().first() {
+            return Some(*block_pos as usize);
+        }
 
-        let inodes_data = self
-            .inodes
-            .iter()
-            .map(|x| x.to_le_bytes())
-            .into_iter()
-            .flatten()
-            .collect::<Vec<u8>>();
-        self.write_into_block(3, &inodes_data);
+        let group = self.layout.group_of_inode(inode_index);
+        match self.inode_blocks(parent).unwrap_or_default().first() {
+            Some(block_pos) if self.layout.group_of_block(*block_pos as usize) == group => {
+                Some(*block_pos as usize)
+            }
+            _ => Some(self.layout.first_data_block(group)),
+        }
+    }
+
+    // 从未写过的索引节点全为0，不做校验
+    pub(crate) fn inode(&self, inode_index: usize) -> Result<InodeRef> {
+        self.inodes
+            .get(inode_index, || self.load_inode(inode_index))
+    }
 
-        self.hardware.data[..4].copy_from_slice(INIT_MAGIC.to_le_bytes().as_slice());
+    // 绕过索引节点缓存，直接从（当前视图的）索引节点表读取并校验
+    pub(crate) fn load_inode(&self, inode_index: usize) -> Result<Inode> {
+        let (block_pos, offset) = self.inode_table_location(inode_index);
+        let raw = &self.read_block(block_pos)[offset..offset + INODE_SIZE];
+        if self.checksums() && raw.iter().any(|x| *x != 0) {
+            let stored = u32::from_le_bytes(raw[INODE_CHECKSUM_OFFSET..].try_into().unwrap());
+            if inode::checksum(inode_index, raw) != stored {
+                return Err(FsError::Corrupted {
+                    what: format!("索引节点 {}", inode_index),
+                    block: block_pos,
+                });
+            }
+        }
+        Ok(Inode::from_bytes(raw))
+    }
+
+    // 浏览快照时索引节点表块换成快照的副本
+    pub(crate) fn inode_table_location(&self, inode_index: usize) -> (usize, usize) {
+        let (mut block_pos, offset) = self.layout.inode_location(inode_index);
+        if let Some(view) = &self.view {
+            block_pos = view.tables[&block_pos];
+        }
+        (block_pos, offset)
+    }
+
+    pub(crate) fn inode_mut(&self, inode_index: usize) -> Result<InodeRef> {
+        let inode = self.inode(inode_index)?;
+        self.inodes.mark_dirty(inode_index);
+        Ok(inode)
+    }
+
+    fn is_ancestor(&self, ancestor: usize, mut inode_index: usize) -> Result<bool> {
+        loop {
+            if inode_index == ancestor {
+                return Ok(true);
+            }
+            if inode_index == self.root_inode_index {
+                return Ok(false);
+            }
+            inode_index = self
+                .read_dir(inode_index)?
+                .items
+                .iter()
+                .find(|item| item.name == "..")
+                .map(|item| item.inode_pos as usize)
+                .unwrap_or(self.root_inode_index);
+        }
+    }
+
+    fn copy_source(&self, src: &str) -> Result<DirItem> {
+        let (_, item) = self.resolve(src, true)?;
+        if item.typ == "dir" {
+            return Err(FsError::IsADirectory(src.to_string()));
+        }
+        if item.typ != "file" {
+            return Err(FsError::NotAFile(src.to_string()));
+        }
+        Ok(item)
+    }
+
+    // 目标是已存在的目录时复制到其中，是已存在的文件时先删除；
+    // 目标就是源文件本身时返回 None
+    fn copy_target(&mut self, item: &DirItem, src: &str, dst: &str) -> Result<Option<String>> {
+        let path = match self.resolve(dst, true) {
+            Ok((real_path, existing)) if existing.typ == "dir" => {
+                join_path(&real_path, split_path(src)?.1)
+            }
+            Ok((real_path, _)) => real_path,
+            Err(FsError::NotFound(_)) => dst.to_string(),
+            Err(e) => return Err(e),
+        };
+        match self.lookup_item(&path) {
+            Ok(existing) if existing.inode_pos == item.inode_pos => return Ok(None),
+            Ok(existing) if existing.typ == "dir" => return Err(FsError::IsADirectory(path)),
+            Ok(_) => self.remove_path(&path, false)?,
+            Err(FsError::NotFound(_)) => {}
+            Err(e) => return Err(e),
+        }
+        Ok(Some(path))
+    }
+
+    // 压缩标志由文件和子目录继承，加密策略和项目由所有新建的索引节点继承，
+    // 所有者是当前用户
+    fn inherit_attrs(&mut self, parent: usize, inode_index: usize) -> Result<()> {
+        let (flags, context, project) = {
+            let parent = self.inode(parent)?;
+            let parent = parent.borrow();
+            (parent.flags, parent.context, parent.project)
+        };
+        let name = self.inode(inode_index)?.borrow().name.clone();
+        let stored_name = self.stored_name(parent, &name)?;
+
+        let inode = self.inode_mut(inode_index)?;
+        let mut inode = inode.borrow_mut();
+        if !inode.is_symlink() {
+            inode.flags |= flags & INODE_FLAG_COMPRESS;
+        }
+        if flags & INODE_FLAG_ENCRYPTED != 0 {
+            inode.flags |= INODE_FLAG_ENCRYPTED;
+            inode.context = context.child();
+            inode.name = stored_name;
+        }
+        inode.uid = self.options.uid;
+        inode.project = project;
+        drop(inode);
+
+        self.charge_quota((self.options.uid, project), 0, 1);
+        Ok(())
+    }
+
+    // 加密目录中的名称加密后不能超过 MAX_NAME_LEN，没有密钥时不能新建
+    fn check_new_name(&self, dir: &Dir, name: &str) -> Result<()> {
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            return Err(FsError::InvalidName(name.to_string()));
+        }
+        let max_len = match self.name_cipher(dir.inode_index)? {
+            Some(_) => MAX_ENCRYPTED_NAME_LEN,
+            None => MAX_NAME_LEN,
+        };
+        if name.len() > max_len {
+            return Err(FsError::NameTooLong(name.to_string()));
+        }
+        if dir.items.iter().any(|item| item.name == name) {
+            return Err(FsError::AlreadyExists(name.to_string()));
+        }
+        Ok(())
+    }
+
+    // 检查主超级块和所有可能的副本位置，选择代数最大的有效副本。
+    // 找不到有效超级块时，只有全零的镜像才会被初始化。
+    fn load_super_block(&mut self) -> Result<()> {
+        let mut candidates = vec![(0, 0)];
+        candidates.extend(Layout::flat().super_block_copies());
+        candidates.extend(
+            (GROUP_BLOCKS..self.cache.device_blocks())
+                .step_by(GROUP_BLOCKS)
+                .map(|x| (x, 0)),
+        );
+        if let Some(block_pos) = self.options.super_block {
+            candidates.retain(|(x, _)| *x == block_pos);
+        }
+
+        let mut best: Option<(usize, SuperBlock, Vec<u8>)> = None;
+        for (block_pos, offset) in candidates {
+            let area = self.read_block(block_pos)[offset..].to_vec();
+            let Some(super_block) = parse_super_block(&area, block_pos == 0) else {
+                continue;
+            };
+            if best
+                .as_ref()
+                .is_none_or(|(_, x, _)| super_block.generation > x.generation)
+            {
+                best = Some((block_pos, super_block, area));
+            }
+        }
+
+        let Some((block_pos, mut super_block, area)) = best else {
+            if let Some(block_pos) = self.options.super_block {
+                return Err(FsError::NoSuperBlockCopy(block_pos));
+            }
+            if !self.is_blank() {
+                return Err(FsError::BadSuperBlock);
+            }
+            self.initialized = false;
+            self.root_inode_index = 0;
+            self.super_block = SuperBlock::new(self.root_inode_index as u32);
+            return Ok(());
+        };
+
+        super_block.check_mountable(self.options.read_only)?;
+        self.layout = Layout::from_super_block(&super_block, &area)?;
+        if block_pos != 0 {
+            self.recovered_from = Some(block_pos);
+        }
+        // 旧镜像在第一次可写挂载时开始维护超级块副本
+        if !self.options.read_only {
+            super_block.feature_compat |= FEATURE_COMPAT_SB_BACKUP;
+        }
+        self.initialized = true;
+        self.root_inode_index = super_block.root_inode_index as usize;
+        self.super_block = super_block;
+
+        Ok(())
+    }
+
+    fn is_blank(&self) -> bool {
+        (0..self.cache.device_blocks()).all(|x| self.read_block(x).iter().all(|b| *b == 0))
+    }
+
+    fn init_root_dir(&mut self) -> Result<()> {
+        self.set_free_inode_used(self.root_inode_index, true);
+
+        self.init_inode(self.root_inode_index, "/", DEFAULT_DIR_MODE)?;
+
+        let mut root_dir = Dir::new("/", self.root_inode_index);
+        root_dir.init_dir(self.root_inode_index);
+
+        self.write_dir(&root_dir)
+            .expect("empty image has room for the root directory");
+        self.count_dir(self.root_inode_index, true);
+        Ok(())
+    }
+
+    fn load_free_blocks(&mut self) -> Result<()> {
+        let shared = self.super_block.feature_incompat & FEATURE_INCOMPAT_SHARED_BLOCKS != 0;
+        let mut used = Vec::with_capacity(self.layout.total_blocks);
+        for g in 0..self.layout.groups.len() {
+            let len = self.layout.blocks(g).len();
+            let bitmap =
+                self.read_bitmap(self.layout.groups[g].block_bitmap as usize, len, "块位图")?;
+            // 没有块共享特性的镜像只有0和1两种取值
+            if shared {
+                used.extend_from_slice(&bitmap);
+            } else {
+                used.extend(bitmap.iter().map(|x| (*x == 1) as u8));
+            }
+        }
+        self.allocator = Allocator::new(used);
+        self.mark_metadata_used();
+        Ok(())
+    }
+
+    // 旧镜像的位图没有标记元数据块，这里统一补上
+    pub(crate) fn mark_metadata_used(&mut self) {
+        for block_pos in self.layout.metadata_blocks() {
+            self.allocator.set_used(block_pos, true);
+        }
+    }
+
+    fn load_free_inodes(&mut self) -> Result<()> {
+        let mut used = Vec::with_capacity(self.layout.inode_count());
+        for g in 0..self.layout.groups.len() {
+            let bitmap = self.read_bitmap(
+                self.layout.groups[g].inode_bitmap as usize,
+                self.layout.inodes_per_group,
+                "索引节点位图",
+            )?;
+            used.extend(bitmap.iter().map(|x| *x == 1));
+        }
+        self.free_inodes = used;
+        Ok(())
+    }
+
+    fn load_data_csums(&mut self) {
+        self.data_csums = vec![0; self.layout.total_blocks];
+        if !self.data_checksums() {
+            return;
+        }
+        for g in 0..self.layout.groups.len() {
+            let table = self.read_block(self.layout.groups[g].block_bitmap as usize);
+            let blocks = self.layout.blocks(g);
+            let start = blocks.start;
+            for (i, chunk) in table[DATA_CSUM_OFFSET..]
+                .chunks_exact(4)
+                .take(blocks.len())
+                .enumerate()
+            {
+                self.data_csums[start + i] = u32::from_le_bytes(chunk.try_into().unwrap());
+            }
+        }
+    }
+
+    pub(crate) fn read_bitmap(&self, block_pos: usize, len: usize, what: &str) -> Result<Vec<u8>> {
+        let mut bitmap = self.read_block(block_pos);
+        if self.checksums() {
+            let stored = u32::from_le_bytes(bitmap[BITMAP_CHECKSUM_OFFSET..].try_into().unwrap());
+            if group::bitmap_checksum(block_pos, &bitmap[..len]) != stored {
+                return Err(FsError::Corrupted {
+                    what: what.to_string(),
+                    block: block_pos,
+                });
+            }
+        }
+        bitmap.truncate(len);
+        Ok(bitmap)
+    }
+
+    // 新目录放在空闲索引节点和空闲块都不少于平均值、目录最少的块组，
+    // 其他文件放在父目录所在的块组，放不下时依次尝试后面的块组
+    fn get_next_free_inode(&mut self, parent: usize, is_dir: bool) -> Result<u32> {
+        let project = self.inode(parent)?.borrow().project;
+        self.check_quota((self.options.uid, project), 0, 1)?;
+        if self.usable_inodes() == 0 {
+            return Err(FsError::NoFreeInodes);
+        }
+
+        let count = self.layout.groups.len();
+        let start = if is_dir {
+            self.find_group_for_dir()
+        } else {
+            self.layout.group_of_inode(parent)
+        };
+
+        for g in (start..count).chain(0..start) {
+            for i in self.layout.inodes(g) {
+                if !self.free_inodes[i] {
+                    return Ok(i as u32);
+                }
+            }
+        }
+        Err(FsError::NoFreeInodes)
+    }
+
+    fn find_group_for_dir(&self) -> usize {
+        let groups = self.group_stats();
+        let count = groups.len() as u32;
+        let average_inodes = groups.iter().map(|x| x.free_inodes).sum::<u32>() / count;
+        let average_blocks = groups.iter().map(|x| x.free_blocks).sum::<u32>() / count;
+
+        groups
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| {
+                x.free_inodes > 0
+                    && x.free_inodes >= average_inodes
+                    && x.free_blocks >= average_blocks
+            })
+            .min_by_key(|(g, x)| (x.dirs, *g))
+            .or_else(|| groups.iter().enumerate().max_by_key(|(_, x)| x.free_inodes))
+            .map_or(0, |(g, _)| g)
+    }
+
+    pub(crate) fn count_dir(&mut self, inode_index: usize, created: bool) {
+        let g = self.layout.group_of_inode(inode_index);
+        let group = &mut self.layout.groups[g];
+        if created {
+            group.dirs += 1;
+        } else {
+            group.dirs = group.dirs.saturating_sub(1);
+        }
+    }
+
+    pub(crate) fn set_free_inode_used(&mut self, inode_pos: usize, used: bool) {
+        self.free_inodes[inode_pos] = used;
+    }
+
+    // 减少一次引用，没有其他引用（例如快照）时才真正释放并清空
+    pub(crate) fn release_block(&mut self, block_pos: usize) {
+        if self.allocator.drop_ref(block_pos) {
+            self.clean_block_data(block_pos);
+        }
+    }
+
+    // 释放后能真正空出来的块数
+    pub(crate) fn exclusive_blocks(&self, blocks: &[u32]) -> usize {
+        blocks
+            .iter()
+            .filter(|x| self.allocator.refcount(**x as usize) == 1)
+            .count()
+    }
+
+    // 计入配额的块数：数据块、区段叶子块和延迟分配预留的块
+    pub(crate) fn charged_blocks(&self, inode_pos: usize) -> Result<usize> {
+        let reserved = self.delayed.get(&inode_pos).map_or(0, |x| x.reserved);
+        Ok(self.inode_blocks(inode_pos)?.len() + self.mapping_blocks(inode_pos)?.len() + reserved)
+    }
+
+    fn write_with_inode(
+        &mut self,
+        inode_pos: usize,
+        data: &[u8],
+        goal: Option<usize>,
+    ) -> Result<()> {
+        self.write_blocks(inode_pos, data, goal, 0)
+    }
+
+    // reserved 是延迟分配时已经预留并计入配额的块数
+    fn write_blocks(
+        &mut self,
+        inode_pos: usize,
+        data: &[u8],
+        goal: Option<usize>,
+        reserved: usize,
+    ) -> Result<()> {
+        let truncated = self.truncates(inode_pos, data)?;
+        let stored = self.stored_data(inode_pos, data)?;
+        let (needed, mapping) = self.blocks_needed(inode_pos, stored.len())?;
+
+        let old_positions = self.inode_blocks(inode_pos)?;
+        let old_mapping = self.mapping_blocks(inode_pos)?;
+        let owner = self.owner(inode_pos)?;
+        let charged = old_positions.len() + old_mapping.len() + reserved;
+        self.check_quota(owner, (needed + mapping).saturating_sub(charged), 0)?;
+        let owned = self.exclusive_blocks(&old_positions) + self.exclusive_blocks(&old_mapping);
+        // 延迟分配的块在预留时已经检查过保留块
+        let available = if reserved > 0 {
+            self.allocator.available()
+        } else {
+            self.usable_blocks()
+        };
+        if needed + mapping > available + owned {
+            return Err(FsError::NoFreeBlocks);
+        }
+
+        for block_pos in old_positions.into_iter().chain(old_mapping) {
+            self.release_block(block_pos as usize);
+        }
+
+        let positions = self
+            .allocator
+            .allocate(needed, goal, Some(inode_pos))
+            .ok_or(FsError::NoFreeBlocks)?;
+        // 截短后原来的窗口已经不在文件末尾，也不需要新窗口
+        if truncated {
+            self.allocator.release(inode_pos);
+        }
+        for (chunk, block_pos) in stored.chunks(hardware::BLOCK_SIZE).zip(positions.iter()) {
+            self.write_into_block(*block_pos as usize, chunk);
+            if self.data_checksums() {
+                let block_pos = *block_pos as usize;
+                self.data_csums[block_pos] = crc32c::crc32c(&self.read_block(block_pos));
+            }
+        }
+
+        let block_map = if self.inode(inode_pos)?.borrow().uses_extents() {
+            self.build_extent_map(inode_pos, &positions)?
+        } else {
+            let mut block_map = positions;
+            block_map.resize(MAX_BLOCKS_PER_INODE, 0);
+            block_map
+        };
+
+        {
+            let inode = self.inode_mut(inode_pos)?;
+            let mut inode = inode.borrow_mut();
+            inode.size = data.len() as u32;
+            inode.block_pos = block_map;
+            inode.mtime = chrono::Utc::now().timestamp();
+        }
+
+        let used = needed + self.mapping_blocks(inode_pos)?.len();
+        self.charge_quota(owner, used as isize - charged as isize, 0);
+        Ok(())
+    }
+
+    fn truncates(&self, inode_pos: usize, data: &[u8]) -> Result<bool> {
+        Ok((data.len() as u32) < self.inode(inode_pos)?.borrow().size)
+    }
+
+    // 实际写入数据块的内容：先压缩再加密，目录的数据只加密其中的文件名
+    fn stored_data<'a>(&self, inode_pos: usize, data: &'a [u8]) -> Result<Cow<'a, [u8]>> {
+        let inode = self.inode(inode_pos)?;
+        let inode = inode.borrow();
+        let mut stored = if inode.is_compressed() {
+            Cow::Owned(compress::encode(data))
+        } else {
+            Cow::Borrowed(data)
+        };
+        if !inode.is_dir() {
+            if let Some(cipher) = self.content_cipher(inode_pos)? {
+                stored = Cow::Owned(crypt::encrypt_blocks(&cipher, &stored));
+            }
+        }
+        Ok(stored)
+    }
+
+    // 返回数据块数和区段树在最坏情况下（每块一个区段）需要的叶子块数
+    fn blocks_needed(&self, inode_pos: usize, size: usize) -> Result<(usize, usize)> {
+        let needed = size.div_ceil(hardware::BLOCK_SIZE);
+        if self.inode(inode_pos)?.borrow().uses_extents() {
+            let mapping = extent::leaves_needed(needed).ok_or(FsError::FileTooLarge(size))?;
+            Ok((needed, mapping))
+        } else if needed > MAX_BLOCKS_PER_INODE {
+            Err(FsError::FileTooLarge(size))
+        } else {
+            Ok((needed, 0))
+        }
+    }
+
+    // 立即释放旧数据块并为新数据预留空间，真正的分配推迟到 sync
+    fn write_delayed(&mut self, inode_pos: usize, data: &[u8], goal: Option<usize>) -> Result<()> {
+        let truncated = self.truncates(inode_pos, data)?;
+        let stored_len = self.stored_data(inode_pos, data)?.len();
+        let (needed, mapping) = self.blocks_needed(inode_pos, stored_len)?;
+
+        let old_positions = self.inode_blocks(inode_pos)?;
+        let old_mapping = self.mapping_blocks(inode_pos)?;
+        let old_reserved = self.delayed.get(&inode_pos).map_or(0, |x| x.reserved);
+        let owner = self.owner(inode_pos)?;
+        let charged = old_positions.len() + old_mapping.len() + old_reserved;
+        self.check_quota(owner, (needed + mapping).saturating_sub(charged), 0)?;
+        let owned = self.exclusive_blocks(&old_positions)
+            + self.exclusive_blocks(&old_mapping)
+            + old_reserved;
+        if needed + mapping > self.usable_blocks() + owned {
+            return Err(FsError::NoFreeBlocks);
+        }
+
+        for block_pos in old_positions.into_iter().chain(old_mapping) {
+            self.release_block(block_pos as usize);
+        }
+        self.allocator.unreserve(old_reserved);
+        assert!(self.allocator.reserve(needed + mapping));
+        self.charge_quota(owner, (needed + mapping) as isize - charged as isize, 0);
+
+        {
+            let inode = self.inode_mut(inode_pos)?;
+            let mut inode = inode.borrow_mut();
+            inode.size = data.len() as u32;
+            inode.block_pos = if inode.uses_extents() {
+                ExtentRoot::Leaf(Vec::new()).encode()
+            } else {
+                vec![0; MAX_BLOCKS_PER_INODE]
+            };
+            inode.mtime = chrono::Utc::now().timestamp();
+        }
+
+        self.delayed.insert(
+            inode_pos,
+            Delayed {
+                data: data.to_vec(),
+                reserved: needed + mapping,
+                goal,
+                release_window: truncated,
+            },
+        );
+        if truncated {
+            self.allocator.release(inode_pos);
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn flush_delayed(&mut self) -> Result<()> {
+        let mut delayed: Vec<(usize, Delayed)> = self.delayed.drain().collect();
+        delayed.sort_by_key(|(inode_pos, _)| *inode_pos);
+
+        for (inode_pos, pending) in delayed {
+            self.allocator.unreserve(pending.reserved);
+            let mtime = self.inode(inode_pos)?.borrow().mtime;
+            self.write_blocks(inode_pos, &pending.data, pending.goal, pending.reserved)?;
+            if pending.release_window {
+                self.allocator.release(inode_pos);
+            }
+            self.inode_mut(inode_pos)?.borrow_mut().mtime = mtime;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn build_extent_map(
+        &mut self,
+        inode_pos: usize,
+        positions: &[u32],
+    ) -> Result<Vec<u32>> {
+        let extents = extent::from_blocks(positions);
+        if extents.len() <= extent::INLINE_ENTRIES {
+            return Ok(ExtentRoot::Leaf(extents).encode());
+        }
+
+        let mut indexes = Vec::new();
+        let mut first_block = 0;
+        for chunk in extents.chunks(extent::LEAF_ENTRIES) {
+            let goal = positions.last().map(|x| *x as usize + 1);
+            let leaf = self
+                .allocator
+                .allocate(1, goal, None)
+                .ok_or(FsError::NoFreeBlocks)?[0];
+            let mut data = extent::encode_leaf(chunk);
+            if self.checksums() {
+                data.resize(extent::LEAF_CHECKSUM_OFFSET, 0);
+                let checksum = extent::leaf_checksum(inode_pos, &data);
+                data.extend_from_slice(&checksum.to_le_bytes());
+            }
+            self.write_into_block(leaf as usize, &data);
+            indexes.push(ExtentIndex { first_block, leaf });
+            first_block += chunk.iter().map(|x| x.len).sum::<u32>();
+        }
+        Ok(ExtentRoot::Index(indexes).encode())
+    }
+
+    // 让新索引节点引用源文件的数据块，区段叶子块不共享
+    fn share_blocks(&mut self, inode_pos: usize, blocks: &[u32], size: u32) -> Result<()> {
+        let (_, mapping) = self.blocks_needed(inode_pos, blocks.len() * hardware::BLOCK_SIZE)?;
+        // 共享的块也计入新文件所有者的配额
+        let owner = self.owner(inode_pos)?;
+        self.check_quota(owner, blocks.len() + mapping, 0)?;
+        if mapping > self.usable_blocks() {
+            return Err(FsError::NoFreeBlocks);
+        }
+
+        let block_map = if self.inode(inode_pos)?.borrow().uses_extents() {
+            self.build_extent_map(inode_pos, blocks)?
+        } else {
+            let mut block_map = blocks.to_vec();
+            block_map.resize(MAX_BLOCKS_PER_INODE, 0);
+            block_map
+        };
+        for block_pos in blocks {
+            self.allocator.add_ref(*block_pos as usize);
+        }
+
+        {
+            let inode = self.inode_mut(inode_pos)?;
+            let mut inode = inode.borrow_mut();
+            inode.size = size;
+            inode.block_pos = block_map;
+        }
+
+        let used = blocks.len() + self.mapping_blocks(inode_pos)?.len();
+        self.charge_quota(owner, used as isize, 0);
+        Ok(())
+    }
+
+    fn extents(&self, inode_pos: usize) -> Result<Vec<Extent>> {
+        let inode = self.inode(inode_pos)?;
+        let inode = inode.borrow();
+        if !inode.uses_extents() {
+            return Ok(Vec::new());
+        }
+
+        let indexes = match ExtentRoot::decode(&inode.block_pos) {
+            ExtentRoot::Leaf(extents) => return Ok(extents),
+            ExtentRoot::Index(indexes) => indexes,
+        };
+        let mut extents = Vec::new();
+        for index in indexes {
+            let block_pos = index.leaf as usize;
+            let data = self.read_block(block_pos);
+            if self.checksums() {
+                let offset = extent::LEAF_CHECKSUM_OFFSET;
+                let stored = u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+                if extent::leaf_checksum(inode_pos, &data) != stored {
+                    return Err(FsError::Corrupted {
+                        what: format!("索引节点 {} 的区段叶子块", inode_pos),
+                        block: block_pos,
+                    });
+                }
+            }
+            extents.extend(extent::decode_leaf(&data));
+        }
+        Ok(extents)
+    }
+
+    // 按逻辑顺序返回文件占用的数据块
+    pub(crate) fn inode_blocks(&self, inode_pos: usize) -> Result<Vec<u32>> {
+        let inode = self.inode(inode_pos)?;
+        if inode.borrow().uses_extents() {
+            return Ok(extent::to_blocks(&self.extents(inode_pos)?));
+        }
+        let blocks = inode
+            .borrow()
+            .block_pos
+            .iter()
+            .take_while(|x| **x != 0)
+            .copied()
+            .collect();
+        Ok(blocks)
+    }
+
+    // 区段树的叶子块，不包含文件数据
+    pub(crate) fn mapping_blocks(&self, inode_pos: usize) -> Result<Vec<u32>> {
+        let inode = self.inode(inode_pos)?;
+        let inode = inode.borrow();
+        if !inode.uses_extents() {
+            return Ok(Vec::new());
+        }
+        match ExtentRoot::decode(&inode.block_pos) {
+            ExtentRoot::Leaf(_) => Ok(Vec::new()),
+            ExtentRoot::Index(indexes) => Ok(indexes.iter().map(|x| x.leaf).collect()),
+        }
+    }
+
+    fn init_inode(&mut self, inode_index: usize, name: &str, mode: u16) -> Result<()> {
+        let extents = self.super_block.feature_incompat & FEATURE_INCOMPAT_EXTENTS != 0;
+        let inode = self.inode_mut(inode_index)?;
+        let mut inode = inode.borrow_mut();
+        inode.init(name, mode);
+        if extents {
+            inode.flags |= INODE_FLAG_EXTENTS;
+            inode.block_pos = ExtentRoot::Leaf(Vec::new()).encode();
+        }
+        Ok(())
+    }
+
+    // 块设备的读写错误无法恢复，与镜像加载失败一样直接终止
+    pub(crate) fn read_block(&self, block_pos: usize) -> Vec<u8> {
+        self.cache
+            .read(block_pos)
+            .unwrap_or_else(|e| panic!("{}: 读取第{}块失败: {}", self.cache.path(), block_pos, e))
+    }
+
+    pub(crate) fn write_into_block(&mut self, block_pos: usize, data: &[u8]) {
+        self.write_into_block_at(block_pos, 0, data);
+    }
+
+    pub(crate) fn write_into_block_at(&mut self, block_pos: usize, offset: usize, data: &[u8]) {
+        if let Err(e) = self.cache.write(block_pos, offset, data) {
+            panic!("{}: 写入第{}块失败: {}", self.cache.path(), block_pos, e);
+        }
+    }
+
+    fn write_bitmap(&mut self, block_pos: usize, bitmap: &[u8]) {
+        self.write_into_block(block_pos, bitmap);
+        if self.checksums() {
+            let checksum = group::bitmap_checksum(block_pos, bitmap);
+            self.write_into_block_at(block_pos, BITMAP_CHECKSUM_OFFSET, &checksum.to_le_bytes());
+        }
+    }
+
+    fn read_inode_data(&self, inode_pos: usize) -> Result<Vec<u8>> {
+        if let Some(pending) = self.delayed.get(&inode_pos) {
+            return Ok(pending.data.clone());
+        }
+
+        let mut data = Vec::new();
+        for block_pos in self.inode_blocks(inode_pos)? {
+            let block_pos = block_pos as usize;
+            if !self.data_block_ok(block_pos) {
+                return Err(FsError::Corrupted {
+                    what: format!("索引节点 {} 的数据", inode_pos),
+                    block: block_pos,
+                });
+            }
+            data.extend_from_slice(&self.read_block(block_pos));
+        }
+        Ok(data)
+    }
+
+    fn read_inode_content(&self, inode_pos: usize) -> Result<Vec<u8>> {
+        let mut data = self.read_inode_data(inode_pos)?;
+        // 延迟写入的数据还没有压缩和加密
+        if self.delayed.contains_key(&inode_pos) {
+            return Ok(data);
+        }
+        let inode = self.inode(inode_pos)?;
+        let inode = inode.borrow();
+        if !inode.is_dir() {
+            if let Some(cipher) = self.content_cipher(inode_pos)? {
+                crypt::decrypt_blocks(&cipher, &mut data);
+            }
+        }
+        if !inode.is_compressed() {
+            data.truncate(inode.size as usize);
+            return Ok(data);
+        }
+        match compress::decode(&data, inode.size as usize) {
+            Some(data) => Ok(data),
+            None => Err(FsError::Corrupted {
+                what: format!("索引节点 {} 的压缩数据", inode_pos),
+                block: self
+                    .inode_blocks(inode_pos)?
+                    .first()
+                    .map_or(0, |x| *x as usize),
+            }),
+        }
+    }
+
+    fn remove_inode_data(&mut self, inode_pos: usize) -> Result<()> {
+        let charged = self.charged_blocks(inode_pos)? as isize;
+        self.charge_quota(self.owner(inode_pos)?, -charged, -1);
+
+        let positions = self.inode_blocks(inode_pos)?;
+        let mapping = self.mapping_blocks(inode_pos)?;
+        for block_pos in positions.into_iter().chain(mapping) {
+            self.release_block(block_pos as usize);
+        }
+
+        self.set_free_inode_used(inode_pos, false);
+
+        if let Some(pending) = self.delayed.remove(&inode_pos) {
+            self.allocator.unreserve(pending.reserved);
+        }
+        self.allocator.release(inode_pos);
+
+        self.inode_mut(inode_pos)?.borrow_mut().clean();
+        self.dentries.invalidate_dir(inode_pos);
+        Ok(())
+    }
+
+    fn clean_block_data(&mut self, block_pos: usize) {
+        self.write_into_block(block_pos, &[0; hardware::BLOCK_SIZE]);
+        if let Some(checksum) = self.data_csums.get_mut(block_pos) {
+            *checksum = 0;
+        }
+    }
+
+    pub fn sync(&mut self) -> Result<usize> {
+        if self.options.read_only || self.view.is_some() {
+            return Ok(0);
+        }
+
+        self.flush_delayed()?;
+        self.flush_metadata()?;
+        self.cache
+            .flush()
+            .map_err(|e| FsError::Host(self.cache.path(), e))
+    }
+
+    fn flush_metadata(&mut self) -> Result<()> {
+        // 写时复制可能分配新块，必须在写位图之前完成
+        let dirty = self.inodes.take_dirty();
+        let mut tables: Vec<usize> = dirty
+            .iter()
+            .map(|(inode_index, _)| self.layout.inode_location(*inode_index).0)
+            .collect();
+        tables.dedup();
+        if let Err(e) = self.cow_inode_tables(&tables) {
+            for (inode_index, _) in dirty {
+                self.inodes.mark_dirty(inode_index);
+            }
+            return Err(e);
+        }
+
+        for g in 0..self.layout.groups.len() {
+            let free_block_data = self.allocator.refcounts()[self.layout.blocks(g)].to_vec();
+            self.write_bitmap(
+                self.layout.groups[g].block_bitmap as usize,
+                &free_block_data,
+            );
+            if self.data_checksums() {
+                let table = self.data_csums[self.layout.blocks(g)]
+                    .iter()
+                    .flat_map(|x| x.to_le_bytes())
+                    .collect::<Vec<u8>>();
+                self.write_into_block_at(
+                    self.layout.groups[g].block_bitmap as usize,
+                    DATA_CSUM_OFFSET,
+                    &table,
+                );
+            }
+
+            let free_inode_data = self.free_inodes[self.layout.inodes(g)]
+                .iter()
+                .map(|x| if *x { 0x01 } else { 0x00 })
+                .collect::<Vec<u8>>();
+            self.write_bitmap(
+                self.layout.groups[g].inode_bitmap as usize,
+                &free_inode_data,
+            );
+        }
+        self.layout.groups = self.group_stats();
+
+        for (inode_index, inode) in dirty {
+            let (block_pos, offset) = self.layout.inode_location(inode_index);
+            let mut data = inode.borrow().to_le_bytes();
+            if self.checksums() {
+                let checksum = inode::checksum(inode_index, &data);
+                data[INODE_CHECKSUM_OFFSET..].copy_from_slice(&checksum.to_le_bytes());
+            }
+            if let Err(e) = self.cache.write(block_pos, offset, &data) {
+                panic!("{}: 写入第{}块失败: {}", self.cache.path(), block_pos, e);
+            }
+        }
+
+        if self.quotas_enabled() {
+            self.write_quota_table();
+        }
+
+        // 超级块区域有变化时代数加一，同时更新所有副本
+        let mut locations = vec![(0, 0)];
+        if self.super_block.has_backups() {
+            locations.extend(self.layout.super_block_copies());
+        }
+        let area = self.layout.super_block_area(&self.super_block);
+        let stale = locations.iter().any(|(block_pos, offset)| {
+            self.read_block(*block_pos)[*offset..*offset + area.len()] != area[..]
+        });
+        if stale {
+            self.super_block.generation = self.super_block.generation.wrapping_add(1);
+            let area = self.layout.super_block_area(&self.super_block);
+            for (block_pos, offset) in locations {
+                self.write_into_block_at(block_pos, offset, &area);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for System {
+    fn drop(&mut self) {
+        let _ = self.sync();
+    }
+}
+
+// 启用备份的超级块必须通过校验；旧镜像没有校验和，只接受第0块的主超级块
+pub(crate) fn parse_super_block(area: &[u8], primary: bool) -> Option<SuperBlock> {
+    let super_block = SuperBlock::from_block_bytes(area);
+    if !super_block.is_initialized() {
+        return None;
+    }
+    if !super_block.has_backups() {
+        return primary.then_some(super_block);
+    }
+
+    let len = group::super_block_area_len(&super_block).filter(|x| *x <= area.len())?;
+    (group::area_checksum(&area[..len]) == super_block.checksum).then_some(super_block)
+}
+
+pub fn split_path(path: &str) -> Result<(&str, &str)> {
+    let path = path.trim_end_matches('/');
+    let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+    if name.is_empty() {
+        return Err(FsError::InvalidName(path.to_string()));
+    }
+    Ok((parent, name))
+}
+
+pub fn join_path(dir: &str, name: &str) -> String {
+    format!("{}/{}", dir.trim_end_matches('/'), name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::quota::{Limits, QuotaKind};
+
+    fn binary() -> Vec<u8> {
+        (0..6000).map(|x| (x * 7 + 0x80) as u8).collect()
+    }
+
+    #[test]
+    fn overwrite_binary_file() {
+        let mut fs = System::format(Hardware::new(), FormatOptions::default()).unwrap();
+        fs.write_path("/a", &binary()).unwrap();
+        fs.write_path("/a", &binary()).unwrap();
+        assert_eq!(fs.read_path("/a").unwrap(), binary());
+
+        fs.write_path("/a", b"x").unwrap();
+        assert_eq!(fs.read_path("/a").unwrap(), b"x");
+    }
+
+    #[test]
+    fn reject_long_names() {
+        let mut fs = System::format(Hardware::new(), FormatOptions::default()).unwrap();
+        let long = format!("/{}", "n".repeat(MAX_NAME_LEN + 1));
+        assert!(matches!(
+            fs.write_path(&long, b"x"),
+            Err(FsError::NameTooLong(_))
+        ));
+        fs.write_path("/a", b"x").unwrap();
+        assert!(matches!(
+            fs.rename("/a", &long),
+            Err(FsError::NameTooLong(_))
+        ));
+        assert!(matches!(
+            fs.create_dir_all(&long),
+            Err(FsError::NameTooLong(_))
+        ));
+    }
+
+    #[test]
+    fn recover_from_backup_super_block() {
+        let image = std::env::temp_dir().join(format!("file-sys-backup-{}", std::process::id()));
+        let image = image.to_str().unwrap().to_string();
+        let options = FormatOptions {
+            blocks: Some(128),
+            ..Default::default()
+        };
+        let mut fs = System::format(Hardware::load(&image).unwrap(), options).unwrap();
+        fs.write_path("/a", &binary()).unwrap();
+        drop(fs);
+
+        // 主超级块所在的第0块整块损坏
+        let mut data = std::fs::read(&image).unwrap();
+        data[..hardware::BLOCK_SIZE].fill(0xff);
+        std::fs::write(&image, &data).unwrap();
+
+        let fs = System::init(Hardware::load(&image).unwrap()).unwrap();
+        assert!(matches!(fs.recovered_from, Some(x) if x != 0));
+        assert_eq!(fs.read_path("/a").unwrap(), binary());
+        drop(fs);
+        std::fs::remove_file(&image).unwrap();
+    }
+
+    #[test]
+    fn statfs_matches_group_stats() {
+        let options = FormatOptions {
+            blocks: Some(128),
+            reserved_percent: 10,
+            ..Default::default()
+        };
+        let mut fs = System::format(Hardware::new(), options).unwrap();
+        // 延迟分配的写入还没有刷新，只预留了块
+        fs.write_path("/a", &binary()).unwrap();
+        let statfs = fs.statfs();
+        assert!(statfs.delayed_blocks > 0);
+        let groups: u32 = fs.group_stats().iter().map(|x| x.free_blocks).sum();
+        assert_eq!(groups as usize, statfs.free_blocks);
+        assert_eq!(
+            statfs.available_blocks,
+            statfs.free_blocks - statfs.delayed_blocks
+        );
+        assert_eq!(statfs.available_inodes, statfs.free_inodes);
+
+        // 普通用户不能使用保留的索引节点
+        fs.options.uid = 1;
+        let statfs = fs.statfs();
+        assert!(statfs.reserved_inodes > 0);
+        assert_eq!(
+            statfs.available_inodes,
+            statfs.free_inodes - statfs.reserved_inodes
+        );
+        for i in 0..statfs.available_inodes {
+            fs.write_path(&format!("/f{}", i), b"").unwrap();
+        }
+        assert!(matches!(
+            fs.write_path("/last", b""),
+            Err(FsError::NoFreeInodes)
+        ));
+        fs.options.uid = 0;
+        fs.write_path("/last", b"").unwrap();
+    }
+
+    #[test]
+    fn detect_corrupted_structures() {
+        let image = std::env::temp_dir().join(format!("file-sys-corrupt-{}", std::process::id()));
+        let image = image.to_str().unwrap().to_string();
+        let options = FormatOptions {
+            blocks: Some(128),
+            checksums: true,
+            data_checksums: true,
+            ..Default::default()
+        };
+
+        type Check = fn(&System) -> Result<()>;
+        let cases: [(&str, Check); 4] = [
+            ("dir", |fs| fs.open_dir_path("/d").map(|_| ())),
+            ("inode", |fs| fs.stat("/d/f").map(|_| ())),
+            ("data", |fs| fs.read_path("/d/f").map(|_| ())),
+            ("bitmap", |_| Ok(())),
+        ];
+        for (what, check) in cases {
+            let _ = std::fs::remove_file(&image);
+            let mut fs = System::format(Hardware::load(&image).unwrap(), options).unwrap();
+            fs.create_dir_all("/d").unwrap();
+            fs.write_path("/d/f", &binary()).unwrap();
+            fs.sync().unwrap();
+            let inode_index = fs.lookup("/d/f").unwrap();
+            let (block, offset) = match what {
+                "dir" => (fs.stat("/d").unwrap().blocks[0] as usize, 0),
+                "inode" => fs.layout.inode_location(inode_index),
+                "data" => (fs.stat("/d/f").unwrap().blocks[0] as usize, 0),
+                _ => (fs.layout.groups[0].block_bitmap as usize, 0),
+            };
+            drop(fs);
+
+            let mut data = std::fs::read(&image).unwrap();
+            data[block * hardware::BLOCK_SIZE + offset + 1] ^= 0xff;
+            std::fs::write(&image, &data).unwrap();
+
+            let result = System::init(Hardware::load(&image).unwrap()).and_then(|fs| check(&fs));
+            assert!(
+                matches!(result, Err(FsError::Corrupted { block: x, .. }) if x == block),
+                "{}: {:?}",
+                what,
+                result
+            );
+        }
+        std::fs::remove_file(&image).unwrap();
+    }
+
+    #[test]
+    fn delayed_file_keeps_zero_bytes() {
+        let mut fs = System::format(Hardware::new(), FormatOptions::default()).unwrap();
+        let data = b"a\0\0b\0".repeat(1000);
+        fs.write_path("/a", &data).unwrap();
+
+        let stat = fs.stat("/a").unwrap();
+        assert!(stat.blocks.is_empty());
+        assert_eq!(
+            stat.delayed_blocks,
+            data.len().div_ceil(hardware::BLOCK_SIZE)
+        );
+        let mut dir = fs.get_root_dir().unwrap();
+        let file = fs.open_file(&mut dir, "a").unwrap();
+        assert_eq!(file.size as usize, data.len());
+        assert_eq!(file.content.as_bytes(), data);
+
+        fs.sync().unwrap();
+        let stat = fs.stat("/a").unwrap();
+        assert_eq!((stat.blocks.len(), stat.delayed_blocks), (2, 0));
+        let file = fs.open_file(&mut dir, "a").unwrap();
+        assert_eq!(file.content.as_bytes(), data);
+    }
+
+    #[test]
+    fn clone_copies_on_write() {
+        let mut fs = System::format(Hardware::new(), FormatOptions::default()).unwrap();
+        fs.write_path("/a", &binary()).unwrap();
+        fs.clone_file("/a", "/b").unwrap();
+        assert_eq!(fs.stat("/a").unwrap().blocks, fs.stat("/b").unwrap().blocks);
+
+        fs.write_path("/b", b"changed").unwrap();
+        fs.sync().unwrap();
+        assert_eq!(fs.read_path("/a").unwrap(), binary());
+        assert_eq!(fs.read_path("/b").unwrap(), b"changed");
+        let shared = fs.stat("/a").unwrap().blocks;
+        assert!(shared
+            .iter()
+            .all(|x| fs.allocator.refcount(*x as usize) == 1));
+    }
+
+    #[test]
+    fn read_only_rejects_every_mutator() {
+        let image = std::env::temp_dir().join(format!("file-sys-ro-{}", std::process::id()));
+        let image = image.to_str().unwrap().to_string();
+        let options = FormatOptions {
+            blocks: Some(128),
+            ..Default::default()
+        };
+        let mut fs = System::format(Hardware::load(&image).unwrap(), options).unwrap();
+        fs.write_path("/a", &binary()).unwrap();
+        fs.create_dir_all("/d").unwrap();
+        fs.create_snapshot("s1").unwrap();
+        fs.enable_trash(10).unwrap();
+        fs.write_path("/t", b"t").unwrap();
+        fs.delete_path("/t", false).unwrap();
+        drop(fs);
+        let before = std::fs::read(&image).unwrap();
+
+        let options = MountOptions {
+            read_only: true,
+            ..Default::default()
+        };
+        let mut fs = System::mount(Hardware::load_read_only(&image).unwrap(), options).unwrap();
+        fs.set_passphrase("secret");
+        type Mutator = fn(&mut System) -> Result<()>;
+        let mutators: Vec<(&str, Mutator)> = vec![
+            ("write_path", |fs| fs.write_path("/a", b"x")),
+            ("write_path new", |fs| fs.write_path("/new", b"x")),
+            ("create_dir_all", |fs| fs.create_dir_all("/n/m").map(|_| ())),
+            ("remove_path", |fs| fs.remove_path("/a", false)),
+            ("rename", |fs| fs.rename("/a", "/b")),
+            ("symlink", |fs| fs.symlink("/a", "/s")),
+            ("link", |fs| fs.link("/a", "/h")),
+            ("clone_file", |fs| fs.clone_file("/a", "/c")),
+            ("copy_file", |fs| fs.copy_file("/a", "/c")),
+            ("set_mode", |fs| fs.set_mode("/a", 0o600)),
+            ("set_compression", |fs| fs.set_compression("/a", true)),
+            ("set_mtime", |fs| fs.set_mtime("/a", 0)),
+            ("set_reserved_percent", |fs| fs.set_reserved_percent(1)),
+            ("encrypt_dir", |fs| fs.encrypt_dir("/d")),
+            ("dedupe", |fs| fs.dedupe().map(|_| ())),
+            ("defrag", |fs| fs.defrag("/").map(|_| ())),
+            ("resize", |fs| fs.resize(160).map(|_| ())),
+            ("create_snapshot", |fs| fs.create_snapshot("s2")),
+            ("delete_snapshot", |fs| fs.delete_snapshot("s1")),
+            ("rollback_snapshot", |fs| fs.rollback_snapshot("s1")),
+            ("set_quota", |fs| {
+                fs.set_quota(QuotaKind::User, 1, Limits::default())
+            }),
+            ("set_grace_period", |fs| fs.set_grace_period(60)),
+            ("set_owner", |fs| fs.set_owner("/a", 1)),
+            ("set_project", |fs| fs.set_project("/a", 1)),
+            ("enable_trash", |fs| fs.enable_trash(20)),
+            ("disable_trash", |fs| fs.disable_trash()),
+            ("delete_path", |fs| fs.delete_path("/a", false)),
+            ("restore_trash", |fs| fs.restore_trash(1, None).map(|_| ())),
+            ("empty_trash", |fs| fs.empty_trash().map(|_| ())),
+            ("tar_in", |fs| {
+                fs.tar_in(&[0u8; 1024][..], "/x", &mut |_| {}).map(|_| ())
+            }),
+            ("import_tree", |fs| {
+                fs.import_tree(&std::env::temp_dir(), "/x", &mut |_| {})
+                    .map(|_| ())
+            }),
+        ];
+        for (name, mutator) in mutators {
+            assert!(
+                matches!(mutator(&mut fs), Err(FsError::ReadOnly)),
+                "{}",
+                name
+            );
+        }
+        assert_eq!(fs.purge_trash().unwrap(), 0);
+        fs.sync().unwrap();
+        drop(fs);
 
-        self.hardware.save(name);
+        assert!(std::fs::read(&image).unwrap() == before);
+        std::fs::remove_file(&image).unwrap();
     }
 }
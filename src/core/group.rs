@@ -0,0 +1,305 @@
+use std::ops::Range;
+
+use crate::core::crc32c;
+use crate::core::error::{FsError, Result};
+use crate::core::hardware::{BLOCK_SIZE, TOTAL_BLOCKS};
+use crate::core::inode::{INODES_PER_BLOCK, INODE_SIZE};
+use crate::core::superblock::{
+    SuperBlock, BLOCK_BITMAP_BLOCK, CHECKSUM_OFFSET, FEATURE_INCOMPAT_GROUPS,
+    FEATURE_RO_COMPAT_DATA_CSUM, INODE_BITMAP_BLOCK, INODE_COUNT, INODE_TABLE_BLOCK,
+    INODE_TABLE_BLOCKS,
+};
+
+pub const GROUP_BLOCKS: usize = 32;
+pub const GROUP_INODE_TABLE_BLOCKS: usize = 1;
+// 块组描述符紧跟在第0块的超级块之后
+pub const GROUP_DESC_OFFSET: usize = 64;
+pub const GROUP_DESC_SIZE: usize = 24;
+pub const MAX_GROUPS: usize = (BLOCK_SIZE - GROUP_DESC_OFFSET) / GROUP_DESC_SIZE;
+// 未启用块组时，超级块副本放在两个位图块的后半部分
+pub const FLAT_BACKUP_OFFSET: usize = BLOCK_SIZE / 2;
+// 启用元数据校验和时，位图块的最后4字节保存校验和
+pub const BITMAP_CHECKSUM_OFFSET: usize = BLOCK_SIZE - 4;
+// 启用数据块校验和时，块位图块从这里开始保存本组每个块的校验和
+pub const DATA_CSUM_OFFSET: usize = 1024;
+pub const MAX_DATA_CSUM_BLOCKS: usize = (FLAT_BACKUP_OFFSET - DATA_CSUM_OFFSET) / 4;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct GroupDesc {
+    pub block_bitmap: u32,
+    pub inode_bitmap: u32,
+    pub inode_table: u32,
+    pub free_blocks: u32,
+    pub free_inodes: u32,
+    pub dirs: u32,
+}
+
+impl GroupDesc {
+    pub fn from_bytes(data: &[u8]) -> Self {
+        let field = |i: usize| u32::from_le_bytes(data[i * 4..i * 4 + 4].try_into().unwrap());
+
+        Self {
+            block_bitmap: field(0),
+            inode_bitmap: field(1),
+            inode_table: field(2),
+            free_blocks: field(3),
+            free_inodes: field(4),
+            dirs: field(5),
+        }
+    }
+
+    pub fn to_le_bytes(&self) -> Vec<u8> {
+        [
+            self.block_bitmap,
+            self.inode_bitmap,
+            self.inode_table,
+            self.free_blocks,
+            self.free_inodes,
+            self.dirs,
+        ]
+        .iter()
+        .flat_map(|x| x.to_le_bytes())
+        .collect()
+    }
+}
+
+// 每个块组依次为：超级块位置、块位图、索引节点位图、索引节点表，其余为数据块。
+// 第0组的第一块保存超级块，其他组的这一块保存超级块和块组描述符的副本。
+// 未启用块组特性的镜像相当于只有一个块组，沿用原来的固定布局。
+#[derive(Debug, Clone)]
+pub struct Layout {
+    pub grouped: bool,
+    pub total_blocks: usize,
+    pub blocks_per_group: usize,
+    pub inodes_per_group: usize,
+    pub inode_table_blocks: usize,
+    pub groups: Vec<GroupDesc>,
+}
+
+impl Layout {
+    pub fn flat() -> Self {
+        Self {
+            grouped: false,
+            total_blocks: TOTAL_BLOCKS,
+            blocks_per_group: TOTAL_BLOCKS,
+            inodes_per_group: INODE_COUNT,
+            inode_table_blocks: INODE_TABLE_BLOCKS,
+            groups: vec![GroupDesc {
+                block_bitmap: BLOCK_BITMAP_BLOCK as u32,
+                inode_bitmap: INODE_BITMAP_BLOCK as u32,
+                inode_table: INODE_TABLE_BLOCK as u32,
+                ..Default::default()
+            }],
+        }
+    }
+
+    pub fn grouped(total_blocks: usize) -> Result<Self> {
+        let empty = Self {
+            grouped: true,
+            total_blocks: 0,
+            blocks_per_group: GROUP_BLOCKS,
+            inodes_per_group: GROUP_INODE_TABLE_BLOCKS * INODES_PER_BLOCK,
+            inode_table_blocks: GROUP_INODE_TABLE_BLOCKS,
+            groups: Vec::new(),
+        };
+        empty.resized(total_blocks)
+    }
+
+    // 调整为指定的块数：保留已有块组的描述符，新增的块组沿用同样的结构
+    pub fn resized(&self, total_blocks: usize) -> Result<Self> {
+        let overhead = 3 + self.inode_table_blocks;
+        let mut count = total_blocks / self.blocks_per_group;
+        let mut total_blocks = total_blocks;
+        // 最后不足一组的部分放不下元数据和至少一个数据块时舍去
+        let tail = total_blocks % self.blocks_per_group;
+        if tail > overhead {
+            count += 1;
+        } else {
+            total_blocks -= tail;
+        }
+
+        if count == 0 {
+            return Err(FsError::InvalidLayout(format!(
+                "至少需要 {} 块",
+                overhead + 1
+            )));
+        }
+        if count > MAX_GROUPS {
+            return Err(FsError::InvalidLayout(format!(
+                "最多支持 {} 个块组 ({} 块)",
+                MAX_GROUPS,
+                MAX_GROUPS * self.blocks_per_group
+            )));
+        }
+
+        let groups = (0..count)
+            .map(|g| {
+                self.groups.get(g).copied().unwrap_or_else(|| {
+                    let start = (g * self.blocks_per_group) as u32;
+                    GroupDesc {
+                        block_bitmap: start + 1,
+                        inode_bitmap: start + 2,
+                        inode_table: start + 3,
+                        ..Default::default()
+                    }
+                })
+            })
+            .collect();
+
+        Ok(Self {
+            grouped: true,
+            total_blocks,
+            blocks_per_group: self.blocks_per_group,
+            inodes_per_group: self.inodes_per_group,
+            inode_table_blocks: self.inode_table_blocks,
+            groups,
+        })
+    }
+
+    pub fn from_super_block(super_block: &SuperBlock, block: &[u8]) -> Result<Self> {
+        if super_block.feature_incompat & FEATURE_INCOMPAT_GROUPS == 0 {
+            return Ok(Self::flat());
+        }
+
+        let total_blocks = super_block.blocks_count as usize;
+        let blocks_per_group = super_block.blocks_per_group as usize;
+        let inodes_per_group = super_block.inodes_per_group as usize;
+        if blocks_per_group == 0
+            || blocks_per_group > BITMAP_CHECKSUM_OFFSET
+            || inodes_per_group == 0
+            || inodes_per_group > BITMAP_CHECKSUM_OFFSET
+        {
+            return Err(FsError::InvalidLayout(String::from("块组参数无效")));
+        }
+        if super_block.feature_ro_compat & FEATURE_RO_COMPAT_DATA_CSUM != 0
+            && blocks_per_group > MAX_DATA_CSUM_BLOCKS
+        {
+            return Err(FsError::InvalidLayout(String::from(
+                "每组块数过多，放不下数据块校验和",
+            )));
+        }
+        let count = total_blocks.div_ceil(blocks_per_group);
+        if count == 0 || count > MAX_GROUPS {
+            return Err(FsError::InvalidLayout(format!("块组数 {} 无效", count)));
+        }
+
+        let groups = block[GROUP_DESC_OFFSET..]
+            .chunks_exact(GROUP_DESC_SIZE)
+            .take(count)
+            .map(GroupDesc::from_bytes)
+            .collect();
+
+        Ok(Self {
+            grouped: true,
+            total_blocks,
+            blocks_per_group,
+            inodes_per_group,
+            inode_table_blocks: inodes_per_group.div_ceil(INODES_PER_BLOCK),
+            groups,
+        })
+    }
+
+    // 第0块的内容：超级块，启用块组时后面跟着块组描述符
+    pub fn super_block_area(&self, super_block: &SuperBlock) -> Vec<u8> {
+        let mut data = super_block.to_le_bytes();
+        if self.grouped {
+            data.resize(GROUP_DESC_OFFSET, 0);
+            for group in self.groups.iter() {
+                data.extend_from_slice(&group.to_le_bytes());
+            }
+        }
+        if super_block.has_backups() {
+            let checksum = area_checksum(&data);
+            data[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 4].copy_from_slice(&checksum.to_le_bytes());
+        }
+        data
+    }
+
+    // 超级块副本的位置（块号, 块内偏移），不包括第0块的主超级块
+    pub fn super_block_copies(&self) -> Vec<(usize, usize)> {
+        if !self.grouped {
+            return vec![
+                (BLOCK_BITMAP_BLOCK, FLAT_BACKUP_OFFSET),
+                (INODE_BITMAP_BLOCK, FLAT_BACKUP_OFFSET),
+            ];
+        }
+        (1..self.groups.len())
+            .map(|g| (self.blocks(g).start, 0))
+            .collect()
+    }
+
+    pub fn blocks(&self, group: usize) -> Range<usize> {
+        let start = group * self.blocks_per_group;
+        start..(start + self.blocks_per_group).min(self.total_blocks)
+    }
+
+    pub fn inodes(&self, group: usize) -> Range<usize> {
+        let start = group * self.inodes_per_group;
+        start..start + self.inodes_per_group
+    }
+
+    pub fn group_of_block(&self, block_pos: usize) -> usize {
+        block_pos / self.blocks_per_group
+    }
+
+    pub fn group_of_inode(&self, inode_index: usize) -> usize {
+        inode_index / self.inodes_per_group
+    }
+
+    pub fn inode_count(&self) -> usize {
+        self.groups.len() * self.inodes_per_group
+    }
+
+    // 返回索引节点所在的块和块内偏移
+    pub fn inode_location(&self, inode_index: usize) -> (usize, usize) {
+        let group = &self.groups[self.group_of_inode(inode_index)];
+        let index = inode_index % self.inodes_per_group;
+        (
+            group.inode_table as usize + index / INODES_PER_BLOCK,
+            index % INODES_PER_BLOCK * INODE_SIZE,
+        )
+    }
+
+    pub fn first_data_block(&self, group: usize) -> usize {
+        self.groups[group].inode_table as usize + self.inode_table_blocks
+    }
+
+    // 按索引节点号顺序排列的所有索引节点表块
+    pub fn inode_tables(&self) -> Vec<usize> {
+        self.groups
+            .iter()
+            .flat_map(|x| x.inode_table as usize..x.inode_table as usize + self.inode_table_blocks)
+            .collect()
+    }
+
+    pub fn metadata_blocks(&self) -> Vec<usize> {
+        (0..self.groups.len())
+            .flat_map(|g| self.blocks(g).start..self.first_data_block(g))
+            .collect()
+    }
+}
+
+// 超级块区域的长度：启用块组时包括块组描述符，参数无效时返回 None
+pub fn super_block_area_len(super_block: &SuperBlock) -> Option<usize> {
+    let len = super_block.to_le_bytes().len();
+    if super_block.feature_incompat & FEATURE_INCOMPAT_GROUPS == 0 {
+        return Some(len);
+    }
+    let blocks_per_group = super_block.blocks_per_group as usize;
+    if blocks_per_group == 0 {
+        return None;
+    }
+    let count = (super_block.blocks_count as usize).div_ceil(blocks_per_group);
+    (count <= MAX_GROUPS).then_some(GROUP_DESC_OFFSET + count * GROUP_DESC_SIZE)
+}
+
+pub fn bitmap_checksum(block_pos: usize, bitmap: &[u8]) -> u32 {
+    crc32c::owned(block_pos, bitmap)
+}
+
+// 计算校验和时把校验和字段本身视为0
+pub fn area_checksum(area: &[u8]) -> u32 {
+    let crc = crc32c::crc32c(&area[..CHECKSUM_OFFSET]);
+    let crc = crc32c::update(crc, &[0; 4]);
+    crc32c::update(crc, &area[CHECKSUM_OFFSET + 4..])
+}
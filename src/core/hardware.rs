@@ -3,33 +3,163 @@ use std::fs;
 pub const BLOCK_SIZE: usize = 4096;
 pub const TOTAL_BLOCKS: usize = 64;
 
+#[derive(Debug)]
+enum Backend {
+    Memory(Vec<u8>),
+    File(fs::File),
+}
+
 #[derive(Debug)]
 pub struct Hardware {
-    pub data: Vec<u8>,
+    path: String,
+    backend: Backend,
+}
+
+impl Default for Hardware {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Hardware {
     pub fn new() -> Self {
         Self {
-            data: vec![0; BLOCK_SIZE * TOTAL_BLOCKS],
+            path: String::new(),
+            backend: Backend::Memory(vec![0; BLOCK_SIZE * TOTAL_BLOCKS]),
         }
     }
 
-    pub fn load(path: &str) -> Self {
+    // 镜像不存在时创建一个默认大小的空白镜像
+    pub fn load(path: &str) -> std::io::Result<Self> {
         if fs::metadata(path).is_err() {
-            fs::write(path, &vec![0; BLOCK_SIZE * TOTAL_BLOCKS]).unwrap();
+            fs::write(path, vec![0; BLOCK_SIZE * TOTAL_BLOCKS])?;
+        }
+
+        let file = fs::OpenOptions::new().read(true).write(true).open(path)?;
+        Self::from_file(path, file)
+    }
+
+    pub fn load_read_only(path: &str) -> std::io::Result<Self> {
+        let file = fs::File::open(path)?;
+        Self::from_file(path, file)
+    }
+
+    fn from_file(path: &str, file: fs::File) -> std::io::Result<Self> {
+        let len = file.metadata()?.len();
+        if len == 0 || !len.is_multiple_of(BLOCK_SIZE as u64) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("镜像大小 {} 不是块大小 {} 的正整数倍", len, BLOCK_SIZE),
+            ));
+        }
+
+        Ok(Self {
+            path: path.to_string(),
+            backend: Backend::File(file),
+        })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn block_count(&self) -> usize {
+        match &self.backend {
+            Backend::Memory(data) => data.len() / BLOCK_SIZE,
+            Backend::File(file) => file.metadata().map_or(0, |x| x.len() as usize / BLOCK_SIZE),
+        }
+    }
+
+    pub fn set_block_count(&mut self, blocks: usize) -> std::io::Result<()> {
+        match &mut self.backend {
+            Backend::Memory(data) => {
+                data.resize(blocks * BLOCK_SIZE, 0);
+                Ok(())
+            }
+            Backend::File(file) => file.set_len((blocks * BLOCK_SIZE) as u64),
         }
+    }
+
+    pub fn read_block(&self, block_pos: usize, buf: &mut [u8]) -> std::io::Result<()> {
+        assert_eq!(buf.len(), BLOCK_SIZE);
+        let offset = block_pos * BLOCK_SIZE;
+        match &self.backend {
+            Backend::Memory(data) => {
+                buf.copy_from_slice(&data[offset..offset + BLOCK_SIZE]);
+                Ok(())
+            }
+            Backend::File(file) => read_at(file, buf, offset as u64),
+        }
+    }
 
-        let data = fs::read(path).unwrap();
+    pub fn write_block(&mut self, block_pos: usize, data: &[u8]) -> std::io::Result<()> {
+        assert_eq!(data.len(), BLOCK_SIZE);
+        let offset = block_pos * BLOCK_SIZE;
+        match &mut self.backend {
+            Backend::Memory(buf) => {
+                buf[offset..offset + BLOCK_SIZE].copy_from_slice(data);
+                Ok(())
+            }
+            Backend::File(file) => write_at(file, data, offset as u64),
+        }
+    }
 
-        if data.len() != BLOCK_SIZE * TOTAL_BLOCKS {
-            panic!("Invalid file size");
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        match &self.backend {
+            Backend::Memory(_) => Ok(()),
+            Backend::File(file) => file.sync_data(),
         }
+    }
 
-        Self { data }
+    pub fn read_all(&self) -> std::io::Result<Vec<u8>> {
+        let mut data = vec![0; BLOCK_SIZE * self.block_count()];
+        for (i, chunk) in data.chunks_exact_mut(BLOCK_SIZE).enumerate() {
+            self.read_block(i, chunk)?;
+        }
+        Ok(data)
     }
+}
+
+#[cfg(unix)]
+fn read_at(file: &fs::File, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, offset)
+}
+
+#[cfg(not(unix))]
+fn read_at(mut file: &fs::File, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+    use std::io::{Read, Seek, SeekFrom};
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(buf)
+}
+
+#[cfg(unix)]
+fn write_at(file: &fs::File, data: &[u8], offset: u64) -> std::io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.write_all_at(data, offset)
+}
+
+#[cfg(not(unix))]
+fn write_at(mut file: &fs::File, data: &[u8], offset: u64) -> std::io::Result<()> {
+    use std::io::{Seek, SeekFrom, Write};
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_reports_bad_images() {
+        let image = std::env::temp_dir().join(format!("file-sys-hardware-{}", std::process::id()));
+        let image = image.to_str().unwrap().to_string();
+        fs::write(&image, b"abc").unwrap();
+        let e = Hardware::load(&image).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        fs::remove_file(&image).unwrap();
 
-    pub fn save(&self, path: &str) {
-        fs::write(path, &self.data).unwrap();
+        assert!(Hardware::load_read_only(&image).is_err());
+        assert!(Hardware::load("/nonexistent/file-sys-image").is_err());
     }
 }
@@ -0,0 +1,177 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::core::inode::Inode;
+
+pub const DEFAULT_INODE_CACHE: usize = 32;
+
+pub type InodeRef = Rc<RefCell<Inode>>;
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct InodeCacheStats {
+    pub capacity: usize,
+    pub cached: usize,
+    pub dirty: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    inode: InodeRef,
+    dirty: bool,
+    last_used: u64,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    entries: HashMap<usize, Entry>,
+    tick: u64,
+    hits: u64,
+    misses: u64,
+}
+
+#[derive(Debug)]
+pub struct InodeCache {
+    capacity: usize,
+    inner: RefCell<Inner>,
+}
+
+impl InodeCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            inner: RefCell::new(Inner::default()),
+        }
+    }
+
+    pub fn get<E>(
+        &self,
+        index: usize,
+        load: impl FnOnce() -> Result<Inode, E>,
+    ) -> Result<InodeRef, E> {
+        let mut inner = self.inner.borrow_mut();
+        inner.tick += 1;
+        let tick = inner.tick;
+
+        if let Some(entry) = inner.entries.get_mut(&index) {
+            entry.last_used = tick;
+            let inode = entry.inode.clone();
+            inner.hits += 1;
+            return Ok(inode);
+        }
+
+        inner.misses += 1;
+        inner.evict(self.capacity);
+
+        let inode = Rc::new(RefCell::new(load()?));
+        inner.entries.insert(
+            index,
+            Entry {
+                inode: inode.clone(),
+                dirty: false,
+                last_used: tick,
+            },
+        );
+        Ok(inode)
+    }
+
+    pub fn mark_dirty(&self, index: usize) {
+        if let Some(entry) = self.inner.borrow_mut().entries.get_mut(&index) {
+            entry.dirty = true;
+        }
+    }
+
+    pub fn take_dirty(&mut self) -> Vec<(usize, InodeRef)> {
+        let mut dirty: Vec<(usize, InodeRef)> = self
+            .inner
+            .get_mut()
+            .entries
+            .iter_mut()
+            .filter(|(_, entry)| entry.dirty)
+            .map(|(index, entry)| {
+                entry.dirty = false;
+                (*index, entry.inode.clone())
+            })
+            .collect();
+        dirty.sort_unstable_by_key(|(index, _)| *index);
+        dirty
+    }
+
+    // 丢弃所有缓存的索引节点，调用前脏节点必须已经写回
+    pub fn clear(&mut self) {
+        self.inner.get_mut().entries.clear();
+    }
+
+    // 丢弃索引节点号不小于 count 的缓存节点，包括脏节点
+    pub fn truncate(&mut self, count: usize) {
+        self.inner
+            .get_mut()
+            .entries
+            .retain(|index, _| *index < count);
+    }
+
+    pub fn stats(&self) -> InodeCacheStats {
+        let inner = self.inner.borrow();
+        InodeCacheStats {
+            capacity: self.capacity,
+            cached: inner.entries.len(),
+            dirty: inner.entries.values().filter(|x| x.dirty).count(),
+            hits: inner.hits,
+            misses: inner.misses,
+        }
+    }
+}
+
+impl Inner {
+    // 脏节点和仍被外部持有的节点不能淘汰，因此缓存中的节点数可能暂时超过容量
+    fn evict(&mut self, capacity: usize) {
+        while self.entries.len() >= capacity {
+            let Some(victim) = self
+                .entries
+                .iter()
+                .filter(|(_, entry)| !entry.dirty && Rc::strong_count(&entry.inode) == 1)
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(index, _)| *index)
+            else {
+                break;
+            };
+            self.entries.remove(&victim);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::core::error::FsError;
+    use crate::core::fs::{FormatOptions, System};
+    use crate::core::hardware::Hardware;
+
+    #[test]
+    fn coherent_after_unlink() {
+        let mut fs = System::format(Hardware::new(), FormatOptions::default()).unwrap();
+        fs.write_path("/a", b"first").unwrap();
+        fs.link("/a", "/b").unwrap();
+        let index = fs.stat("/a").unwrap().inode_index;
+        assert_eq!(fs.stat("/b").unwrap().links, 2);
+
+        // 通过另一个名字看到的链接数来自同一个缓存节点
+        fs.remove_path("/a", false).unwrap();
+        assert_eq!(fs.stat("/b").unwrap().links, 1);
+        assert_eq!(fs.read_path("/b").unwrap(), b"first");
+
+        // 索引节点释放后被重新使用，不能读到旧内容
+        fs.remove_path("/b", false).unwrap();
+        assert!(matches!(fs.stat("/b"), Err(FsError::NotFound(_))));
+        fs.write_path("/c", b"second").unwrap();
+        let stat = fs.stat("/c").unwrap();
+        assert_eq!(stat.inode_index, index);
+        assert_eq!((stat.links, stat.size), (1, 6));
+        assert_eq!(fs.read_path("/c").unwrap(), b"second");
+
+        fs.sync().unwrap();
+        assert_eq!(fs.inode_cache_stats().dirty, 0);
+        assert_eq!(fs.read_path("/c").unwrap(), b"second");
+    }
+}
@@ -1,65 +1,176 @@
-const INODE_SIZE: usize = 64;
+use crate::core::crc32c;
+use crate::core::crypt::{Context, CONTEXT_SIZE};
+use crate::core::hardware::BLOCK_SIZE;
+
+pub const INODE_SIZE: usize = 128;
+pub const INODES_PER_BLOCK: usize = BLOCK_SIZE / INODE_SIZE;
+pub const MAX_NAME_LEN: usize = 31;
+pub const MAX_BLOCKS_PER_INODE: usize = 7;
+// 启用元数据校验和时，索引节点的最后4字节保存校验和
+pub const INODE_CHECKSUM_OFFSET: usize = INODE_SIZE - 4;
+
+pub const S_IFMT: u16 = 0o170000;
+pub const S_IFDIR: u16 = 0o040000;
+pub const S_IFREG: u16 = 0o100000;
+pub const S_IFLNK: u16 = 0o120000;
+
+pub const DEFAULT_DIR_MODE: u16 = S_IFDIR | 0o755;
+pub const DEFAULT_FILE_MODE: u16 = S_IFREG | 0o644;
+pub const DEFAULT_SYMLINK_MODE: u16 = S_IFLNK | 0o777;
+
+pub const INODE_FLAG_EXTENTS: u32 = 0x1;
+// 文件写入时压缩数据；目录上的标志由新建的文件和子目录继承
+pub const INODE_FLAG_COMPRESS: u32 = 0x2;
+// 文件内容与目录中的文件名已加密，加密上下文保存在标志之后
+pub const INODE_FLAG_ENCRYPTED: u32 = 0x4;
 
 #[derive(Debug)]
 pub struct Inode {
     pub name: String,
     pub size: u32,
     pub block_pos: Vec<u32>,
+    pub mode: u16,
+    pub links: u16,
+    pub mtime: i64,
+    pub ctime: i64,
+    pub flags: u32,
+    pub context: Context,
+    // 所有者和所属项目，用于配额统计
+    pub uid: u16,
+    pub project: u16,
 }
 
 impl Inode {
-    pub fn init(&mut self, name: &str) {
+    pub fn init(&mut self, name: &str, mode: u16) {
+        let now = chrono::Utc::now().timestamp();
         self.name = name.to_string();
         self.size = 0;
         self.block_pos = Vec::new();
+        self.mode = mode;
+        self.links = 1;
+        self.mtime = now;
+        self.ctime = now;
+        self.flags = 0;
+        self.context = Context::default();
+        self.uid = 0;
+        self.project = 0;
     }
 
     pub fn clean(&mut self) {
         self.name = String::new();
         self.size = 0;
         self.block_pos = Vec::new();
+        self.mode = 0;
+        self.links = 0;
+        self.mtime = 0;
+        self.ctime = 0;
+        self.flags = 0;
+        self.context = Context::default();
+        self.uid = 0;
+        self.project = 0;
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.mode & S_IFMT == S_IFLNK
+    }
+
+    pub fn uses_extents(&self) -> bool {
+        self.flags & INODE_FLAG_EXTENTS != 0
+    }
+
+    // 目录上的压缩标志只用于继承，目录本身的数据不压缩
+    pub fn is_compressed(&self) -> bool {
+        self.flags & INODE_FLAG_COMPRESS != 0 && !self.is_dir()
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.flags & INODE_FLAG_ENCRYPTED != 0
     }
 
     pub fn from_block_bytes(data: &[u8]) -> Vec<Inode> {
-        let inodes = data
-            .chunks_exact(INODE_SIZE)
-            .map(|chunk| {
-                let mut i = 0;
-                let name_len = chunk[i];
-                let name =
-                    String::from_utf8(chunk[i + 1..i + 1 + name_len as usize].to_vec()).unwrap();
-
-                i += 32;
-
-                let size = u32::from_le_bytes(chunk[i..i + 4].try_into().unwrap());
-
-                i += 4;
-
-                let block_pos = chunk[i..i + 28]
-                    .to_vec()
-                    .chunks_exact(4)
-                    .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()))
-                    .collect();
-
-                Inode {
-                    name: name,
-                    size: size,
-                    block_pos: block_pos,
-                }
-            })
+        data.chunks_exact(INODE_SIZE)
+            .map(Inode::from_bytes)
+            .collect()
+    }
+
+    pub fn from_bytes(chunk: &[u8]) -> Inode {
+        let mut i = 0;
+        let name_len = (chunk[i] as usize).min(MAX_NAME_LEN);
+        let name = String::from_utf8_lossy(&chunk[i + 1..i + 1 + name_len]).to_string();
+
+        i += 32;
+
+        let size = u32::from_le_bytes(chunk[i..i + 4].try_into().unwrap());
+
+        i += 4;
+
+        let block_pos = chunk[i..i + 28]
+            .to_vec()
+            .chunks_exact(4)
+            .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()))
             .collect();
-        inodes
+
+        i += 28;
+
+        let mode = u16::from_le_bytes(chunk[i..i + 2].try_into().unwrap());
+
+        i += 2;
+
+        let links = u16::from_le_bytes(chunk[i..i + 2].try_into().unwrap());
+
+        i += 2;
+
+        let mtime = i64::from_le_bytes(chunk[i..i + 8].try_into().unwrap());
+
+        i += 8;
+
+        let ctime = i64::from_le_bytes(chunk[i..i + 8].try_into().unwrap());
+
+        i += 8;
+
+        let flags = u32::from_le_bytes(chunk[i..i + 4].try_into().unwrap());
+
+        i += 4;
+
+        let context = Context::from_bytes(&chunk[i..i + CONTEXT_SIZE]);
+
+        i += CONTEXT_SIZE;
+
+        let uid = u16::from_le_bytes(chunk[i..i + 2].try_into().unwrap());
+
+        i += 2;
+
+        let project = u16::from_le_bytes(chunk[i..i + 2].try_into().unwrap());
+
+        Inode {
+            name,
+            size,
+            block_pos,
+            mode,
+            links,
+            mtime,
+            ctime,
+            flags,
+            context,
+            uid,
+            project,
+        }
     }
 
     pub fn to_le_bytes(&self) -> Vec<u8> {
         let mut i = 0;
-        let mut raw_data = vec![0; 64];
-        let name_len = self.name.len() as u8;
-        if name_len > 32 {
-            todo!("Name too long");
+        let mut raw_data = vec![0; INODE_SIZE];
+        // 过长的名称在 check_new_name 中已经返回 NameTooLong，这里只保证不会写出界
+        let mut name_len = self.name.len().min(MAX_NAME_LEN);
+        while !self.name.is_char_boundary(name_len) {
+            name_len -= 1;
         }
-        raw_data[i] = name_len;
-        raw_data[i + 1..i + 1 + name_len as usize].copy_from_slice(self.name.as_bytes());
+        raw_data[i] = name_len as u8;
+        raw_data[i + 1..i + 1 + name_len].copy_from_slice(&self.name.as_bytes()[..name_len]);
         i += 32;
 
         let size_data = self.size.to_le_bytes();
@@ -69,11 +180,38 @@ impl Inode {
         let block_pos_data = self
             .block_pos
             .iter()
-            .map(|x| x.to_le_bytes())
-            .flatten()
+            .flat_map(|x| x.to_le_bytes())
             .collect::<Vec<u8>>();
         raw_data[i..i + block_pos_data.len()].copy_from_slice(&block_pos_data);
+        i += 28;
+
+        raw_data[i..i + 2].copy_from_slice(&self.mode.to_le_bytes());
+        i += 2;
+
+        raw_data[i..i + 2].copy_from_slice(&self.links.to_le_bytes());
+        i += 2;
+
+        raw_data[i..i + 8].copy_from_slice(&self.mtime.to_le_bytes());
+        i += 8;
+
+        raw_data[i..i + 8].copy_from_slice(&self.ctime.to_le_bytes());
+        i += 8;
+
+        raw_data[i..i + 4].copy_from_slice(&self.flags.to_le_bytes());
+        i += 4;
+
+        raw_data[i..i + CONTEXT_SIZE].copy_from_slice(&self.context.to_bytes());
+        i += CONTEXT_SIZE;
+
+        raw_data[i..i + 2].copy_from_slice(&self.uid.to_le_bytes());
+        i += 2;
+
+        raw_data[i..i + 2].copy_from_slice(&self.project.to_le_bytes());
 
         raw_data
     }
 }
+
+pub fn checksum(inode_index: usize, raw: &[u8]) -> u32 {
+    crc32c::owned(inode_index, &raw[..INODE_CHECKSUM_OFFSET])
+}
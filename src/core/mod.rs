@@ -1,5 +1,30 @@
+pub mod aes;
+pub mod alloc;
+pub mod cache;
+pub mod compress;
+pub mod crc32c;
+pub mod crypt;
+pub mod dcache;
+pub mod dedupe;
+pub mod defrag;
+pub mod dir;
+pub mod error;
+pub mod extent;
 pub mod file;
-pub mod hardware;
+pub mod frag;
 pub mod fs;
-pub mod dir;
-pub mod inode;
\ No newline at end of file
+pub mod group;
+pub mod hardware;
+pub mod icache;
+pub mod inode;
+pub mod quota;
+pub mod resize;
+pub mod scrub;
+pub mod sha256;
+pub mod snapshot;
+pub mod superblock;
+pub mod tar;
+pub mod transfer;
+pub mod trash;
+pub mod upgrade;
+pub mod usage;
@@ -0,0 +1,550 @@
+use std::collections::{BTreeMap, HashSet};
+
+use crate::core::error::{FsError, Result};
+use crate::core::fs::{join_path, System};
+use crate::core::snapshot::SNAPSHOT_CHECKSUM_OFFSET;
+use crate::core::superblock::FEATURE_RO_COMPAT_QUOTA;
+
+pub const DEFAULT_GRACE_PERIOD: i64 = 7 * 24 * 3600;
+
+// 配额表块：记录数（u32）、宽限期（i64，秒），随后是每条记录：类型（u8）、保留（u8）、
+// 编号（u16）、块和索引节点的软/硬限制（u32），以及两个宽限期的截止时间（i64）。
+// 用量不保存，挂载时扫描索引节点得到。
+const TABLE_HEADER: usize = 12;
+const RECORD_SIZE: usize = 36;
+pub const MAX_QUOTA_RECORDS: usize = (SNAPSHOT_CHECKSUM_OFFSET - TABLE_HEADER) / RECORD_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum QuotaKind {
+    User,
+    Project,
+}
+
+impl QuotaKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            QuotaKind::User => "用户",
+            QuotaKind::Project => "项目",
+        }
+    }
+}
+
+// 0 表示不限制
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub block_soft: u32,
+    pub block_hard: u32,
+    pub inode_soft: u32,
+    pub inode_hard: u32,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct QuotaEntry {
+    pub limits: Limits,
+    pub blocks: usize,
+    pub inodes: usize,
+    // 用量超过软限制时宽限期的截止时间，0 表示没有超过
+    pub block_grace: i64,
+    pub inode_grace: i64,
+}
+
+impl QuotaEntry {
+    // 超过硬限制，或超过软限制且宽限期已过时返回超出的资源
+    fn exceeded(&self, blocks: usize, inodes: usize, now: i64) -> Option<&'static str> {
+        let over = |used: usize, added: usize, soft: u32, hard: u32, grace: i64| {
+            added > 0
+                && ((hard != 0 && used + added > hard as usize)
+                    || (soft != 0 && used + added > soft as usize && grace != 0 && now >= grace))
+        };
+        let limits = &self.limits;
+        if over(
+            self.blocks,
+            blocks,
+            limits.block_soft,
+            limits.block_hard,
+            self.block_grace,
+        ) {
+            Some("块")
+        } else if over(
+            self.inodes,
+            inodes,
+            limits.inode_soft,
+            limits.inode_hard,
+            self.inode_grace,
+        ) {
+            Some("索引节点")
+        } else {
+            None
+        }
+    }
+
+    fn update_grace(&mut self, grace_period: i64, now: i64) {
+        let deadline = |used: usize, soft: u32, deadline: i64| {
+            if soft == 0 || used <= soft as usize {
+                0
+            } else if deadline == 0 {
+                now + grace_period
+            } else {
+                deadline
+            }
+        };
+        self.block_grace = deadline(self.blocks, self.limits.block_soft, self.block_grace);
+        self.inode_grace = deadline(self.inodes, self.limits.inode_soft, self.inode_grace);
+    }
+}
+
+#[derive(Debug)]
+pub struct Quotas {
+    pub grace_period: i64,
+    pub entries: BTreeMap<(QuotaKind, u16), QuotaEntry>,
+}
+
+impl Default for Quotas {
+    fn default() -> Self {
+        Self {
+            grace_period: DEFAULT_GRACE_PERIOD,
+            entries: BTreeMap::new(),
+        }
+    }
+}
+
+impl Quotas {
+    fn decode(data: &[u8]) -> Option<Self> {
+        let count = u32::from_le_bytes(data[0..4].try_into().unwrap()) as usize;
+        if count > MAX_QUOTA_RECORDS {
+            return None;
+        }
+        let grace_period = i64::from_le_bytes(data[4..12].try_into().unwrap());
+        let mut entries = BTreeMap::new();
+        for record in data[TABLE_HEADER..].chunks_exact(RECORD_SIZE).take(count) {
+            let word = |i: usize| u32::from_le_bytes(record[i..i + 4].try_into().unwrap());
+            let time = |i: usize| i64::from_le_bytes(record[i..i + 8].try_into().unwrap());
+            let kind = match record[0] {
+                0 => QuotaKind::User,
+                1 => QuotaKind::Project,
+                _ => return None,
+            };
+            let id = u16::from_le_bytes([record[2], record[3]]);
+            let entry = QuotaEntry {
+                limits: Limits {
+                    block_soft: word(4),
+                    block_hard: word(8),
+                    inode_soft: word(12),
+                    inode_hard: word(16),
+                },
+                block_grace: time(20),
+                inode_grace: time(28),
+                ..Default::default()
+            };
+            entries.insert((kind, id), entry);
+        }
+        Some(Self {
+            grace_period,
+            entries,
+        })
+    }
+
+    // 只保存设置了限制的记录
+    fn encode(&self) -> Vec<u8> {
+        let records: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|(_, x)| x.limits != Limits::default())
+            .collect();
+        let mut data = (records.len() as u32).to_le_bytes().to_vec();
+        data.extend_from_slice(&self.grace_period.to_le_bytes());
+        for ((kind, id), entry) in records {
+            data.push(*kind as u8);
+            data.push(0);
+            data.extend_from_slice(&id.to_le_bytes());
+            let limits = &entry.limits;
+            for x in [
+                limits.block_soft,
+                limits.block_hard,
+                limits.inode_soft,
+                limits.inode_hard,
+            ] {
+                data.extend_from_slice(&x.to_le_bytes());
+            }
+            data.extend_from_slice(&entry.block_grace.to_le_bytes());
+            data.extend_from_slice(&entry.inode_grace.to_le_bytes());
+        }
+        data
+    }
+
+    fn records(&self) -> usize {
+        self.entries
+            .values()
+            .filter(|x| x.limits != Limits::default())
+            .count()
+    }
+}
+
+impl System {
+    pub fn quotas_enabled(&self) -> bool {
+        self.super_block.feature_ro_compat & FEATURE_RO_COMPAT_QUOTA != 0
+    }
+
+    pub fn grace_period(&self) -> i64 {
+        self.quotas.grace_period
+    }
+
+    // 没有设置限制的编号也返回其用量
+    pub fn quota(&self, kind: QuotaKind, id: u16) -> Result<QuotaEntry> {
+        if !self.quotas_enabled() {
+            return Err(FsError::QuotaDisabled);
+        }
+        Ok(self
+            .quotas
+            .entries
+            .get(&(kind, id))
+            .copied()
+            .unwrap_or_default())
+    }
+
+    pub fn quota_report(&self) -> Result<Vec<(QuotaKind, u16, QuotaEntry)>> {
+        if !self.quotas_enabled() {
+            return Err(FsError::QuotaDisabled);
+        }
+        Ok(self
+            .quotas
+            .entries
+            .iter()
+            .filter(|(_, x)| x.limits != Limits::default() || x.inodes > 0)
+            .map(|((kind, id), x)| (*kind, *id, *x))
+            .collect())
+    }
+
+    // 第一次设置时启用配额并统计现有文件的用量
+    pub fn set_quota(&mut self, kind: QuotaKind, id: u16, limits: Limits) -> Result<()> {
+        self.check_writable()?;
+        self.check_root("配额")?;
+        let replaced = self
+            .quotas
+            .entries
+            .get(&(kind, id))
+            .is_some_and(|x| x.limits != Limits::default());
+        if limits != Limits::default() && !replaced && self.quotas.records() >= MAX_QUOTA_RECORDS {
+            return Err(FsError::TooManyQuotas(MAX_QUOTA_RECORDS));
+        }
+        self.enable_quotas()?;
+
+        let now = chrono::Utc::now().timestamp();
+        let grace_period = self.quotas.grace_period;
+        let entry = self.quotas.entries.entry((kind, id)).or_default();
+        entry.limits = limits;
+        entry.update_grace(grace_period, now);
+        Ok(())
+    }
+
+    // 只影响之后开始的宽限期
+    pub fn set_grace_period(&mut self, seconds: i64) -> Result<()> {
+        self.check_writable()?;
+        self.check_root("配额")?;
+        self.enable_quotas()?;
+        self.quotas.grace_period = seconds;
+        Ok(())
+    }
+
+    // 修改所有者和项目时用量随之转移，不检查限制
+    pub fn set_owner(&mut self, path: &str, uid: u16) -> Result<()> {
+        self.check_writable()?;
+        self.check_root(path)?;
+        let index = self.lookup(path)?;
+        let project = self.owner(index)?.1;
+        self.move_quota(index, (uid, project))
+    }
+
+    // 设置目录树中所有索引节点的项目，之后在其中新建的索引节点继承目录的项目
+    pub fn set_project(&mut self, path: &str, project: u16) -> Result<()> {
+        self.check_writable()?;
+        self.check_root(path)?;
+        let mut seen = HashSet::new();
+        self.set_project_tree(path, project, &mut seen)
+    }
+
+    fn set_project_tree(
+        &mut self,
+        path: &str,
+        project: u16,
+        seen: &mut HashSet<usize>,
+    ) -> Result<()> {
+        let stat = self.stat(path)?;
+        if !seen.insert(stat.inode_index) {
+            return Ok(());
+        }
+        let uid = self.owner(stat.inode_index)?.0;
+        self.move_quota(stat.inode_index, (uid, project))?;
+        if stat.typ != "dir" {
+            return Ok(());
+        }
+
+        let names: Vec<String> = self
+            .open_dir_path(path)?
+            .items
+            .iter()
+            .filter(|x| x.name != "." && x.name != "..")
+            .map(|x| x.name.clone())
+            .collect();
+        for name in names {
+            self.set_project_tree(&join_path(path, &name), project, seen)?;
+        }
+        Ok(())
+    }
+
+    fn move_quota(&mut self, inode_index: usize, owner: (u16, u16)) -> Result<()> {
+        let blocks = self.charged_blocks(inode_index)? as isize;
+        self.charge_quota(self.owner(inode_index)?, -blocks, -1);
+        {
+            let inode = self.inode_mut(inode_index)?;
+            let mut inode = inode.borrow_mut();
+            (inode.uid, inode.project) = owner;
+            inode.ctime = chrono::Utc::now().timestamp();
+        }
+        self.charge_quota(owner, blocks, 1);
+        Ok(())
+    }
+
+    fn enable_quotas(&mut self) -> Result<()> {
+        if self.quotas_enabled() {
+            return Ok(());
+        }
+        let table = self
+            .allocator
+            .allocate(1, None, None)
+            .ok_or(FsError::NoFreeBlocks)?[0];
+        self.super_block.quota_table = table;
+        self.super_block.feature_ro_compat |= FEATURE_RO_COMPAT_QUOTA;
+        self.count_quota_usage()
+    }
+
+    pub(crate) fn owner(&self, inode_index: usize) -> Result<(u16, u16)> {
+        let inode = self.inode(inode_index)?;
+        let inode = inode.borrow();
+        Ok((inode.uid, inode.project))
+    }
+
+    // owner 是 (用户, 项目)，超出任何一方的限制都会失败
+    pub(crate) fn check_quota(
+        &self,
+        owner: (u16, u16),
+        blocks: usize,
+        inodes: usize,
+    ) -> Result<()> {
+        if !self.quotas_enabled() {
+            return Ok(());
+        }
+        let now = chrono::Utc::now().timestamp();
+        for (kind, id) in [(QuotaKind::User, owner.0), (QuotaKind::Project, owner.1)] {
+            let Some(entry) = self.quotas.entries.get(&(kind, id)) else {
+                continue;
+            };
+            if let Some(what) = entry.exceeded(blocks, inodes, now) {
+                return Err(FsError::QuotaExceeded(format!(
+                    "{} {} 的{}",
+                    kind.name(),
+                    id,
+                    what
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn charge_quota(&mut self, owner: (u16, u16), blocks: isize, inodes: isize) {
+        if !self.quotas_enabled() || (blocks == 0 && inodes == 0) {
+            return;
+        }
+        let now = chrono::Utc::now().timestamp();
+        for key in [(QuotaKind::User, owner.0), (QuotaKind::Project, owner.1)] {
+            let entry = self.quotas.entries.entry(key).or_default();
+            entry.blocks = entry.blocks.saturating_add_signed(blocks);
+            entry.inodes = entry.inodes.saturating_add_signed(inodes);
+            entry.update_grace(self.quotas.grace_period, now);
+        }
+    }
+
+    // 重新统计所有索引节点的用量，用于挂载以及回滚快照、合并数据块之后。
+    // 全部统计完再更新宽限期，保存的截止时间不会因为中途用量偏低而被清除。
+    pub(crate) fn count_quota_usage(&mut self) -> Result<()> {
+        if !self.quotas_enabled() {
+            return Ok(());
+        }
+        for entry in self.quotas.entries.values_mut() {
+            entry.blocks = 0;
+            entry.inodes = 0;
+        }
+        for inode_index in 0..self.free_inodes.len() {
+            if !self.free_inodes[inode_index] {
+                continue;
+            }
+            let blocks = self.charged_blocks(inode_index)?;
+            let (uid, project) = self.owner(inode_index)?;
+            for key in [(QuotaKind::User, uid), (QuotaKind::Project, project)] {
+                let entry = self.quotas.entries.entry(key).or_default();
+                entry.blocks += blocks;
+                entry.inodes += 1;
+            }
+        }
+        let now = chrono::Utc::now().timestamp();
+        for entry in self.quotas.entries.values_mut() {
+            entry.update_grace(self.quotas.grace_period, now);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn load_quotas(&mut self) -> Result<()> {
+        self.quotas = Quotas::default();
+        if !self.quotas_enabled() {
+            return Ok(());
+        }
+        let table = self.super_block.quota_table as usize;
+        let data = self.read_sealed(table, "配额表")?;
+        self.quotas = Quotas::decode(&data).ok_or_else(|| FsError::Corrupted {
+            what: String::from("配额表"),
+            block: table,
+        })?;
+        self.count_quota_usage()
+    }
+
+    pub(crate) fn write_quota_table(&mut self) {
+        let data = self.quotas.encode();
+        self.write_sealed(self.super_block.quota_table as usize, data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::fs::FormatOptions;
+    use crate::core::hardware::{Hardware, BLOCK_SIZE};
+
+    fn usage(fs: &System, kind: QuotaKind, id: u16) -> (usize, usize) {
+        let entry = fs.quota(kind, id).unwrap();
+        (entry.blocks, entry.inodes)
+    }
+
+    #[test]
+    fn hard_limit_rejects() {
+        let mut fs = System::format(Hardware::new(), FormatOptions::default()).unwrap();
+        let limits = Limits {
+            block_hard: 3,
+            inode_hard: 2,
+            ..Default::default()
+        };
+        fs.set_quota(QuotaKind::User, 1, limits).unwrap();
+        fs.options.uid = 1;
+
+        fs.write_path("/a", &[1; BLOCK_SIZE * 2]).unwrap();
+        assert_eq!(usage(&fs, QuotaKind::User, 1), (2, 1));
+        assert!(matches!(
+            fs.write_path("/a", &[1; BLOCK_SIZE * 4]),
+            Err(FsError::QuotaExceeded(_))
+        ));
+        assert_eq!(usage(&fs, QuotaKind::User, 1), (2, 1));
+        fs.write_path("/b", b"").unwrap();
+        assert!(matches!(
+            fs.write_path("/c", b""),
+            Err(FsError::QuotaExceeded(_))
+        ));
+        assert_eq!(usage(&fs, QuotaKind::User, 1), (2, 2));
+    }
+
+    #[test]
+    fn soft_limit_grace() {
+        let mut entry = QuotaEntry {
+            limits: Limits {
+                block_soft: 2,
+                block_hard: 4,
+                ..Default::default()
+            },
+            blocks: 3,
+            ..Default::default()
+        };
+        // 超过软限制时开始宽限期，期间仍然可以写到硬限制
+        entry.update_grace(100, 1000);
+        assert_eq!(entry.block_grace, 1100);
+        assert_eq!(entry.exceeded(1, 0, 1050), None);
+        assert_eq!(entry.exceeded(2, 0, 1050), Some("块"));
+        // 宽限期过后超过软限制的写入被拒绝，不增加用量的操作不受影响
+        assert_eq!(entry.exceeded(1, 0, 1100), Some("块"));
+        assert_eq!(entry.exceeded(0, 1, 1100), None);
+
+        // 截止时间不会因为再次更新而推后，回到软限制以内时清除
+        entry.update_grace(100, 1050);
+        assert_eq!(entry.block_grace, 1100);
+        entry.blocks = 2;
+        entry.update_grace(100, 1200);
+        assert_eq!(entry.block_grace, 0);
+        assert_eq!(entry.exceeded(0, 0, 1200), None);
+    }
+
+    #[test]
+    fn usage_follows_owner_and_project() {
+        let mut fs = System::format(Hardware::new(), FormatOptions::default()).unwrap();
+        fs.set_quota(QuotaKind::Project, 9, Limits::default())
+            .unwrap();
+        fs.create_dir_all("/d").unwrap();
+        fs.write_path("/d/a", &[1; BLOCK_SIZE * 2]).unwrap();
+        fs.sync().unwrap();
+        let root = usage(&fs, QuotaKind::User, 0);
+
+        fs.set_owner("/d/a", 5).unwrap();
+        assert_eq!(usage(&fs, QuotaKind::User, 5), (2, 1));
+        assert_eq!(usage(&fs, QuotaKind::User, 0), (root.0 - 2, root.1 - 1));
+
+        // 目录和其中的文件一起转到新项目
+        let before = usage(&fs, QuotaKind::Project, 0);
+        fs.set_project("/d", 9).unwrap();
+        let moved = usage(&fs, QuotaKind::Project, 9);
+        assert_eq!(moved.1, 2);
+        assert_eq!(
+            usage(&fs, QuotaKind::Project, 0),
+            (before.0 - moved.0, before.1 - 2)
+        );
+
+        // 新建的文件继承目录的项目
+        fs.write_path("/d/b", b"x").unwrap();
+        assert_eq!(usage(&fs, QuotaKind::Project, 9).1, 3);
+    }
+
+    #[test]
+    fn table_roundtrip() {
+        let mut quotas = Quotas {
+            grace_period: 3600,
+            ..Default::default()
+        };
+        let limited = QuotaEntry {
+            limits: Limits {
+                block_soft: 1,
+                block_hard: 2,
+                inode_soft: 3,
+                inode_hard: 4,
+            },
+            block_grace: 5,
+            inode_grace: 6,
+            ..Default::default()
+        };
+        quotas.entries.insert((QuotaKind::User, 7), limited);
+        quotas.entries.insert((QuotaKind::Project, 8), limited);
+        // 没有限制的记录只有用量，不保存
+        let unlimited = QuotaEntry {
+            blocks: 10,
+            ..Default::default()
+        };
+        quotas.entries.insert((QuotaKind::User, 9), unlimited);
+
+        let decoded = Quotas::decode(&quotas.encode()).unwrap();
+        assert_eq!(decoded.grace_period, 3600);
+        assert_eq!(decoded.entries.len(), 2);
+        for key in [(QuotaKind::User, 7), (QuotaKind::Project, 8)] {
+            let entry = decoded.entries[&key];
+            assert_eq!(entry.limits, limited.limits);
+            assert_eq!((entry.block_grace, entry.inode_grace), (5, 6));
+        }
+
+        let mut data = quotas.encode();
+        data[0..4].copy_from_slice(&(MAX_QUOTA_RECORDS as u32 + 1).to_le_bytes());
+        assert!(Quotas::decode(&data).is_none());
+    }
+}
@@ -0,0 +1,356 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::core::error::{FsError, Result};
+use crate::core::extent::{self, LEAF_CHECKSUM_OFFSET};
+use crate::core::fs::System;
+use crate::core::group::{BITMAP_CHECKSUM_OFFSET, FLAT_BACKUP_OFFSET};
+use crate::core::inode::{Inode, MAX_BLOCKS_PER_INODE};
+use crate::core::superblock::FEATURE_INCOMPAT_GROUPS;
+
+impl System {
+    // 在线调整文件系统的块数，返回实际的块数（末尾放不下一个块组元数据的部分会被舍去）。
+    // 扩大时先加长设备再在末尾追加块组；缩小时先把要去掉的部分中仍在使用的数据块和索引节点
+    // 搬到前面，写回新的超级块及其副本之后才截断设备。未启用块组的镜像先转换成一个块组，
+    // 存在快照时拒绝。
+    pub fn resize(&mut self, blocks: usize) -> Result<usize> {
+        self.check_writable()?;
+        self.check_root("调整大小")?;
+        if !self.snapshots.is_empty() {
+            return Err(FsError::HasSnapshots("调整大小"));
+        }
+
+        let target = self.layout.resized(blocks)?;
+        let total = target.total_blocks;
+        let growing = total > self.layout.total_blocks;
+        self.flush_delayed()?;
+        if total < self.layout.total_blocks {
+            self.evacuate(total, target.inode_count())?;
+        }
+        if !self.layout.grouped {
+            self.convert_to_groups();
+        }
+
+        // 搬迁目录会改变各块组的目录数，重新生成布局
+        let layout = self.layout.resized(total)?;
+        if growing {
+            self.set_device_blocks(total)?;
+        }
+        self.inodes.truncate(layout.inode_count());
+        self.allocator.resize(total);
+        self.data_csums.resize(total, 0);
+        self.free_inodes.resize(layout.inode_count(), false);
+        self.layout = layout;
+        self.super_block.blocks_count = total as u32;
+        self.mark_metadata_used();
+        self.pin_metadata()?;
+        self.count_quota_usage()?;
+        if !growing {
+            // 中途崩溃时超级块不能指向设备之外
+            self.sync()?;
+            self.set_device_blocks(total)?;
+        }
+        Ok(total)
+    }
+
+    fn set_device_blocks(&mut self, total: usize) -> Result<()> {
+        self.cache
+            .set_device_blocks(total)
+            .map_err(|e| FsError::Host(self.cache.path(), e))
+    }
+
+    // 未启用块组的布局与一个 TOTAL_BLOCKS 块的块组相同，只需在超级块中记下块组参数；
+    // 原来放在位图块后半部分的超级块副本清掉，以免恢复时被当成有效的副本
+    fn convert_to_groups(&mut self) {
+        for (block_pos, offset) in self.layout.super_block_copies() {
+            self.write_into_block_at(
+                block_pos,
+                offset,
+                &[0; BITMAP_CHECKSUM_OFFSET - FLAT_BACKUP_OFFSET],
+            );
+        }
+        self.layout.grouped = true;
+        self.super_block.feature_incompat |= FEATURE_INCOMPAT_GROUPS;
+        self.super_block.blocks_per_group = self.layout.blocks_per_group as u32;
+        self.super_block.inodes_per_group = self.layout.inodes_per_group as u32;
+    }
+
+    // 把 total 块之后仍被引用的块、编号不小于 inode_count 的索引节点搬到前面
+    fn evacuate(&mut self, total: usize, inode_count: usize) -> Result<()> {
+        let moving: Vec<usize> = (inode_count..self.free_inodes.len())
+            .filter(|x| self.free_inodes[*x])
+            .collect();
+        let targets: Vec<usize> = (0..inode_count)
+            .filter(|x| !self.free_inodes[*x])
+            .take(moving.len())
+            .collect();
+        if targets.len() < moving.len() {
+            return Err(FsError::NoFreeInodes);
+        }
+
+        // 需要的空闲块：末尾被引用的数据块、受影响的文件在最坏情况下的区段叶子块，以及配额表
+        let mut tail_blocks = HashSet::new();
+        let mut leaves = 0;
+        for index in self.tail_inodes(total)? {
+            let blocks = self.inode_blocks(index)?;
+            tail_blocks.extend(blocks.iter().copied().filter(|x| *x as usize >= total));
+            if self.inode(index)?.borrow().uses_extents() {
+                leaves += extent::leaves_needed(blocks.len()).unwrap_or(0);
+            }
+        }
+        let quota_table = self.quotas_enabled() && self.super_block.quota_table as usize >= total;
+        let free = self.allocator.refcounts()[..total]
+            .iter()
+            .filter(|x| **x == 0)
+            .count();
+        if tail_blocks.len() + leaves + quota_table as usize > free {
+            return Err(FsError::NoFreeBlocks);
+        }
+
+        // 末尾的空闲块先标记为已用，搬迁时不会被分配出去；失败时再恢复
+        let mut placeholders: Vec<usize> = (total..self.layout.total_blocks)
+            .filter(|x| !self.allocator.is_used(*x))
+            .collect();
+        for block_pos in placeholders.iter() {
+            self.allocator.set_used(*block_pos, true);
+        }
+
+        let result = self.move_blocks(total, &mut placeholders).and_then(|_| {
+            if quota_table {
+                self.super_block.quota_table = self
+                    .allocator
+                    .allocate(1, None, None)
+                    .ok_or(FsError::NoFreeBlocks)?[0];
+            }
+            // 没有快照时快照表为空，下次创建快照时重新分配
+            if self.super_block.snapshot_table as usize >= total {
+                self.super_block.snapshot_table = 0;
+            }
+            let moves: Vec<(usize, usize)> = moving.into_iter().zip(targets).collect();
+            self.renumber_inodes(&moves)
+        });
+        if result.is_err() {
+            for block_pos in placeholders {
+                self.allocator.set_used(block_pos, false);
+            }
+        }
+        result
+    }
+
+    // 数据块或区段叶子块位于 total 之后的索引节点
+    fn tail_inodes(&self, total: usize) -> Result<Vec<usize>> {
+        let mut found = Vec::new();
+        for index in 0..self.free_inodes.len() {
+            if !self.free_inodes[index] {
+                continue;
+            }
+            let blocks = self.inode_blocks(index)?;
+            let mapping = self.mapping_blocks(index)?;
+            if blocks.iter().chain(&mapping).any(|x| *x as usize >= total) {
+                found.push(index);
+            }
+        }
+        Ok(found)
+    }
+
+    fn move_blocks(&mut self, total: usize, placeholders: &mut Vec<usize>) -> Result<()> {
+        // 被多个文件共享的块只复制一次
+        let mut moved: HashMap<u32, u32> = HashMap::new();
+        for index in self.tail_inodes(total)? {
+            let blocks = self.inode_blocks(index)?;
+            let mapping = self.mapping_blocks(index)?;
+
+            let mut positions: Vec<u32> = Vec::with_capacity(blocks.len());
+            for block_pos in blocks.iter().copied() {
+                let target = if (block_pos as usize) < total {
+                    block_pos
+                } else if let Some(target) = moved.get(&block_pos) {
+                    self.allocator.add_ref(*target as usize);
+                    *target
+                } else {
+                    let goal = positions.last().map(|x| *x as usize + 1);
+                    let target = self
+                        .allocator
+                        .allocate(1, goal, Some(index))
+                        .ok_or(FsError::NoFreeBlocks)?[0];
+                    let data = self.read_block(block_pos as usize);
+                    self.write_into_block(target as usize, &data);
+                    self.data_csums[target as usize] = self.data_csums[block_pos as usize];
+                    moved.insert(block_pos, target);
+                    target
+                };
+                positions.push(target);
+            }
+
+            let uses_extents = self.inode(index)?.borrow().uses_extents();
+            let block_map = if uses_extents {
+                for leaf in mapping.iter().copied().filter(|x| (*x as usize) < total) {
+                    self.release_block(leaf as usize);
+                }
+                self.build_extent_map(index, &positions)?
+            } else {
+                let mut block_map = positions;
+                block_map.resize(MAX_BLOCKS_PER_INODE, 0);
+                block_map
+            };
+            self.inode_mut(index)?.borrow_mut().block_pos = block_map;
+
+            let tail = blocks.into_iter().chain(mapping);
+            for block_pos in tail.map(|x| x as usize).filter(|x| *x >= total) {
+                // 末尾的块不再被引用时仍保持已用，随设备一起舍去
+                if self.allocator.drop_ref(block_pos) {
+                    self.allocator.set_used(block_pos, true);
+                    placeholders.push(block_pos);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // 目录块和区段叶子块的校验和与索引节点号有关，目录项中也记录着索引节点号
+    fn renumber_inodes(&mut self, moves: &[(usize, usize)]) -> Result<()> {
+        if moves.is_empty() {
+            return Ok(());
+        }
+        let renumber: HashMap<u32, u32> = moves
+            .iter()
+            .map(|(old, new)| (*old as u32, *new as u32))
+            .collect();
+
+        // 改号之后旧的校验和就对不上了，受影响的目录先读出来
+        let mut dirs = Vec::new();
+        for index in 0..self.free_inodes.len() {
+            if !self.free_inodes[index] || !self.inode(index)?.borrow().is_dir() {
+                continue;
+            }
+            let dir = self.read_dir(index)?;
+            if renumber.contains_key(&(index as u32))
+                || dir
+                    .items
+                    .iter()
+                    .any(|item| renumber.contains_key(&item.inode_pos))
+            {
+                dirs.push(dir);
+            }
+        }
+
+        for (old, new) in moves.iter().copied() {
+            let inode = Inode::from_bytes(&self.inode(old)?.borrow().to_le_bytes());
+            let is_dir = inode.is_dir();
+            *self.inode_mut(new)?.borrow_mut() = inode;
+            self.inode_mut(old)?.borrow_mut().clean();
+            self.set_free_inode_used(old, false);
+            self.set_free_inode_used(new, true);
+            self.allocator.release(old);
+            if is_dir {
+                self.count_dir(old, false);
+                self.count_dir(new, true);
+            }
+            if self.checksums() {
+                for leaf in self.mapping_blocks(new)? {
+                    let mut data = self.read_block(leaf as usize);
+                    let checksum = extent::leaf_checksum(new, &data);
+                    data[LEAF_CHECKSUM_OFFSET..LEAF_CHECKSUM_OFFSET + 4]
+                        .copy_from_slice(&checksum.to_le_bytes());
+                    self.write_into_block(leaf as usize, &data);
+                }
+            }
+        }
+
+        for mut dir in dirs {
+            if let Some(new) = renumber.get(&(dir.inode_index as u32)) {
+                dir.inode_index = *new as usize;
+            }
+            for item in dir.items.iter_mut() {
+                if let Some(new) = renumber.get(&item.inode_pos) {
+                    item.inode_pos = *new;
+                }
+            }
+            self.write_dir(&dir)?;
+        }
+        self.dentries.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::fs::FormatOptions;
+    use crate::core::hardware::{Hardware, BLOCK_SIZE};
+
+    #[test]
+    fn resize_keeps_data() {
+        let options = FormatOptions {
+            blocks: Some(96),
+            ..Default::default()
+        };
+        let mut fs = System::format(Hardware::new(), options).unwrap();
+        let data: Vec<u8> = (0..20000).map(|x| (x % 253) as u8).collect();
+        fs.write_path("/a", &data).unwrap();
+
+        assert_eq!(fs.resize(160).unwrap(), 160);
+        // 先占满前面的块组，让新文件落到扩出来的部分
+        let mut fillers = 0;
+        loop {
+            let path = format!("/f{}", fillers);
+            fs.write_path(&path, &[1; BLOCK_SIZE]).unwrap();
+            fs.sync().unwrap();
+            fillers += 1;
+            if fs.stat(&path).unwrap().blocks[0] >= 96 {
+                break;
+            }
+        }
+        fs.create_dir_all("/d").unwrap();
+        fs.write_path("/d/b", &data).unwrap();
+        fs.sync().unwrap();
+        let blocks = fs.stat("/d/b").unwrap().blocks;
+        assert!(blocks.iter().any(|x| *x >= 96));
+        for i in 0..fillers {
+            fs.remove_path(&format!("/f{}", i), false).unwrap();
+        }
+
+        // 缩小时搬回前面的块组
+        assert_eq!(fs.resize(96).unwrap(), 96);
+        assert_eq!(fs.statfs().total_blocks, 96);
+        assert_eq!(fs.read_path("/a").unwrap(), data);
+        assert_eq!(fs.read_path("/d/b").unwrap(), data);
+    }
+
+    #[test]
+    fn resize_flat_image() {
+        let image = std::env::temp_dir().join(format!("file-sys-resize-{}", std::process::id()));
+        let image = image.to_str().unwrap().to_string();
+        let mut fs =
+            System::format(Hardware::load(&image).unwrap(), FormatOptions::default()).unwrap();
+        assert!(!fs.layout.grouped);
+        let data: Vec<u8> = (0..BLOCK_SIZE * 5).map(|x| (x % 253) as u8).collect();
+        fs.write_path("/a", &data).unwrap();
+
+        assert_eq!(fs.resize(192).unwrap(), 192);
+        fs.write_path("/b", &data).unwrap();
+        drop(fs);
+        assert_eq!(
+            std::fs::metadata(&image).unwrap().len(),
+            192 * BLOCK_SIZE as u64
+        );
+
+        let mut fs = System::init(Hardware::load(&image).unwrap()).unwrap();
+        assert!(fs.layout.grouped);
+        assert_eq!(fs.statfs().total_blocks, 192);
+        assert_eq!(fs.read_path("/b").unwrap(), data);
+        fs.remove_path("/b", false).unwrap();
+        assert_eq!(fs.resize(64).unwrap(), 64);
+        // 截断之前新的超级块已经写回
+        assert_eq!(
+            std::fs::metadata(&image).unwrap().len(),
+            64 * BLOCK_SIZE as u64
+        );
+        std::mem::forget(fs);
+
+        let fs = System::init(Hardware::load(&image).unwrap()).unwrap();
+        assert_eq!(fs.statfs().total_blocks, 64);
+        assert_eq!(fs.read_path("/a").unwrap(), data);
+        drop(fs);
+        std::fs::remove_file(&image).unwrap();
+    }
+}
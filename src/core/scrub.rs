@@ -0,0 +1,326 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::core::error::{FsError, Result};
+use crate::core::fs::{join_path, parse_super_block, System};
+use crate::core::superblock::FEATURE_INCOMPAT_SHARED_BLOCKS;
+
+#[derive(Debug)]
+pub struct ScrubError {
+    pub path: String,
+    pub inode_index: Option<usize>,
+    pub block: usize,
+    pub what: String,
+}
+
+#[derive(Debug, Default)]
+pub struct ScrubReport {
+    pub inodes: usize,
+    pub blocks: usize,
+    pub errors: Vec<ScrubError>,
+}
+
+// 一次检查过程中的状态：每个块只检查和报告一次
+struct Scrubber {
+    report: ScrubReport,
+    // 被元数据、文件或快照引用的块
+    referenced: Vec<bool>,
+    // 已经报告过错误的块
+    reported: HashSet<usize>,
+    // 已经检查过的索引节点在索引节点表中的位置，快照共享的表块不重复检查
+    inodes: HashSet<(usize, usize)>,
+    // 有索引节点或区段叶子块损坏时无法知道它引用了哪些块
+    incomplete: bool,
+}
+
+impl Scrubber {
+    fn error(&mut self, path: &str, inode_index: Option<usize>, block: usize, what: String) {
+        if self.reported.insert(block) {
+            self.report.errors.push(ScrubError {
+                path: path.to_string(),
+                inode_index,
+                block,
+                what,
+            });
+        }
+    }
+
+    fn corrupted(&mut self, path: &str, inode_index: Option<usize>, e: FsError) -> Result<()> {
+        match e {
+            FsError::Corrupted { what, block } => {
+                self.error(path, inode_index, block, format!("{} 已损坏", what));
+                Ok(())
+            }
+            e => Err(e),
+        }
+    }
+
+    // 返回 false 表示块号超出范围，不能再读
+    fn reference(&mut self, path: &str, inode_index: Option<usize>, block_pos: usize) -> bool {
+        if block_pos >= self.referenced.len() {
+            self.error(path, inode_index, block_pos, String::from("块号超出范围"));
+            return false;
+        }
+        if !self.referenced[block_pos] {
+            self.referenced[block_pos] = true;
+            self.report.blocks += 1;
+        }
+        true
+    }
+}
+
+impl System {
+    // 检查所有已分配的块：超级块及其副本、位图、正在使用的索引节点、
+    // 它们的区段叶子块和数据块、快照和配额等记录块，包括快照独有的部分。
+    // 位图中已分配却没有任何引用的块、被引用却标记为空闲的块也算作错误。
+    // 浏览快照时会先回到当前文件系统，检查完再切换回去。
+    pub fn scrub(&mut self) -> Result<ScrubReport> {
+        let viewing = self.viewing().map(String::from);
+        self.browse_snapshot(None)?;
+
+        let mut scrubber = Scrubber {
+            report: ScrubReport::default(),
+            referenced: vec![false; self.layout.total_blocks],
+            reported: HashSet::new(),
+            inodes: HashSet::new(),
+            incomplete: false,
+        };
+        let result = self.scrub_all(&mut scrubber);
+        self.browse_snapshot(viewing.as_deref())?;
+        result?;
+        Ok(scrubber.report)
+    }
+
+    fn scrub_all(&mut self, scrubber: &mut Scrubber) -> Result<()> {
+        self.scrub_metadata(scrubber);
+        self.scrub_view("", &self.free_inodes.clone(), scrubber)?;
+
+        let names: Vec<String> = self.snapshots.iter().map(|x| x.name.clone()).collect();
+        for (index, name) in names.iter().enumerate() {
+            let prefix = format!("快照 {}:", name);
+            let snapshot = &self.snapshots[index];
+            let header = snapshot.header as usize;
+            let tables: Vec<usize> = snapshot.tables.iter().map(|x| *x as usize).collect();
+            let used_inodes = snapshot.used_inodes.clone();
+
+            scrubber.reference(&prefix, None, header);
+            for block_pos in tables {
+                scrubber.reference(&prefix, None, block_pos);
+            }
+            self.browse_snapshot(Some(name))?;
+            self.scrub_view(&prefix, &used_inodes, scrubber)?;
+        }
+        self.browse_snapshot(None)?;
+
+        for block_pos in 0..self.layout.total_blocks {
+            let used = self.allocator.is_used(block_pos);
+            if used && !scrubber.referenced[block_pos] && !scrubber.incomplete {
+                scrubber.error("-", None, block_pos, String::from("已分配但没有被引用"));
+            } else if !used && scrubber.referenced[block_pos] {
+                scrubber.error(
+                    "-",
+                    None,
+                    block_pos,
+                    String::from("被引用但在位图中是空闲的"),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn scrub_metadata(&self, scrubber: &mut Scrubber) {
+        for block_pos in self.layout.metadata_blocks() {
+            scrubber.reference("-", None, block_pos);
+        }
+
+        if parse_super_block(&self.read_block(0), true).is_none() {
+            scrubber.error("-", None, 0, String::from("主超级块已损坏"));
+        }
+        if self.super_block.has_backups() {
+            for (block_pos, offset) in self.layout.super_block_copies() {
+                if parse_super_block(&self.read_block(block_pos)[offset..], false).is_none() {
+                    scrubber.error("-", None, block_pos, String::from("超级块副本已损坏"));
+                }
+            }
+        }
+
+        for g in 0..self.layout.groups.len() {
+            let group = &self.layout.groups[g];
+            let bitmaps = [
+                (
+                    group.block_bitmap as usize,
+                    self.layout.blocks(g).len(),
+                    "块位图",
+                ),
+                (
+                    group.inode_bitmap as usize,
+                    self.layout.inodes_per_group,
+                    "索引节点位图",
+                ),
+            ];
+            for (block_pos, len, what) in bitmaps {
+                if let Err(e) = self.read_bitmap(block_pos, len, what) {
+                    let _ = scrubber.corrupted("-", None, e);
+                }
+            }
+        }
+
+        let shared = self.super_block.feature_incompat & FEATURE_INCOMPAT_SHARED_BLOCKS != 0;
+        let table = self.super_block.snapshot_table as usize;
+        if shared && table != 0 && scrubber.reference("-", None, table) {
+            if let Err(e) = self.read_sealed(table, "快照表") {
+                let _ = scrubber.corrupted("-", None, e);
+            }
+            for snapshot in self.snapshots.iter() {
+                let header = snapshot.header as usize;
+                if scrubber.reference("-", None, header) {
+                    if let Err(e) = self.read_sealed(header, "快照记录") {
+                        let _ = scrubber.corrupted("-", None, e);
+                    }
+                }
+            }
+        }
+
+        let table = self.super_block.quota_table as usize;
+        if self.quotas_enabled() && scrubber.reference("-", None, table) {
+            if let Err(e) = self.read_sealed(table, "配额表") {
+                let _ = scrubber.corrupted("-", None, e);
+            }
+        }
+    }
+
+    // 检查当前视图中所有正在使用的索引节点，路径只用于报告
+    fn scrub_view(&self, prefix: &str, used: &[bool], scrubber: &mut Scrubber) -> Result<()> {
+        let mut paths = HashMap::new();
+        self.collect_paths(self.root_inode_index, "/", &mut paths);
+
+        for (inode_index, used) in used.iter().enumerate() {
+            if !*used {
+                continue;
+            }
+            let location = self.inode_table_location(inode_index);
+            if !scrubber.inodes.insert(location) {
+                continue;
+            }
+            let path = match paths.get(&inode_index) {
+                Some(path) => format!("{}{}", prefix, path),
+                None => format!("{}索引节点 {}", prefix, inode_index),
+            };
+            self.scrub_inode(&path, inode_index, scrubber)?;
+        }
+        Ok(())
+    }
+
+    fn scrub_inode(&self, path: &str, inode_index: usize, scrubber: &mut Scrubber) -> Result<()> {
+        let index = Some(inode_index);
+        scrubber.report.inodes += 1;
+        let inode = match self.load_inode(inode_index) {
+            Ok(inode) => inode,
+            Err(e) => {
+                scrubber.incomplete = true;
+                return scrubber.corrupted(path, index, e);
+            }
+        };
+
+        // 叶子块的位置记录在索引节点里，叶子块损坏时也要算作被引用
+        for block_pos in self.mapping_blocks(inode_index)? {
+            scrubber.reference(path, index, block_pos as usize);
+        }
+        let blocks = match self.inode_blocks(inode_index) {
+            Ok(blocks) => blocks,
+            Err(e) => {
+                scrubber.incomplete = true;
+                return scrubber.corrupted(path, index, e);
+            }
+        };
+
+        let mut readable = true;
+        for block_pos in blocks {
+            let block_pos = block_pos as usize;
+            if !scrubber.reference(path, index, block_pos) {
+                readable = false;
+            } else if !self.data_block_ok(block_pos) {
+                scrubber.error(path, index, block_pos, String::from("数据块校验和不匹配"));
+            }
+        }
+        if readable && inode.is_dir() {
+            if let Err(e) = self.read_dir(inode_index) {
+                scrubber.corrupted(path, index, e)?;
+            }
+        }
+        Ok(())
+    }
+
+    // 尽量为索引节点找到一个路径，损坏的目录直接跳过
+    fn collect_paths(&self, inode_index: usize, path: &str, paths: &mut HashMap<usize, String>) {
+        if paths.contains_key(&inode_index) {
+            return;
+        }
+        paths.insert(inode_index, path.to_string());
+        let Ok(dir) = self.read_dir(inode_index) else {
+            return;
+        };
+        for item in dir.items.iter() {
+            if item.name == "." || item.name == ".." {
+                continue;
+            }
+            let child = join_path(path, &item.name);
+            if item.typ == "dir" {
+                self.collect_paths(item.inode_pos as usize, &child, paths);
+            } else {
+                paths.entry(item.inode_pos as usize).or_insert(child);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::fs::FormatOptions;
+    use crate::core::hardware::Hardware;
+    use crate::core::quota::{Limits, QuotaKind};
+
+    #[test]
+    fn scrub_every_allocated_block() {
+        let options = FormatOptions {
+            extents: true,
+            blocks: Some(128),
+            checksums: true,
+            data_checksums: true,
+            ..Default::default()
+        };
+        let mut fs = System::format(Hardware::new(), options).unwrap();
+        let data: Vec<u8> = (0..9000).map(|x| (x % 251) as u8).collect();
+        fs.create_dir_all("/d").unwrap();
+        fs.write_path("/d/a", &data).unwrap();
+        fs.clone_file("/d/a", "/b").unwrap();
+        fs.write_path("/c", &data).unwrap();
+        let limits = Limits {
+            block_hard: 100,
+            ..Default::default()
+        };
+        fs.set_quota(QuotaKind::User, 0, limits).unwrap();
+        fs.create_snapshot("s").unwrap();
+        let hidden = fs.stat("/c").unwrap().blocks[0] as usize;
+        fs.delete_path("/c", false).unwrap();
+        fs.sync().unwrap();
+
+        let report = fs.scrub().unwrap();
+        assert!(report.errors.is_empty(), "{:?}", report.errors);
+
+        // 只有快照还引用的数据块，从路径上已经找不到
+        fs.write_into_block_at(hidden, 0, b"garbage");
+        let report = fs.scrub().unwrap();
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.errors[0].block, hidden);
+        assert!(report.errors[0].path.starts_with("快照 s:"));
+
+        // 分配了却没有人引用的块
+        let leaked = (0..fs.layout.total_blocks)
+            .find(|x| !fs.allocator.is_used(*x))
+            .unwrap();
+        fs.allocator.set_used(leaked, true);
+        let report = fs.scrub().unwrap();
+        assert!(report.errors.iter().any(|x| x.block == leaked));
+    }
+}
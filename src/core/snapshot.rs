@@ -0,0 +1,458 @@
+use std::collections::HashMap;
+
+use crate::core::alloc::MAX_REFS;
+use crate::core::crc32c;
+use crate::core::error::{FsError, Result};
+use crate::core::fs::System;
+use crate::core::hardware::BLOCK_SIZE;
+use crate::core::inode::MAX_NAME_LEN;
+use crate::core::superblock::FEATURE_INCOMPAT_SHARED_BLOCKS;
+
+pub const MAX_SNAPSHOTS: usize = 32;
+// 启用元数据校验和时，快照表和快照记录块的最后4字节保存校验和
+pub const SNAPSHOT_CHECKSUM_OFFSET: usize = BLOCK_SIZE - 4;
+
+// 快照记录块：名称（1字节长度 + 31字节）、创建时间（i64）、根目录索引节点、
+// 索引节点表块数、索引节点数（u32），随后是索引节点表块的位置和索引节点位图（每个索引节点1位）
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub name: String,
+    pub ctime: i64,
+    pub root_inode_index: usize,
+    // 快照记录所在的块
+    pub header: u32,
+    // 与当前布局的索引节点表块一一对应，尚未写时复制的块仍与当前文件系统共享
+    pub tables: Vec<u32>,
+    pub used_inodes: Vec<bool>,
+}
+
+impl Snapshot {
+    pub fn from_bytes(header: u32, data: &[u8]) -> Option<Self> {
+        let word = |i: usize| u32::from_le_bytes(data[i..i + 4].try_into().unwrap());
+
+        let name_len = data[0] as usize;
+        if name_len > MAX_NAME_LEN {
+            return None;
+        }
+        let name = String::from_utf8(data[1..1 + name_len].to_vec()).ok()?;
+        let ctime = i64::from_le_bytes(data[32..40].try_into().unwrap());
+        let root_inode_index = word(40) as usize;
+        let table_count = word(44) as usize;
+        let inode_count = word(48) as usize;
+
+        let bitmap_start = 52 + table_count * 4;
+        if bitmap_start + inode_count.div_ceil(8) > SNAPSHOT_CHECKSUM_OFFSET {
+            return None;
+        }
+        let tables = (0..table_count).map(|i| word(52 + i * 4)).collect();
+        let used_inodes = (0..inode_count)
+            .map(|i| data[bitmap_start + i / 8] & (1 << (i % 8)) != 0)
+            .collect();
+
+        Some(Self {
+            name,
+            ctime,
+            root_inode_index,
+            header,
+            tables,
+            used_inodes,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = vec![0; 32];
+        data[0] = self.name.len() as u8;
+        data[1..1 + self.name.len()].copy_from_slice(self.name.as_bytes());
+        data.extend_from_slice(&self.ctime.to_le_bytes());
+        for x in [
+            self.root_inode_index as u32,
+            self.tables.len() as u32,
+            self.used_inodes.len() as u32,
+        ] {
+            data.extend_from_slice(&x.to_le_bytes());
+        }
+        for x in self.tables.iter() {
+            data.extend_from_slice(&x.to_le_bytes());
+        }
+
+        let mut bitmap = vec![0; self.used_inodes.len().div_ceil(8)];
+        for (i, used) in self.used_inodes.iter().enumerate() {
+            if *used {
+                bitmap[i / 8] |= 1 << (i % 8);
+            }
+        }
+        data.extend_from_slice(&bitmap);
+        assert!(data.len() <= SNAPSHOT_CHECKSUM_OFFSET);
+        data
+    }
+}
+
+// 浏览快照时，索引节点从快照的索引节点表中读取
+#[derive(Debug)]
+pub(crate) struct View {
+    pub name: String,
+    pub tables: HashMap<usize, usize>,
+    pub live_root: usize,
+}
+
+// 快照表块：快照数（u32），随后是各个快照记录块的位置
+fn decode_table(data: &[u8]) -> Vec<u32> {
+    let count = u32::from_le_bytes(data[0..4].try_into().unwrap()) as usize;
+    data[4..]
+        .chunks_exact(4)
+        .take(count.min(MAX_SNAPSHOTS))
+        .map(|x| u32::from_le_bytes(x.try_into().unwrap()))
+        .collect()
+}
+
+fn encode_table(headers: &[u32]) -> Vec<u8> {
+    let mut data = (headers.len() as u32).to_le_bytes().to_vec();
+    for x in headers {
+        data.extend_from_slice(&x.to_le_bytes());
+    }
+    data
+}
+
+fn checksum(block_pos: usize, data: &[u8]) -> u32 {
+    crc32c::owned(block_pos, &data[..SNAPSHOT_CHECKSUM_OFFSET])
+}
+
+impl System {
+    pub fn snapshots(&self) -> &[Snapshot] {
+        &self.snapshots
+    }
+
+    // 正在浏览的快照名称
+    pub fn viewing(&self) -> Option<&str> {
+        self.view.as_ref().map(|x| x.name.as_str())
+    }
+
+    // 冻结当前状态：所有文件的数据块和索引节点表块的引用计数加一，
+    // 之后对共享块的修改都会写到新的块上
+    pub fn create_snapshot(&mut self, name: &str) -> Result<()> {
+        self.check_writable()?;
+        if name.is_empty() || name.contains('/') {
+            return Err(FsError::InvalidName(name.to_string()));
+        }
+        if name.len() > MAX_NAME_LEN {
+            return Err(FsError::NameTooLong(name.to_string()));
+        }
+        if self.snapshots.iter().any(|x| x.name == name) {
+            return Err(FsError::AlreadyExists(format!("快照 {}", name)));
+        }
+        if self.snapshots.len() >= MAX_SNAPSHOTS {
+            return Err(FsError::TooManySnapshots(MAX_SNAPSHOTS));
+        }
+        self.sync()?;
+
+        let used_inodes = self.free_inodes.clone();
+        let tables: Vec<u32> = self
+            .layout
+            .inode_tables()
+            .into_iter()
+            .map(|x| x as u32)
+            .collect();
+        let mut shared = self.referenced_blocks(&used_inodes)?;
+        shared.extend_from_slice(&tables);
+        // 克隆的文件之间共享的块会出现多次
+        let mut counts: HashMap<u32, usize> = HashMap::new();
+        for block_pos in shared.iter() {
+            *counts.entry(*block_pos).or_default() += 1;
+        }
+        if let Some((block_pos, _)) = counts
+            .iter()
+            .find(|(x, n)| self.allocator.refcount(**x as usize) as usize + **n > MAX_REFS as usize)
+        {
+            return Err(FsError::TooManyRefs(*block_pos as usize));
+        }
+
+        let needed = if self.super_block.snapshot_table == 0 {
+            2
+        } else {
+            1
+        };
+        if self.allocator.available() < needed {
+            return Err(FsError::NoFreeBlocks);
+        }
+        let positions = self
+            .allocator
+            .allocate(needed, None, None)
+            .ok_or(FsError::NoFreeBlocks)?;
+        if needed == 2 {
+            self.super_block.snapshot_table = positions[1];
+        }
+        for block_pos in shared {
+            self.allocator.add_ref(block_pos as usize);
+        }
+
+        self.snapshots.push(Snapshot {
+            name: name.to_string(),
+            ctime: chrono::Utc::now().timestamp(),
+            root_inode_index: self.root_inode_index,
+            header: positions[0],
+            tables,
+            used_inodes,
+        });
+        self.write_snapshot(self.snapshots.len() - 1);
+        self.write_snapshot_table();
+        self.super_block.feature_incompat |= FEATURE_INCOMPAT_SHARED_BLOCKS;
+
+        Ok(())
+    }
+
+    pub fn delete_snapshot(&mut self, name: &str) -> Result<()> {
+        self.check_writable()?;
+        let index = self.find_snapshot(name)?;
+        self.sync()?;
+
+        let blocks = self.snapshot_blocks(index)?;
+        let snapshot = self.snapshots.remove(index);
+        for block_pos in blocks.into_iter().chain(snapshot.tables) {
+            self.release_block(block_pos as usize);
+        }
+        self.release_block(snapshot.header as usize);
+        self.write_snapshot_table();
+
+        Ok(())
+    }
+
+    // 把当前文件系统恢复到快照时的状态，快照本身保留
+    pub fn rollback_snapshot(&mut self, name: &str) -> Result<()> {
+        self.check_writable()?;
+        let index = self.find_snapshot(name)?;
+        self.sync()?;
+
+        let live = self.referenced_blocks(&self.free_inodes.clone())?;
+
+        let restore: Vec<(usize, usize)> = self
+            .layout
+            .inode_tables()
+            .into_iter()
+            .zip(self.snapshots[index].tables.iter().map(|x| *x as usize))
+            .filter(|(live, snapshot)| live != snapshot)
+            .collect();
+        // 被覆盖的索引节点表块可能还被其他快照共享
+        let overwritten: Vec<usize> = restore.iter().map(|(live, _)| *live).collect();
+        self.cow_inode_tables(&overwritten)?;
+        for (live, snapshot) in restore {
+            let data = self.read_block(snapshot);
+            self.write_into_block(live, &data);
+        }
+
+        let snapshot = &self.snapshots[index];
+        self.free_inodes = snapshot.used_inodes.clone();
+        self.root_inode_index = snapshot.root_inode_index;
+        self.super_block.root_inode_index = self.root_inode_index as u32;
+        self.inodes.clear();
+        self.dentries.clear();
+
+        // 先为快照中的块加引用，再释放原来的块，两边共享的块不会被清空
+        for block_pos in self.referenced_blocks(&self.free_inodes.clone())? {
+            self.allocator.add_ref(block_pos as usize);
+        }
+        for block_pos in live {
+            self.release_block(block_pos as usize);
+        }
+
+        for group in self.layout.groups.iter_mut() {
+            group.dirs = 0;
+        }
+        for inode_index in 0..self.free_inodes.len() {
+            if self.free_inodes[inode_index] && self.inode(inode_index)?.borrow().is_dir() {
+                let g = self.layout.group_of_inode(inode_index);
+                self.layout.groups[g].dirs += 1;
+            }
+        }
+
+        self.count_quota_usage()
+    }
+
+    // 切换到快照的只读视图，None 表示回到当前文件系统
+    pub fn browse_snapshot(&mut self, name: Option<&str>) -> Result<()> {
+        let index = match name {
+            Some(name) => Some(self.find_snapshot(name)?),
+            None => None,
+        };
+        if self.view.is_none() {
+            self.sync()?;
+        }
+        self.leave_view();
+
+        if let Some(index) = index {
+            self.enter_view(index);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn load_snapshots(&mut self) -> Result<()> {
+        self.snapshots.clear();
+        let table = self.super_block.snapshot_table as usize;
+        if self.super_block.feature_incompat & FEATURE_INCOMPAT_SHARED_BLOCKS == 0 || table == 0 {
+            return Ok(());
+        }
+
+        let data = self.read_sealed(table, "快照表")?;
+        for header in decode_table(&data) {
+            let data = self.read_sealed(header as usize, "快照记录")?;
+            let snapshot =
+                Snapshot::from_bytes(header, &data).ok_or_else(|| FsError::Corrupted {
+                    what: String::from("快照记录"),
+                    block: header as usize,
+                })?;
+            self.snapshots.push(snapshot);
+        }
+        Ok(())
+    }
+
+    // 即将写入的索引节点表块如果还被快照共享，先把原内容复制到新块并让这些快照指向新块
+    pub(crate) fn cow_inode_tables(&mut self, blocks: &[usize]) -> Result<()> {
+        for block_pos in blocks.iter().copied() {
+            let sharing: Vec<usize> = (0..self.snapshots.len())
+                .filter(|x| self.snapshots[*x].tables.contains(&(block_pos as u32)))
+                .collect();
+            if sharing.is_empty() {
+                continue;
+            }
+
+            let copy = self
+                .allocator
+                .allocate(1, Some(block_pos), None)
+                .ok_or(FsError::NoFreeBlocks)?[0];
+            let data = self.read_block(block_pos);
+            self.write_into_block(copy as usize, &data);
+
+            for (i, index) in sharing.into_iter().enumerate() {
+                if i > 0 {
+                    self.allocator.add_ref(copy as usize);
+                }
+                self.allocator.drop_ref(block_pos);
+                for table in self.snapshots[index].tables.iter_mut() {
+                    if *table == block_pos as u32 {
+                        *table = copy;
+                    }
+                }
+                self.write_snapshot(index);
+            }
+        }
+        Ok(())
+    }
+
+    fn find_snapshot(&self, name: &str) -> Result<usize> {
+        self.snapshots
+            .iter()
+            .position(|x| x.name == name)
+            .ok_or_else(|| FsError::NotFound(format!("快照 {}", name)))
+    }
+
+    // 快照中所有文件的数据块和区段叶子块，只在没有浏览快照时调用
+    fn snapshot_blocks(&mut self, index: usize) -> Result<Vec<u32>> {
+        let used_inodes = self.snapshots[index].used_inodes.clone();
+        self.enter_view(index);
+        let blocks = self.referenced_blocks(&used_inodes);
+        self.leave_view();
+        blocks
+    }
+
+    fn referenced_blocks(&self, used_inodes: &[bool]) -> Result<Vec<u32>> {
+        let mut blocks = Vec::new();
+        for (inode_index, used) in used_inodes.iter().enumerate() {
+            if *used {
+                blocks.extend(self.inode_blocks(inode_index)?);
+                blocks.extend(self.mapping_blocks(inode_index)?);
+            }
+        }
+        Ok(blocks)
+    }
+
+    fn enter_view(&mut self, index: usize) {
+        let snapshot = &self.snapshots[index];
+        let tables = self
+            .layout
+            .inode_tables()
+            .into_iter()
+            .zip(snapshot.tables.iter().map(|x| *x as usize))
+            .collect();
+        self.view = Some(View {
+            name: snapshot.name.clone(),
+            tables,
+            live_root: self.root_inode_index,
+        });
+        self.root_inode_index = snapshot.root_inode_index;
+        self.inodes.clear();
+        self.dentries.clear();
+    }
+
+    fn leave_view(&mut self) {
+        if let Some(view) = self.view.take() {
+            self.root_inode_index = view.live_root;
+            self.inodes.clear();
+            self.dentries.clear();
+        }
+    }
+
+    fn write_snapshot(&mut self, index: usize) {
+        let header = self.snapshots[index].header as usize;
+        let data = self.snapshots[index].to_bytes();
+        self.write_sealed(header, data);
+    }
+
+    fn write_snapshot_table(&mut self) {
+        let headers: Vec<u32> = self.snapshots.iter().map(|x| x.header).collect();
+        self.write_sealed(
+            self.super_block.snapshot_table as usize,
+            encode_table(&headers),
+        );
+    }
+
+    pub(crate) fn write_sealed(&mut self, block_pos: usize, mut data: Vec<u8>) {
+        data.resize(SNAPSHOT_CHECKSUM_OFFSET, 0);
+        let checksum = if self.checksums() {
+            checksum(block_pos, &data)
+        } else {
+            0
+        };
+        data.extend_from_slice(&checksum.to_le_bytes());
+        self.write_into_block(block_pos, &data);
+    }
+
+    pub(crate) fn read_sealed(&self, block_pos: usize, what: &str) -> Result<Vec<u8>> {
+        let corrupted = || FsError::Corrupted {
+            what: what.to_string(),
+            block: block_pos,
+        };
+        if block_pos >= self.layout.total_blocks {
+            return Err(corrupted());
+        }
+        let data = self.read_block(block_pos);
+        let stored = u32::from_le_bytes(data[SNAPSHOT_CHECKSUM_OFFSET..].try_into().unwrap());
+        if self.checksums() && checksum(block_pos, &data) != stored {
+            return Err(corrupted());
+        }
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::fs::FormatOptions;
+    use crate::core::hardware::Hardware;
+
+    #[test]
+    fn rollback_restores_state() {
+        let mut fs = System::format(Hardware::new(), FormatOptions::default()).unwrap();
+        fs.write_path("/a", b"before").unwrap();
+        fs.create_snapshot("s1").unwrap();
+
+        fs.write_path("/a", b"after").unwrap();
+        fs.write_path("/b", b"new").unwrap();
+        fs.rollback_snapshot("s1").unwrap();
+
+        assert_eq!(fs.read_path("/a").unwrap(), b"before");
+        assert!(matches!(fs.read_path("/b"), Err(FsError::NotFound(_))));
+        // 快照本身保留，可以再次回滚
+        fs.write_path("/a", b"again").unwrap();
+        fs.rollback_snapshot("s1").unwrap();
+        assert_eq!(fs.read_path("/a").unwrap(), b"before");
+    }
+}
@@ -0,0 +1,208 @@
+use crate::core::error::{FsError, Result};
+use crate::core::inode::INODES_PER_BLOCK;
+
+pub const INIT_MAGIC: u32 = 0xDEADBEEF_u32;
+pub const CHECKSUM_OFFSET: usize = 40;
+pub const FORMAT_VERSION: u32 = 2;
+
+pub const BLOCK_BITMAP_BLOCK: usize = 1;
+pub const INODE_BITMAP_BLOCK: usize = 2;
+pub const INODE_TABLE_BLOCK: usize = 3;
+pub const INODE_TABLE_BLOCKS: usize = 2;
+pub const INODE_COUNT: usize = INODE_TABLE_BLOCKS * INODES_PER_BLOCK;
+pub const FIRST_DATA_BLOCK: usize = INODE_TABLE_BLOCK + INODE_TABLE_BLOCKS;
+
+pub const FEATURE_COMPAT_SB_BACKUP: u32 = 0x1;
+pub const FEATURE_COMPAT_RESERVED_BLOCKS: u32 = 0x2;
+// 回收站只是根目录下的普通目录，旧版本把它当作普通目录即可
+pub const FEATURE_COMPAT_TRASH: u32 = 0x4;
+
+// 旧版本写入数据时不会更新数据块校验和，只能以只读方式挂载
+pub const FEATURE_RO_COMPAT_DATA_CSUM: u32 = 0x1;
+// 旧版本写入时不会检查和更新配额
+pub const FEATURE_RO_COMPAT_QUOTA: u32 = 0x2;
+
+pub const FEATURE_INCOMPAT_LINKS: u32 = 0x1;
+pub const FEATURE_INCOMPAT_EXTENTS: u32 = 0x2;
+pub const FEATURE_INCOMPAT_GROUPS: u32 = 0x4;
+// 目录块的格式也会改变，因此不能作为只读兼容特性
+pub const FEATURE_INCOMPAT_METADATA_CSUM: u32 = 0x8;
+// 快照和克隆文件共享数据块，块位图改为引用计数，旧版本会把计数大于1的块当作空闲块
+pub const FEATURE_INCOMPAT_SHARED_BLOCKS: u32 = 0x10;
+pub const FEATURE_INCOMPAT_COMPRESSION: u32 = 0x20;
+pub const FEATURE_INCOMPAT_ENCRYPTION: u32 = 0x40;
+// 碎片整理的日志尚未完成，旧版本不会重放日志，不能挂载
+pub const FEATURE_INCOMPAT_RECOVER: u32 = 0x80;
+
+pub const FEATURE_COMPAT_SUPP: u32 =
+    FEATURE_COMPAT_SB_BACKUP | FEATURE_COMPAT_RESERVED_BLOCKS | FEATURE_COMPAT_TRASH;
+pub const FEATURE_RO_COMPAT_SUPP: u32 = FEATURE_RO_COMPAT_DATA_CSUM | FEATURE_RO_COMPAT_QUOTA;
+pub const FEATURE_INCOMPAT_SUPP: u32 = FEATURE_INCOMPAT_LINKS
+    | FEATURE_INCOMPAT_EXTENTS
+    | FEATURE_INCOMPAT_GROUPS
+    | FEATURE_INCOMPAT_METADATA_CSUM
+    | FEATURE_INCOMPAT_SHARED_BLOCKS
+    | FEATURE_INCOMPAT_COMPRESSION
+    | FEATURE_INCOMPAT_ENCRYPTION
+    | FEATURE_INCOMPAT_RECOVER;
+
+#[derive(Debug, Default)]
+pub struct SuperBlock {
+    pub magic: u32,
+    pub root_inode_index: u32,
+    pub version: u32,
+    pub feature_compat: u32,
+    pub feature_ro_compat: u32,
+    pub feature_incompat: u32,
+    // 以下字段只在启用块组特性时有意义
+    pub blocks_count: u32,
+    pub blocks_per_group: u32,
+    pub inodes_per_group: u32,
+    // 每次写回超级块时加一，挂载时选择代数最大的有效副本
+    pub generation: u32,
+    // 启用超级块备份时有效，覆盖整个超级块区域（包括块组描述符）
+    pub checksum: u32,
+    // 以下字段依次排列，只写到最后一个已启用特性的字段为止，
+    // 各字段只在对应特性（块共享、配额、保留块、回收站、日志恢复）启用时有意义
+    pub snapshot_table: u32,
+    pub quota_table: u32,
+    // 只有 root 可以使用的块占总块数的百分比
+    pub reserved_percent: u32,
+    // 空闲块低于总块数的这个百分比时自动清理回收站
+    pub trash_threshold: u32,
+    // 需要重放的碎片整理日志所在的块
+    pub journal_block: u32,
+}
+
+impl SuperBlock {
+    pub fn new(root_inode_index: u32) -> Self {
+        Self {
+            magic: INIT_MAGIC,
+            root_inode_index,
+            version: FORMAT_VERSION,
+            feature_compat: FEATURE_COMPAT_SB_BACKUP,
+            ..Default::default()
+        }
+    }
+
+    pub fn from_block_bytes(data: &[u8]) -> Self {
+        let field = |i: usize| u32::from_le_bytes(data[i * 4..i * 4 + 4].try_into().unwrap());
+
+        Self {
+            magic: field(0),
+            root_inode_index: field(1),
+            version: field(2),
+            feature_compat: field(3),
+            feature_ro_compat: field(4),
+            feature_incompat: field(5),
+            blocks_count: field(6),
+            blocks_per_group: field(7),
+            inodes_per_group: field(8),
+            generation: field(9),
+            checksum: field(10),
+            snapshot_table: if field(5) & FEATURE_INCOMPAT_SHARED_BLOCKS != 0 {
+                field(11)
+            } else {
+                0
+            },
+            quota_table: if field(4) & FEATURE_RO_COMPAT_QUOTA != 0 {
+                field(12)
+            } else {
+                0
+            },
+            reserved_percent: if field(3) & FEATURE_COMPAT_RESERVED_BLOCKS != 0 {
+                field(13)
+            } else {
+                0
+            },
+            trash_threshold: if field(3) & FEATURE_COMPAT_TRASH != 0 {
+                field(14)
+            } else {
+                0
+            },
+            journal_block: if field(5) & FEATURE_INCOMPAT_RECOVER != 0 {
+                field(15)
+            } else {
+                0
+            },
+        }
+    }
+
+    pub fn to_le_bytes(&self) -> Vec<u8> {
+        let mut fields = vec![
+            self.magic,
+            self.root_inode_index,
+            self.version,
+            self.feature_compat,
+            self.feature_ro_compat,
+            self.feature_incompat,
+            self.blocks_count,
+            self.blocks_per_group,
+            self.inodes_per_group,
+            self.generation,
+            self.checksum,
+        ];
+        let optional = [
+            (
+                self.feature_incompat & FEATURE_INCOMPAT_SHARED_BLOCKS != 0,
+                self.snapshot_table,
+            ),
+            (
+                self.feature_ro_compat & FEATURE_RO_COMPAT_QUOTA != 0,
+                self.quota_table,
+            ),
+            (
+                self.feature_compat & FEATURE_COMPAT_RESERVED_BLOCKS != 0,
+                self.reserved_percent,
+            ),
+            (
+                self.feature_compat & FEATURE_COMPAT_TRASH != 0,
+                self.trash_threshold,
+            ),
+            (
+                self.feature_incompat & FEATURE_INCOMPAT_RECOVER != 0,
+                self.journal_block,
+            ),
+        ];
+        if let Some(last) = optional.iter().rposition(|(enabled, _)| *enabled) {
+            fields.extend(optional[..=last].iter().map(|(_, x)| *x));
+        }
+        fields.iter().flat_map(|x| x.to_le_bytes()).collect()
+    }
+
+    pub fn is_initialized(&self) -> bool {
+        self.magic == INIT_MAGIC
+    }
+
+    pub fn has_backups(&self) -> bool {
+        self.feature_compat & FEATURE_COMPAT_SB_BACKUP != 0
+    }
+
+    pub fn check_mountable(&self, read_only: bool) -> Result<()> {
+        if self.version < FORMAT_VERSION {
+            return Err(FsError::OutdatedVersion(self.version));
+        }
+        if self.version > FORMAT_VERSION {
+            return Err(FsError::UnsupportedVersion(self.version));
+        }
+
+        let ro_compat = if read_only {
+            0
+        } else {
+            self.feature_ro_compat & !FEATURE_RO_COMPAT_SUPP
+        };
+        let incompat = self.feature_incompat & !FEATURE_INCOMPAT_SUPP;
+        if ro_compat != 0 || incompat != 0 {
+            return Err(FsError::UnsupportedFeatures {
+                ro_compat,
+                incompat,
+            });
+        }
+        // 只读挂载不能重放日志，也不能挂载切换到一半的镜像
+        if read_only && self.feature_incompat & FEATURE_INCOMPAT_RECOVER != 0 {
+            return Err(FsError::NeedsRecovery);
+        }
+
+        Ok(())
+    }
+}
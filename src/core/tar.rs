@@ -0,0 +1,516 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+use crate::core::error::{FsError, Result};
+use crate::core::fs::{join_path, split_path, System};
+use crate::core::transfer::TransferReport;
+
+const BLOCK: usize = 512;
+
+const TYPE_FILE: u8 = b'0';
+const TYPE_OLD_FILE: u8 = b'\0';
+const TYPE_CONTIGUOUS: u8 = b'7';
+const TYPE_HARD_LINK: u8 = b'1';
+const TYPE_SYMLINK: u8 = b'2';
+const TYPE_DIR: u8 = b'5';
+const TYPE_PAX: u8 = b'x';
+const TYPE_PAX_GLOBAL: u8 = b'g';
+const TYPE_GNU_LONG_NAME: u8 = b'L';
+const TYPE_GNU_LONG_LINK: u8 = b'K';
+
+#[derive(Debug)]
+struct Header {
+    path: String,
+    mode: u16,
+    size: u64,
+    mtime: i64,
+    typeflag: u8,
+    link_name: String,
+}
+
+impl System {
+    pub fn tar_in(
+        &mut self,
+        mut reader: impl Read,
+        fs_path: &str,
+        progress: &mut dyn FnMut(&str),
+    ) -> Result<TransferReport> {
+        self.check_writable()?;
+        self.create_dir_all(fs_path)?;
+
+        let mut report = TransferReport::default();
+        let mut pax: HashMap<String, String> = HashMap::new();
+        let mut long_name = None;
+        let mut long_link = None;
+        let mut dir_times = Vec::new();
+
+        while let Some(mut header) = read_header(&mut reader)? {
+            match header.typeflag {
+                TYPE_PAX => {
+                    pax = parse_pax(&read_data(&mut reader, header.size)?);
+                    continue;
+                }
+                TYPE_PAX_GLOBAL => {
+                    read_data(&mut reader, header.size)?;
+                    continue;
+                }
+                TYPE_GNU_LONG_NAME => {
+                    long_name = Some(parse_str(&read_data(&mut reader, header.size)?));
+                    continue;
+                }
+                TYPE_GNU_LONG_LINK => {
+                    long_link = Some(parse_str(&read_data(&mut reader, header.size)?));
+                    continue;
+                }
+                _ => {}
+            }
+
+            if let Some(path) = long_name.take() {
+                header.path = path;
+            }
+            if let Some(link_name) = long_link.take() {
+                header.link_name = link_name;
+            }
+            if let Some(path) = pax.remove("path") {
+                header.path = path;
+            }
+            if let Some(link_name) = pax.remove("linkpath") {
+                header.link_name = link_name;
+            }
+            if let Some(size) = pax.remove("size").and_then(|x| x.parse().ok()) {
+                header.size = size;
+            }
+            if let Some(mtime) = pax
+                .remove("mtime")
+                .and_then(|x| x.split('.').next().and_then(|x| x.parse().ok()))
+            {
+                header.mtime = mtime;
+            }
+            pax.clear();
+
+            let data = read_data(&mut reader, header.size)?;
+
+            let Some(name) = sanitize(&header.path) else {
+                report.skip(&header.path, "非法的路径");
+                continue;
+            };
+            if name.is_empty() {
+                continue;
+            }
+            let path = join_path(fs_path, &name);
+            progress(&path);
+
+            if let Err(e) = self.extract_entry(&header, &path, &data, fs_path, &mut report) {
+                report.skip(&path, e);
+                continue;
+            }
+
+            if header.typeflag == TYPE_DIR {
+                dir_times.push((path, header.mtime));
+            } else if header.typeflag != TYPE_SYMLINK {
+                let _ = self.set_mode(&path, header.mode);
+                let _ = self.set_mtime(&path, header.mtime);
+            }
+        }
+
+        for (path, mtime) in dir_times.into_iter().rev() {
+            let _ = self.set_mtime(&path, mtime);
+        }
+
+        Ok(report)
+    }
+
+    pub fn tar_out(
+        &self,
+        fs_path: &str,
+        mut writer: impl Write,
+        progress: &mut dyn FnMut(&str),
+    ) -> Result<TransferReport> {
+        let mut report = TransferReport::default();
+        let mut seen = HashMap::new();
+
+        if self.stat(fs_path)?.typ == "dir" {
+            self.archive_dir(fs_path, "", &mut writer, &mut seen, &mut report, progress)?;
+        } else {
+            let (_, name) = split_path(fs_path)?;
+            self.archive_entry(fs_path, name, &mut writer, &mut seen, &mut report, progress)?;
+        }
+
+        write_all(&mut writer, &[0; BLOCK * 2])?;
+        writer
+            .flush()
+            .map_err(|e| FsError::Host(String::from("tar"), e))?;
+
+        Ok(report)
+    }
+
+    fn extract_entry(
+        &mut self,
+        header: &Header,
+        path: &str,
+        data: &[u8],
+        fs_path: &str,
+        report: &mut TransferReport,
+    ) -> Result<()> {
+        let (parent, _) = split_path(path)?;
+        self.create_dir_all(parent)?;
+
+        match header.typeflag {
+            TYPE_DIR => {
+                self.create_dir_all(path)?;
+                report.dirs += 1;
+            }
+            TYPE_FILE | TYPE_OLD_FILE | TYPE_CONTIGUOUS => {
+                self.write_path(path, data)?;
+                report.files += 1;
+                report.bytes += data.len() as u64;
+            }
+            TYPE_SYMLINK => {
+                self.remove_existing(path)?;
+                self.symlink(&header.link_name, path)?;
+                report.files += 1;
+            }
+            TYPE_HARD_LINK => {
+                let target = sanitize(&header.link_name)
+                    .ok_or_else(|| FsError::InvalidName(header.link_name.clone()))?;
+                self.remove_existing(path)?;
+                self.link(&join_path(fs_path, &target), path)?;
+                report.files += 1;
+            }
+            _ => return Err(FsError::InvalidArchive("不支持的条目类型")),
+        }
+
+        Ok(())
+    }
+
+    fn remove_existing(&mut self, path: &str) -> Result<()> {
+        match self.remove_path(path, false) {
+            Ok(()) | Err(FsError::NotFound(_)) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn archive_dir(
+        &self,
+        fs_dir: &str,
+        prefix: &str,
+        writer: &mut impl Write,
+        seen: &mut HashMap<usize, String>,
+        report: &mut TransferReport,
+        progress: &mut dyn FnMut(&str),
+    ) -> Result<()> {
+        let dir = self.open_dir_path(fs_dir)?;
+
+        for item in dir.items.iter() {
+            if item.name == "." || item.name == ".." {
+                continue;
+            }
+            let path = join_path(fs_dir, &item.name);
+            let name = format!("{}{}", prefix, item.name);
+            self.archive_entry(&path, &name, writer, seen, report, progress)?;
+        }
+
+        Ok(())
+    }
+
+    fn archive_entry(
+        &self,
+        path: &str,
+        name: &str,
+        writer: &mut impl Write,
+        seen: &mut HashMap<usize, String>,
+        report: &mut TransferReport,
+        progress: &mut dyn FnMut(&str),
+    ) -> Result<()> {
+        progress(path);
+        let stat = self.stat(path)?;
+
+        match stat.typ.as_str() {
+            "dir" => {
+                let name = format!("{}/", name);
+                write_header(writer, &name, stat.mode, 0, stat.mtime, TYPE_DIR, "")?;
+                report.dirs += 1;
+                self.archive_dir(path, &name, writer, seen, report, progress)?;
+            }
+            "symlink" => {
+                let target = self.read_link(path)?;
+                write_header(
+                    writer,
+                    name,
+                    stat.mode,
+                    0,
+                    stat.mtime,
+                    TYPE_SYMLINK,
+                    &target,
+                )?;
+                report.files += 1;
+            }
+            _ => {
+                if let Some(first) = seen.get(&stat.inode_index) {
+                    write_header(
+                        writer,
+                        name,
+                        stat.mode,
+                        0,
+                        stat.mtime,
+                        TYPE_HARD_LINK,
+                        first,
+                    )?;
+                } else {
+                    let data = self.read_path(path)?;
+                    let size = data.len() as u64;
+                    write_header(writer, name, stat.mode, size, stat.mtime, TYPE_FILE, "")?;
+                    write_all(writer, &data)?;
+                    write_all(writer, &vec![0; padding(size) as usize])?;
+                    report.bytes += size;
+                    if stat.links > 1 {
+                        seen.insert(stat.inode_index, name.to_string());
+                    }
+                }
+                report.files += 1;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn read_header(reader: &mut impl Read) -> Result<Option<Header>> {
+    let mut block = [0; BLOCK];
+    let mut filled = 0;
+    while filled < BLOCK {
+        match reader.read(&mut block[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err(FsError::InvalidArchive("归档被截断")),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(FsError::Host(String::from("tar"), e)),
+        }
+    }
+
+    if block.iter().all(|x| *x == 0) {
+        return Ok(None);
+    }
+
+    let stored = parse_octal(&block[148..156]).ok_or(FsError::InvalidArchive("头部校验和错误"))?;
+    if stored != checksum(&block) {
+        return Err(FsError::InvalidArchive("头部校验和错误"));
+    }
+
+    let mut path = parse_str(&block[0..100]);
+    if &block[257..262] == b"ustar" {
+        let prefix = parse_str(&block[345..500]);
+        if !prefix.is_empty() {
+            path = format!("{}/{}", prefix, path);
+        }
+    }
+
+    Ok(Some(Header {
+        path,
+        mode: parse_octal(&block[100..108]).unwrap_or(0o644) as u16,
+        size: parse_octal(&block[124..136]).ok_or(FsError::InvalidArchive("非法的文件大小"))?,
+        mtime: parse_octal(&block[136..148]).unwrap_or(0) as i64,
+        typeflag: block[156],
+        link_name: parse_str(&block[157..257]),
+    }))
+}
+
+fn read_data(reader: &mut impl Read, size: u64) -> Result<Vec<u8>> {
+    let mut data = vec![0; (size + padding(size)) as usize];
+    reader.read_exact(&mut data).map_err(|e| match e.kind() {
+        std::io::ErrorKind::UnexpectedEof => FsError::InvalidArchive("归档被截断"),
+        _ => FsError::Host(String::from("tar"), e),
+    })?;
+    data.truncate(size as usize);
+    Ok(data)
+}
+
+fn write_header(
+    writer: &mut impl Write,
+    name: &str,
+    mode: u16,
+    size: u64,
+    mtime: i64,
+    typeflag: u8,
+    link_name: &str,
+) -> Result<()> {
+    let mut records = String::new();
+    if name.len() > 100 {
+        records.push_str(&pax_record("path", name));
+    }
+    if link_name.len() > 100 {
+        records.push_str(&pax_record("linkpath", link_name));
+    }
+    if !records.is_empty() {
+        let pax_name = format!("PaxHeaders/{}", truncate(name, 80));
+        write_ustar(
+            writer,
+            &pax_name,
+            0o644,
+            records.len() as u64,
+            mtime,
+            TYPE_PAX,
+            "",
+        )?;
+        write_all(writer, records.as_bytes())?;
+        write_all(writer, &vec![0; padding(records.len() as u64) as usize])?;
+    }
+
+    write_ustar(
+        writer,
+        truncate(name, 100),
+        mode,
+        size,
+        mtime,
+        typeflag,
+        truncate(link_name, 100),
+    )
+}
+
+fn write_ustar(
+    writer: &mut impl Write,
+    name: &str,
+    mode: u16,
+    size: u64,
+    mtime: i64,
+    typeflag: u8,
+    link_name: &str,
+) -> Result<()> {
+    let mut block = [0; BLOCK];
+    block[..name.len()].copy_from_slice(name.as_bytes());
+    block[100..108].copy_from_slice(format!("{:07o}\0", mode & 0o7777).as_bytes());
+    block[108..116].copy_from_slice(b"0000000\0");
+    block[116..124].copy_from_slice(b"0000000\0");
+    block[124..136].copy_from_slice(format!("{:011o}\0", size).as_bytes());
+    block[136..148].copy_from_slice(format!("{:011o}\0", mtime.max(0)).as_bytes());
+    block[156] = typeflag;
+    block[157..157 + link_name.len()].copy_from_slice(link_name.as_bytes());
+    block[257..263].copy_from_slice(b"ustar\0");
+    block[263..265].copy_from_slice(b"00");
+
+    let sum = checksum(&block);
+    block[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+
+    write_all(writer, &block)
+}
+
+fn write_all(writer: &mut impl Write, data: &[u8]) -> Result<()> {
+    writer
+        .write_all(data)
+        .map_err(|e| FsError::Host(String::from("tar"), e))
+}
+
+fn checksum(block: &[u8; BLOCK]) -> u64 {
+    block
+        .iter()
+        .enumerate()
+        .map(|(i, x)| if (148..156).contains(&i) { b' ' } else { *x } as u64)
+        .sum()
+}
+
+fn padding(size: u64) -> u64 {
+    (BLOCK as u64 - size % BLOCK as u64) % BLOCK as u64
+}
+
+fn parse_str(field: &[u8]) -> String {
+    let end = field.iter().position(|x| *x == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).to_string()
+}
+
+fn parse_octal(field: &[u8]) -> Option<u64> {
+    if field[0] & 0x80 != 0 {
+        return Some(
+            field[1..]
+                .iter()
+                .fold(0u64, |acc, x| (acc << 8) | *x as u64),
+        );
+    }
+
+    let text = parse_str(field);
+    let text = text.trim_matches(|c: char| c == ' ');
+    if text.is_empty() {
+        return Some(0);
+    }
+    u64::from_str_radix(text, 8).ok()
+}
+
+fn parse_pax(data: &[u8]) -> HashMap<String, String> {
+    let mut records = HashMap::new();
+    let mut rest = data;
+
+    while let Some(space) = rest.iter().position(|x| *x == b' ') {
+        let Some(len) = std::str::from_utf8(&rest[..space])
+            .ok()
+            .and_then(|x| x.parse::<usize>().ok())
+        else {
+            break;
+        };
+        if len <= space + 1 || len > rest.len() {
+            break;
+        }
+
+        let record = String::from_utf8_lossy(&rest[space + 1..len - 1]);
+        if let Some((key, value)) = record.split_once('=') {
+            records.insert(key.to_string(), value.to_string());
+        }
+        rest = &rest[len..];
+    }
+
+    records
+}
+
+fn pax_record(key: &str, value: &str) -> String {
+    let body = format!(" {}={}\n", key, value);
+    let mut len = body.len() + 1;
+    while (len.to_string().len() + body.len()) != len {
+        len = len.to_string().len() + body.len();
+    }
+    format!("{}{}", len, body)
+}
+
+fn sanitize(path: &str) -> Option<String> {
+    let mut parts = Vec::new();
+    for name in path.split('/') {
+        match name {
+            "" | "." => {}
+            ".." => return None,
+            _ => parts.push(name),
+        }
+    }
+    Some(parts.join("/"))
+}
+
+fn truncate(s: &str, max: usize) -> &str {
+    if s.len() <= max {
+        return s;
+    }
+    let mut end = max;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::fs::FormatOptions;
+    use crate::core::hardware::Hardware;
+
+    #[test]
+    fn tar_in_twice() {
+        let data: Vec<u8> = (0..5000).map(|x| (x % 253) as u8 | 0x80).collect();
+        let mut src = System::format(Hardware::new(), FormatOptions::default()).unwrap();
+        src.create_dir_all("/src/sub").unwrap();
+        src.write_path("/src/sub/bin", &data).unwrap();
+        let mut archive = Vec::new();
+        src.tar_out("/src", &mut archive, &mut |_| {}).unwrap();
+
+        let mut fs = System::format(Hardware::new(), FormatOptions::default()).unwrap();
+        for _ in 0..2 {
+            let report = fs.tar_in(archive.as_slice(), "/dst", &mut |_| {}).unwrap();
+            assert_eq!(report.files, 1);
+            assert_eq!(fs.read_path("/dst/sub/bin").unwrap(), data);
+        }
+    }
+}
@@ -0,0 +1,272 @@
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::core::error::{FsError, Result};
+use crate::core::fs::{join_path, System};
+
+#[derive(Debug, Default)]
+pub struct TransferReport {
+    pub dirs: usize,
+    pub files: usize,
+    pub bytes: u64,
+    pub skipped: Vec<(String, String)>,
+}
+
+impl TransferReport {
+    pub(crate) fn skip(&mut self, path: &str, reason: impl ToString) {
+        self.skipped.push((path.to_string(), reason.to_string()));
+    }
+}
+
+impl System {
+    pub fn import_tree(
+        &mut self,
+        host_dir: &Path,
+        fs_path: &str,
+        progress: &mut dyn FnMut(&str),
+    ) -> Result<TransferReport> {
+        self.check_writable()?;
+        let entries = read_host_dir(host_dir)?;
+        self.create_dir_all(fs_path)?;
+
+        let mut report = TransferReport::default();
+        self.import_entries(entries, fs_path, &mut report, progress);
+        Ok(report)
+    }
+
+    pub fn export_tree(
+        &self,
+        fs_path: &str,
+        host_dir: &Path,
+        progress: &mut dyn FnMut(&str),
+    ) -> Result<TransferReport> {
+        self.open_dir_path(fs_path)?;
+        fs::create_dir_all(host_dir)
+            .map_err(|e| FsError::Host(host_dir.display().to_string(), e))?;
+
+        let mut report = TransferReport::default();
+        self.export_dir(fs_path, host_dir, &mut report, progress);
+        Ok(report)
+    }
+
+    fn import_entries(
+        &mut self,
+        entries: Vec<fs::DirEntry>,
+        fs_dir: &str,
+        report: &mut TransferReport,
+        progress: &mut dyn FnMut(&str),
+    ) {
+        for entry in entries {
+            let host_path = entry.path();
+            let Ok(name) = entry.file_name().into_string() else {
+                report.skip(&host_path.display().to_string(), "名称不是合法的 UTF-8");
+                continue;
+            };
+            let path = join_path(fs_dir, &name);
+            progress(&path);
+
+            let metadata = match fs::symlink_metadata(&host_path) {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    report.skip(&path, e);
+                    continue;
+                }
+            };
+
+            if metadata.is_dir() {
+                let entries = match read_host_dir(&host_path) {
+                    Ok(entries) => entries,
+                    Err(e) => {
+                        report.skip(&path, e);
+                        continue;
+                    }
+                };
+                if let Err(e) = self.create_dir_all(&path) {
+                    report.skip(&path, e);
+                    continue;
+                }
+                report.dirs += 1;
+                self.import_entries(entries, &path, report, progress);
+            } else if metadata.is_file() {
+                let data = match fs::read(&host_path) {
+                    Ok(data) => data,
+                    Err(e) => {
+                        report.skip(&path, e);
+                        continue;
+                    }
+                };
+                if let Err(e) = self.write_path(&path, &data) {
+                    report.skip(&path, e);
+                    continue;
+                }
+                report.files += 1;
+                report.bytes += data.len() as u64;
+            } else if metadata.is_symlink() {
+                let target = match fs::read_link(&host_path) {
+                    Ok(target) => target,
+                    Err(e) => {
+                        report.skip(&path, e);
+                        continue;
+                    }
+                };
+                let Some(target) = target.to_str() else {
+                    report.skip(&path, "链接目标不是合法的 UTF-8");
+                    continue;
+                };
+                if let Err(e) = self.symlink(target, &path) {
+                    report.skip(&path, e);
+                    continue;
+                }
+                report.files += 1;
+                continue;
+            } else {
+                report.skip(&path, "不支持的文件类型");
+                continue;
+            }
+
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                let _ = self.set_mode(&path, metadata.permissions().mode() as u16);
+            }
+            if let Ok(mtime) = metadata.modified() {
+                let _ = self.set_mtime(&path, to_unix_time(mtime));
+            }
+        }
+    }
+
+    fn export_dir(
+        &self,
+        fs_dir: &str,
+        host_dir: &Path,
+        report: &mut TransferReport,
+        progress: &mut dyn FnMut(&str),
+    ) {
+        let dir = match self.open_dir_path(fs_dir) {
+            Ok(dir) => dir,
+            Err(e) => {
+                report.skip(fs_dir, e);
+                return;
+            }
+        };
+
+        for item in dir.items.iter() {
+            if item.name == "." || item.name == ".." {
+                continue;
+            }
+
+            let path = join_path(fs_dir, &item.name);
+            let host_path = host_dir.join(&item.name);
+            progress(&path);
+
+            let stat = match self.stat(&path) {
+                Ok(stat) => stat,
+                Err(e) => {
+                    report.skip(&path, e);
+                    continue;
+                }
+            };
+
+            if item.typ == "dir" {
+                if let Err(e) = fs::create_dir_all(&host_path) {
+                    report.skip(&path, e);
+                    continue;
+                }
+                report.dirs += 1;
+                self.export_dir(&path, &host_path, report, progress);
+            } else if item.typ == "symlink" {
+                let result = self
+                    .read_link(&path)
+                    .map_err(|e| e.to_string())
+                    .and_then(|target| {
+                        #[cfg(unix)]
+                        return std::os::unix::fs::symlink(target, &host_path)
+                            .map_err(|e| e.to_string());
+                        #[cfg(not(unix))]
+                        return Err(String::from("当前平台不支持符号链接"));
+                    });
+                match result {
+                    Ok(()) => report.files += 1,
+                    Err(e) => report.skip(&path, e),
+                }
+                continue;
+            } else {
+                let data = match self.read_path(&path) {
+                    Ok(data) => data,
+                    Err(e) => {
+                        report.skip(&path, e);
+                        continue;
+                    }
+                };
+                if let Err(e) = fs::write(&host_path, &data) {
+                    report.skip(&path, e);
+                    continue;
+                }
+                report.files += 1;
+                report.bytes += data.len() as u64;
+            }
+
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                let permissions = fs::Permissions::from_mode(stat.mode as u32 & 0o7777);
+                let _ = fs::set_permissions(&host_path, permissions);
+            }
+            if let Ok(file) = fs::File::open(&host_path) {
+                let _ = file.set_modified(from_unix_time(stat.mtime));
+            }
+        }
+    }
+}
+
+fn read_host_dir(host_dir: &Path) -> Result<Vec<fs::DirEntry>> {
+    let mut entries = fs::read_dir(host_dir)
+        .and_then(|entries| entries.collect::<std::io::Result<Vec<_>>>())
+        .map_err(|e| FsError::Host(host_dir.display().to_string(), e))?;
+    entries.sort_by_key(|entry| entry.file_name());
+    Ok(entries)
+}
+
+fn to_unix_time(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64),
+    }
+}
+
+fn from_unix_time(secs: i64) -> SystemTime {
+    if secs >= 0 {
+        UNIX_EPOCH + Duration::from_secs(secs as u64)
+    } else {
+        UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::fs::FormatOptions;
+    use crate::core::hardware::Hardware;
+
+    #[cfg(unix)]
+    #[test]
+    fn import_twice() {
+        let host = std::env::temp_dir().join(format!("file-sys-import-{}", std::process::id()));
+        fs::create_dir_all(&host).unwrap();
+        let data: Vec<u8> = (0..3000).map(|x| (x % 251) as u8 | 0x80).collect();
+        fs::write(host.join("bin"), &data).unwrap();
+        std::os::unix::fs::symlink("bin", host.join("link")).unwrap();
+
+        let mut fs = System::format(Hardware::new(), FormatOptions::default()).unwrap();
+        let first = fs.import_tree(&host, "/in", &mut |_| {}).unwrap();
+        assert_eq!((first.files, first.skipped.len()), (2, 0));
+
+        // 符号链接已存在，只计为跳过；二进制文件被覆盖
+        let second = fs.import_tree(&host, "/in", &mut |_| {}).unwrap();
+        assert_eq!((second.files, second.skipped.len()), (1, 1));
+        assert_eq!(fs.read_path("/in/bin").unwrap(), data);
+
+        fs::remove_dir_all(&host).unwrap();
+    }
+}
@@ -0,0 +1,331 @@
+use crate::core::error::{FsError, Result};
+use crate::core::fs::{join_path, split_path, System};
+use crate::core::superblock::FEATURE_COMPAT_TRASH;
+
+pub const TRASH_DIR: &str = "/.trash";
+pub const DEFAULT_TRASH_THRESHOLD: u32 = 10;
+pub const MAX_TRASH_THRESHOLD: u32 = 50;
+
+// 索引文件每行一个条目：编号、删除时间和原路径，以制表符分隔；
+// 条目本身以编号为名保存在回收站目录中
+const TRASH_INDEX: &str = "/.trash/.index";
+
+#[derive(Debug, Clone)]
+pub struct TrashEntry {
+    pub id: u32,
+    pub deleted: i64,
+    pub path: String,
+}
+
+impl TrashEntry {
+    fn location(&self) -> String {
+        join_path(TRASH_DIR, &self.id.to_string())
+    }
+}
+
+impl System {
+    pub fn trash_enabled(&self) -> bool {
+        self.super_block.feature_compat & FEATURE_COMPAT_TRASH != 0
+    }
+
+    pub fn trash_threshold(&self) -> u32 {
+        self.super_block.trash_threshold
+    }
+
+    // 已启用时修改清理阈值，并补建缺失的回收站目录和索引
+    pub fn enable_trash(&mut self, threshold: u32) -> Result<()> {
+        self.check_writable()?;
+        self.check_root("回收站")?;
+        if threshold > MAX_TRASH_THRESHOLD {
+            return Err(FsError::InvalidThreshold(threshold));
+        }
+        match self.stat(TRASH_DIR) {
+            Ok(stat) if stat.typ != "dir" => {
+                return Err(FsError::AlreadyExists(TRASH_DIR.to_string()))
+            }
+            Ok(_) => {}
+            Err(FsError::NotFound(_)) => {
+                self.create_dir_all(TRASH_DIR)?;
+            }
+            Err(e) => return Err(e),
+        }
+        if let Err(FsError::NotFound(_)) = self.lookup(TRASH_INDEX) {
+            self.write_path(TRASH_INDEX, b"")?;
+        }
+        self.super_block.feature_compat |= FEATURE_COMPAT_TRASH;
+        self.super_block.trash_threshold = threshold;
+        Ok(())
+    }
+
+    // 关闭时清空并删除回收站目录
+    pub fn disable_trash(&mut self) -> Result<()> {
+        self.check_writable()?;
+        self.check_root("回收站")?;
+        if !self.trash_enabled() {
+            return Err(FsError::TrashDisabled);
+        }
+        self.empty_trash()?;
+        self.remove_path(TRASH_DIR, true)?;
+        self.super_block.feature_compat &= !FEATURE_COMPAT_TRASH;
+        self.super_block.trash_threshold = 0;
+        Ok(())
+    }
+
+    pub fn trash_entries(&self) -> Result<Vec<TrashEntry>> {
+        if !self.trash_enabled() {
+            return Err(FsError::TrashDisabled);
+        }
+        let data = self.read_path(TRASH_INDEX)?;
+        let entries = String::from_utf8_lossy(&data)
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.splitn(3, '\t');
+                Some(TrashEntry {
+                    id: fields.next()?.parse().ok()?,
+                    deleted: fields.next()?.parse().ok()?,
+                    path: fields.next()?.to_string(),
+                })
+            })
+            .collect();
+        Ok(entries)
+    }
+
+    fn write_trash_index(&mut self, entries: &[TrashEntry]) -> Result<()> {
+        let data: String = entries
+            .iter()
+            .map(|x| format!("{}\t{}\t{}\n", x.id, x.deleted, x.path))
+            .collect();
+        self.write_path(TRASH_INDEX, data.as_bytes())
+    }
+
+    // 启用时回收站目录和索引不能删除或移走，否则回收站无法再使用
+    pub(crate) fn check_trash_storage(&self, path: &str) -> Result<()> {
+        if self.trash_enabled() && (path == TRASH_DIR || path == TRASH_INDEX) {
+            return Err(FsError::TrashInUse(path.to_string()));
+        }
+        Ok(())
+    }
+
+    // 启用回收站时把条目移到回收站，否则与 remove_path 相同。
+    // 回收站中的条目直接删除并从索引中去掉，回收站目录和索引本身拒绝删除；
+    // 加密目录中的条目也直接删除，否则索引会以明文记下它的名称
+    pub fn delete_path(&mut self, path: &str, recursive: bool) -> Result<()> {
+        self.check_trash_storage(path)?;
+        if !self.trash_enabled() {
+            return self.remove_path(path, recursive);
+        }
+        if let Some(id) = trash_entry_id(path) {
+            self.remove_path(path, recursive)?;
+            let mut entries = self.trash_entries()?;
+            entries.retain(|x| x.id != id);
+            return self.write_trash_index(&entries);
+        }
+        if in_trash(path) || self.in_encrypted_dir(path)? {
+            return self.remove_path(path, recursive);
+        }
+        self.check_writable()?;
+        if self.stat(path)?.typ == "dir" && !recursive {
+            return Err(FsError::IsADirectory(path.to_string()));
+        }
+
+        let mut entries = self.trash_entries()?;
+        let entry = TrashEntry {
+            id: entries.iter().map(|x| x.id).max().map_or(1, |x| x + 1),
+            deleted: chrono::Utc::now().timestamp(),
+            path: path.to_string(),
+        };
+        self.rename(path, &entry.location())?;
+        entries.push(entry);
+        self.write_trash_index(&entries)?;
+        self.purge_trash()?;
+        Ok(())
+    }
+
+    fn in_encrypted_dir(&self, path: &str) -> Result<bool> {
+        let (parent, _) = split_path(path)?;
+        let parent = if parent.is_empty() { "/" } else { parent };
+        Ok(self.stat(parent)?.encrypted)
+    }
+
+    // 恢复到原路径或指定的路径，返回恢复到的路径；目标已存在时拒绝
+    pub fn restore_trash(&mut self, id: u32, to: Option<&str>) -> Result<String> {
+        self.check_writable()?;
+        let mut entries = self.trash_entries()?;
+        let pos = entries
+            .iter()
+            .position(|x| x.id == id)
+            .ok_or(FsError::NoTrashEntry(id))?;
+        let target = to.unwrap_or(&entries[pos].path).to_string();
+        match self.lookup(&target) {
+            Ok(_) => return Err(FsError::AlreadyExists(target)),
+            Err(FsError::NotFound(_)) => {}
+            Err(e) => return Err(e),
+        }
+
+        self.rename(&entries[pos].location(), &target)?;
+        entries.remove(pos);
+        self.write_trash_index(&entries)?;
+        Ok(target)
+    }
+
+    pub fn empty_trash(&mut self) -> Result<usize> {
+        self.check_writable()?;
+        let entries = self.trash_entries()?;
+        for entry in entries.iter() {
+            self.remove_trash_entry(entry)?;
+        }
+        self.write_trash_index(&[])?;
+        Ok(entries.len())
+    }
+
+    // 空闲块低于阈值时从最早删除的条目开始清理，返回清理的条目数
+    pub fn purge_trash(&mut self) -> Result<usize> {
+        if !self.trash_enabled() || self.is_read_only() {
+            return Ok(0);
+        }
+        let low = self.layout.total_blocks * self.super_block.trash_threshold as usize / 100;
+        if self.allocator.available() >= low {
+            return Ok(0);
+        }
+
+        let mut entries = self.trash_entries()?;
+        entries.sort_by_key(|x| (x.deleted, x.id));
+        let mut purged = 0;
+        while purged < entries.len() && self.allocator.available() < low {
+            self.remove_trash_entry(&entries[purged])?;
+            purged += 1;
+        }
+        if purged > 0 {
+            self.write_trash_index(&entries[purged..])?;
+        }
+        Ok(purged)
+    }
+
+    // 空闲块不足导致操作失败时，从最早删除的条目开始逐个清理回收站并重试，
+    // 直到成功或回收站已空；操作回收站内部的路径时不清理，以免删掉正在使用的条目
+    pub(crate) fn retry_after_purge<T>(
+        &mut self,
+        path: &str,
+        mut op: impl FnMut(&mut Self) -> Result<T>,
+    ) -> Result<T> {
+        loop {
+            match op(self) {
+                Err(FsError::NoFreeBlocks) if !in_trash(path) && self.purge_oldest()? => {}
+                result => return result,
+            }
+        }
+    }
+
+    fn purge_oldest(&mut self) -> Result<bool> {
+        if !self.trash_enabled() || self.is_read_only() {
+            return Ok(false);
+        }
+        let mut entries = self.trash_entries()?;
+        let Some(pos) = (0..entries.len()).min_by_key(|i| (entries[*i].deleted, entries[*i].id))
+        else {
+            return Ok(false);
+        };
+        let entry = entries.remove(pos);
+        self.remove_trash_entry(&entry)?;
+        self.write_trash_index(&entries)?;
+        Ok(true)
+    }
+
+    // 索引中有记录、但条目已被手动删除时忽略
+    fn remove_trash_entry(&mut self, entry: &TrashEntry) -> Result<()> {
+        match self.remove_path(&entry.location(), true) {
+            Err(FsError::NotFound(_)) => Ok(()),
+            result => result,
+        }
+    }
+}
+
+fn in_trash(path: &str) -> bool {
+    path == TRASH_DIR || path.starts_with("/.trash/")
+}
+
+// 回收站目录下直接以编号命名的条目
+fn trash_entry_id(path: &str) -> Option<u32> {
+    path.strip_prefix("/.trash/")?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::fs::FormatOptions;
+    use crate::core::hardware::{Hardware, BLOCK_SIZE};
+
+    #[test]
+    fn protect_trash_storage() {
+        let mut fs = System::format(Hardware::new(), FormatOptions::default()).unwrap();
+        fs.enable_trash(DEFAULT_TRASH_THRESHOLD).unwrap();
+        fs.write_path("/a", b"a").unwrap();
+        fs.delete_path("/a", false).unwrap();
+
+        for path in [TRASH_DIR, TRASH_INDEX] {
+            assert!(matches!(
+                fs.delete_path(path, true),
+                Err(FsError::TrashInUse(_))
+            ));
+            assert!(matches!(
+                fs.rename(path, "/moved"),
+                Err(FsError::TrashInUse(_))
+            ));
+        }
+        // 回收站中的条目仍然可以直接删除，索引中的记录一并去掉
+        fs.delete_path("/.trash/1", false).unwrap();
+        assert!(fs.trash_entries().unwrap().is_empty());
+
+        // 再次启用时补建缺失的索引
+        fs.remove_path(TRASH_INDEX, false).unwrap();
+        fs.enable_trash(DEFAULT_TRASH_THRESHOLD).unwrap();
+        assert!(fs.trash_entries().unwrap().is_empty());
+    }
+
+    #[test]
+    fn encrypted_names_stay_out_of_index() {
+        let mut fs = System::format(Hardware::new(), FormatOptions::default()).unwrap();
+        fs.enable_trash(DEFAULT_TRASH_THRESHOLD).unwrap();
+        fs.set_passphrase("secret");
+        fs.create_dir_all("/sec").unwrap();
+        fs.encrypt_dir("/sec").unwrap();
+        fs.write_path("/sec/payroll", b"x").unwrap();
+        fs.delete_path("/sec/payroll", false).unwrap();
+
+        let index = fs.read_path(TRASH_INDEX).unwrap();
+        assert!(!index.windows(7).any(|x| x == b"payroll"));
+        assert!(fs.trash_entries().unwrap().is_empty());
+        assert!(matches!(
+            fs.read_path("/sec/payroll"),
+            Err(FsError::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn purge_when_full() {
+        let mut fs = System::format(Hardware::new(), FormatOptions::default()).unwrap();
+        fs.enable_trash(0).unwrap();
+        let data = vec![7; BLOCK_SIZE * 4];
+        let mut count = 0;
+        while fs.write_path(&format!("/f{}", count), &data).is_ok() {
+            count += 1;
+        }
+        assert!(count > 1);
+        for i in 0..count {
+            fs.delete_path(&format!("/f{}", i), false).unwrap();
+        }
+        assert_eq!(fs.trash_entries().unwrap().len(), count);
+
+        // 空间只能从回收站中腾出来，最早删除的条目先被清理
+        fs.write_path("/big", &data).unwrap();
+        let entries = fs.trash_entries().unwrap();
+        assert!(entries.len() < count);
+        assert!(entries.iter().all(|x| x.path != "/f0"));
+
+        // 低于阈值时删除之后立即清理
+        fs.enable_trash(MAX_TRASH_THRESHOLD).unwrap();
+        fs.delete_path("/big", false).unwrap();
+        let low = fs.statfs().total_blocks * MAX_TRASH_THRESHOLD as usize / 100;
+        assert!(fs.allocator.available() >= low || fs.trash_entries().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,261 @@
+use crate::core::crypt::Context;
+use crate::core::dir::Dir;
+use crate::core::error::{FsError, Result};
+use crate::core::hardware::{Hardware, BLOCK_SIZE, TOTAL_BLOCKS};
+use crate::core::inode::{Inode, DEFAULT_DIR_MODE, DEFAULT_FILE_MODE, INODE_SIZE, MAX_NAME_LEN};
+use crate::core::superblock::{
+    SuperBlock, BLOCK_BITMAP_BLOCK, FIRST_DATA_BLOCK, FORMAT_VERSION, INODE_BITMAP_BLOCK,
+    INODE_TABLE_BLOCK, INODE_TABLE_BLOCKS,
+};
+
+const V1_INODE_SIZE: usize = 64;
+const V1_FIRST_DATA_BLOCK: usize = 4;
+
+pub fn upgrade(hardware: &mut Hardware) -> Result<u32> {
+    let path = hardware.path().to_string();
+    let host_error = |e| FsError::Host(path.clone(), e);
+    let original = hardware.read_all().map_err(host_error)?;
+    let mut data = original.clone();
+
+    let mut super_block = SuperBlock::from_block_bytes(&data[..BLOCK_SIZE]);
+    if !super_block.is_initialized() {
+        return Err(FsError::Unformatted);
+    }
+    if super_block.version > FORMAT_VERSION {
+        return Err(FsError::UnsupportedVersion(super_block.version));
+    }
+
+    let from_version = super_block.version;
+
+    while super_block.version < FORMAT_VERSION {
+        match super_block.version {
+            0 => upgrade_v0(&mut super_block),
+            1 => upgrade_v1(&mut data, &mut super_block)?,
+            _ => unreachable!(),
+        }
+    }
+
+    let super_block_data = super_block.to_le_bytes();
+    data[..super_block_data.len()].copy_from_slice(&super_block_data);
+
+    let blocks = data
+        .chunks_exact(BLOCK_SIZE)
+        .zip(original.chunks_exact(BLOCK_SIZE));
+    for (i, (new, old)) in blocks.enumerate() {
+        if new != old {
+            hardware.write_block(i, new).map_err(host_error)?;
+        }
+    }
+    hardware.flush().map_err(host_error)?;
+
+    Ok(from_version)
+}
+
+// v0 镜像只写了 magic，版本号与特性位均为 0，布局与 v1 相同
+fn upgrade_v0(super_block: &mut SuperBlock) {
+    super_block.feature_compat = 0;
+    super_block.feature_ro_compat = 0;
+    super_block.feature_incompat = 0;
+    super_block.version = 1;
+}
+
+// v2 的索引节点扩展为 128 字节，索引节点表占用第3、4块，原先第4块中的数据需要先搬走
+fn upgrade_v1(data: &mut [u8], super_block: &mut SuperBlock) -> Result<()> {
+    let block_bitmap = BLOCK_SIZE * BLOCK_BITMAP_BLOCK;
+    let inode_bitmap = BLOCK_SIZE * INODE_BITMAP_BLOCK;
+    let inode_table = BLOCK_SIZE * INODE_TABLE_BLOCK;
+
+    let mut inodes: Vec<Inode> = data[inode_table..inode_table + BLOCK_SIZE]
+        .chunks_exact(V1_INODE_SIZE)
+        .enumerate()
+        .map(|(i, chunk)| parse_v1_inode(i, chunk))
+        .collect::<Result<_>>()?;
+
+    if data[block_bitmap + V1_FIRST_DATA_BLOCK] == 1 {
+        let target = (FIRST_DATA_BLOCK..TOTAL_BLOCKS)
+            .find(|i| data[block_bitmap + i] != 1)
+            .ok_or(FsError::NoFreeBlocks)?;
+
+        let src = V1_FIRST_DATA_BLOCK * BLOCK_SIZE;
+        data.copy_within(src..src + BLOCK_SIZE, target * BLOCK_SIZE);
+        data[block_bitmap + V1_FIRST_DATA_BLOCK] = 0;
+        data[block_bitmap + target] = 1;
+
+        for inode in inodes.iter_mut() {
+            for block_pos in inode.block_pos.iter_mut() {
+                if *block_pos as usize == V1_FIRST_DATA_BLOCK {
+                    *block_pos = target as u32;
+                }
+            }
+        }
+    }
+
+    let now = chrono::Utc::now().timestamp();
+    for (i, inode) in inodes.iter_mut().enumerate() {
+        if data[inode_bitmap + i] == 1 {
+            inode.mode = DEFAULT_FILE_MODE;
+            inode.links = 1;
+            inode.mtime = now;
+            inode.ctime = now;
+        }
+    }
+
+    let corrupted = |inode_index: usize| FsError::Corrupted {
+        what: format!("目录 {}", inode_index),
+        block: INODE_TABLE_BLOCK,
+    };
+    let mut visited = vec![false; inodes.len()];
+    let mut stack = vec![super_block.root_inode_index as usize];
+    while let Some(inode_index) = stack.pop() {
+        // 目录项中的索引节点号来自磁盘，越界或成环都说明镜像已损坏
+        if inode_index >= inodes.len() || visited[inode_index] {
+            return Err(corrupted(inode_index));
+        }
+        visited[inode_index] = true;
+        inodes[inode_index].mode = DEFAULT_DIR_MODE;
+
+        let mut dir_data = Vec::new();
+        for block_pos in inodes[inode_index].block_pos.iter() {
+            if *block_pos == 0 {
+                break;
+            }
+            if *block_pos as usize >= TOTAL_BLOCKS {
+                return Err(corrupted(inode_index));
+            }
+            let r = *block_pos as usize * BLOCK_SIZE;
+            dir_data.extend_from_slice(&data[r..r + BLOCK_SIZE]);
+        }
+
+        let dir = Dir::from_block_bytes("", inode_index, &dir_data).ok_or_else(|| {
+            FsError::Corrupted {
+                what: format!("目录 {}", inode_index),
+                block: inodes[inode_index].block_pos[0] as usize,
+            }
+        })?;
+        for item in dir.items {
+            if item.typ == "dir" && item.name != "." && item.name != ".." {
+                stack.push(item.inode_pos as usize);
+            }
+        }
+    }
+
+    let inodes_data = inodes
+        .iter()
+        .flat_map(|x| x.to_le_bytes())
+        .collect::<Vec<u8>>();
+    assert!(inodes_data.len() <= BLOCK_SIZE * INODE_TABLE_BLOCKS);
+    assert_eq!(inodes_data.len() / INODE_SIZE, inodes.len());
+    data[inode_table..inode_table + inodes_data.len()].copy_from_slice(&inodes_data);
+
+    super_block.version = 2;
+
+    Ok(())
+}
+
+fn parse_v1_inode(inode_index: usize, chunk: &[u8]) -> Result<Inode> {
+    let name_len = chunk[0] as usize;
+    if name_len > MAX_NAME_LEN {
+        return Err(FsError::Corrupted {
+            what: format!("索引节点 {}", inode_index),
+            block: INODE_TABLE_BLOCK,
+        });
+    }
+
+    Ok(Inode {
+        name: String::from_utf8_lossy(&chunk[1..1 + name_len]).to_string(),
+        size: u32::from_le_bytes(chunk[32..36].try_into().unwrap()),
+        block_pos: chunk[36..64]
+            .chunks_exact(4)
+            .map(|x| u32::from_le_bytes(x.try_into().unwrap()))
+            .collect(),
+        mode: 0,
+        links: 0,
+        mtime: 0,
+        ctime: 0,
+        flags: 0,
+        context: Context::default(),
+        uid: 0,
+        project: 0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::dir::DirItem;
+    use crate::core::fs::System;
+    use crate::core::superblock::INIT_MAGIC;
+
+    fn item(inode_pos: u32, name: &str, typ: &str, size: u32) -> DirItem {
+        DirItem {
+            inode_pos,
+            name: name.to_string(),
+            typ: typ.to_string(),
+            size,
+        }
+    }
+
+    fn v1_inode(name: &str, size: u32, block_pos: u32) -> Vec<u8> {
+        let mut data = vec![0; V1_INODE_SIZE];
+        data[0] = name.len() as u8;
+        data[1..1 + name.len()].copy_from_slice(name.as_bytes());
+        data[32..36].copy_from_slice(&size.to_le_bytes());
+        data[36..40].copy_from_slice(&block_pos.to_le_bytes());
+        data
+    }
+
+    // 根目录放在第4块，升级时需要先把它搬走
+    fn old_image(version: u32, inode_table: &[Vec<u8>]) -> Hardware {
+        let mut hardware = Hardware::new();
+        let mut block = |pos: usize, data: &[u8]| {
+            let mut buf = vec![0; BLOCK_SIZE];
+            buf[..data.len()].copy_from_slice(data);
+            hardware.write_block(pos, &buf).unwrap();
+        };
+
+        let super_block = SuperBlock {
+            magic: INIT_MAGIC,
+            version,
+            ..Default::default()
+        };
+        block(0, &super_block.to_le_bytes());
+        block(BLOCK_BITMAP_BLOCK, &[0, 0, 0, 0, 1, 1]);
+        block(INODE_BITMAP_BLOCK, &[1, 1]);
+        block(INODE_TABLE_BLOCK, &inode_table.concat());
+
+        let mut root = Dir::new("/", 0);
+        root.items = vec![
+            item(0, ".", "dir", 0),
+            item(0, "..", "dir", 0),
+            item(1, "a", "file", 5),
+        ];
+        block(V1_FIRST_DATA_BLOCK, &root.to_block_bytes());
+        block(V1_FIRST_DATA_BLOCK + 1, b"hello");
+        hardware
+    }
+
+    #[test]
+    fn upgrade_old_versions() {
+        for version in [0, 1] {
+            let inodes = [v1_inode("/", 0, 4), v1_inode("a", 5, 5)];
+            let mut hardware = old_image(version, &inodes);
+            assert_eq!(upgrade(&mut hardware).unwrap(), version);
+
+            let fs = System::init(hardware).unwrap();
+            assert_eq!(fs.super_block.version, FORMAT_VERSION);
+            assert_eq!(fs.read_path("/a").unwrap(), b"hello");
+            assert!(fs.stat("/").unwrap().typ == "dir");
+        }
+    }
+
+    #[test]
+    fn reject_garbage_v1_inode() {
+        let mut garbage = v1_inode("a", 5, 5);
+        garbage[0] = 200;
+        let mut hardware = old_image(1, &[v1_inode("/", 0, 4), garbage]);
+        assert!(matches!(
+            upgrade(&mut hardware),
+            Err(FsError::Corrupted { .. })
+        ));
+    }
+}
@@ -0,0 +1,53 @@
+use std::collections::HashSet;
+
+use crate::core::error::Result;
+use crate::core::fs::{join_path, System};
+use crate::core::hardware::BLOCK_SIZE;
+
+#[derive(Debug, Default)]
+pub struct DiskUsage {
+    pub inodes: usize,
+    pub logical: u64,
+    pub physical: u64,
+}
+
+impl System {
+    // 逻辑大小是内容长度之和，物理大小是实际占用的块（包括区段叶子块）。
+    // 硬链接、克隆文件和快照共享的块只计一次；尚未刷新的延迟写入不计入物理大小。
+    pub fn disk_usage(&self, path: &str) -> Result<DiskUsage> {
+        let mut usage = DiskUsage::default();
+        let mut seen = HashSet::new();
+        let mut blocks = HashSet::new();
+        self.usage_path(path, &mut usage, &mut seen, &mut blocks)?;
+        usage.physical = (blocks.len() * BLOCK_SIZE) as u64;
+        Ok(usage)
+    }
+
+    fn usage_path(
+        &self,
+        path: &str,
+        usage: &mut DiskUsage,
+        seen: &mut HashSet<usize>,
+        blocks: &mut HashSet<u32>,
+    ) -> Result<()> {
+        let stat = self.stat(path)?;
+        if !seen.insert(stat.inode_index) {
+            return Ok(());
+        }
+
+        usage.inodes += 1;
+        usage.logical += stat.size as u64;
+        blocks.extend(stat.blocks);
+        blocks.extend(self.mapping_blocks(stat.inode_index)?);
+        if stat.typ != "dir" {
+            return Ok(());
+        }
+
+        for item in self.open_dir_path(path)?.items.iter() {
+            if item.name != "." && item.name != ".." {
+                self.usage_path(&join_path(path, &item.name), usage, seen, blocks)?;
+            }
+        }
+        Ok(())
+    }
+}
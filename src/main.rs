@@ -1,95 +1,240 @@
-use file_sys::core::hardware;
+use std::io::IsTerminal;
+use std::process;
+
+use file_sys::core::cache::DEFAULT_CACHE_BLOCKS;
 use file_sys::core::fs;
+use file_sys::core::hardware;
+use file_sys::shell::{self, Flow, Shell};
+
+const USAGE: &str =
+    "用法: file-sys [--image <镜像文件>] [--read-only] [--cache <块数>] [--no-delalloc]
+               [--superblock <块号>] [--passphrase <口令>] [--uid <用户号>]
+               [-c <命令>] [脚本文件]
+      file-sys [--read-only] [--superblock <块号>] <镜像文件> <子命令> [参数]...";
+
+struct Options {
+    image: String,
+    read_only: bool,
+    cache_blocks: usize,
+    delayed_alloc: bool,
+    super_block: Option<usize>,
+    passphrase: Option<String>,
+    uid: u16,
+    command: Option<String>,
+    script: Option<String>,
+    subcommand: Option<Vec<String>>,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut options = Options {
+        image: String::from("fs_data"),
+        read_only: false,
+        cache_blocks: DEFAULT_CACHE_BLOCKS,
+        delayed_alloc: true,
+        super_block: None,
+        passphrase: None,
+        uid: 0,
+        command: None,
+        script: None,
+        subcommand: None,
+    };
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--image" => {
+                options.image = args.next().ok_or("--image 缺少参数")?;
+            }
+            "--read-only" => {
+                options.read_only = true;
+            }
+            "--cache" => {
+                options.cache_blocks = args
+                    .next()
+                    .and_then(|x| x.parse().ok())
+                    .filter(|x| *x > 0)
+                    .ok_or("--cache 需要一个正整数")?;
+            }
+            "--no-delalloc" => {
+                options.delayed_alloc = false;
+            }
+            "--superblock" => {
+                options.super_block = Some(
+                    args.next()
+                        .and_then(|x| x.parse().ok())
+                        .ok_or("--superblock 需要一个块号")?,
+                );
+            }
+            "--passphrase" => {
+                options.passphrase = Some(args.next().ok_or("--passphrase 缺少参数")?);
+            }
+            "--uid" => {
+                options.uid = args
+                    .next()
+                    .and_then(|x| x.parse().ok())
+                    .ok_or("--uid 需要一个用户号（0-65535）")?;
+            }
+            "-c" => {
+                options.command = Some(args.next().ok_or("-c 缺少参数")?);
+            }
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            _ if arg.starts_with('-') => return Err(format!("未知选项 {}", arg)),
+            _ => {
+                let rest: Vec<String> = args.by_ref().collect();
+                if rest.is_empty() {
+                    options.script = Some(arg);
+                } else {
+                    options.image = arg;
+                    options.subcommand = Some(rest);
+                }
+            }
+        }
+    }
+
+    if options.command.is_some() && (options.script.is_some() || options.subcommand.is_some()) {
+        return Err(String::from("-c 不能与脚本文件或子命令同时使用"));
+    }
+
+    Ok(options)
+}
 
 fn main() {
-    let hardware = hardware::Hardware::load("fs_data");
-    let mut fs: fs::System = fs::System::init(hardware);
+    let options = parse_args().unwrap_or_else(|e| {
+        eprintln!("{}\n{}", e, USAGE);
+        process::exit(2);
+    });
+
+    let script = match (&options.command, &options.script) {
+        _ if options.subcommand.is_some() => None,
+        (Some(command), _) => Some(command.clone()),
+        (None, Some(path)) => Some(std::fs::read_to_string(path).unwrap_or_else(|e| {
+            eprintln!("{}: {}", path, e);
+            process::exit(2);
+        })),
+        (None, None) if !std::io::stdin().is_terminal() => {
+            Some(std::io::read_to_string(std::io::stdin()).expect("Failed to read stdin"))
+        }
+        (None, None) => None,
+    };
 
-    main_cmd_loop(&mut fs);
+    let hardware = if options.read_only {
+        hardware::Hardware::load_read_only(&options.image)
+    } else {
+        hardware::Hardware::load(&options.image)
+    };
+    let hardware = hardware.unwrap_or_else(|e| {
+        eprintln!("{}: {}", options.image, e);
+        process::exit(1);
+    });
+    let mount_options = fs::MountOptions {
+        read_only: options.read_only,
+        cache_blocks: options.cache_blocks,
+        delayed_alloc: options.delayed_alloc,
+        super_block: options.super_block,
+        uid: options.uid,
+    };
+    let mut fs: fs::System = fs::System::mount(hardware, mount_options).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    if let Some(passphrase) = &options.passphrase {
+        fs.set_passphrase(passphrase);
+    }
+    if let Some(block_pos) = fs.recovered_from {
+        eprintln!("警告: 主超级块已损坏，使用第{}块的副本", block_pos);
+    }
+    let mut shell = Shell::new(fs).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
 
-    fs.save("fs_data");
+    let code = match (script, &options.subcommand) {
+        (_, Some(args)) => run_subcommand(&mut shell, args),
+        (Some(script), None) => run_script(&mut shell, &script),
+        (None, None) => main_cmd_loop(&mut shell),
+    };
+
+    let code = match shell.fs.sync() {
+        Ok(_) => code,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    };
+
+    process::exit(code);
+}
+
+fn run_subcommand(shell: &mut Shell, args: &[String]) -> i32 {
+    match shell.execute(args) {
+        Ok(Flow::Continue) => 0,
+        Ok(Flow::Exit(code)) => code,
+        Err(e) => {
+            eprintln!("{}", e);
+            e.exit_code()
+        }
+    }
+}
+
+fn run_script(shell: &mut Shell, script: &str) -> i32 {
+    let commands = match shell::parse(script) {
+        Ok(commands) => commands,
+        Err(e) => {
+            eprintln!("{}", e);
+            return e.exit_code();
+        }
+    };
+
+    for command in commands {
+        match shell.execute(&command.args) {
+            Ok(Flow::Continue) => {}
+            Ok(Flow::Exit(code)) => return code,
+            Err(e) => {
+                eprintln!("第{}行: {}", command.line, e);
+                return e.exit_code();
+            }
+        }
+    }
+
+    0
 }
 
-fn main_cmd_loop(fs: &mut fs::System) {
-    let mut current_dir = fs.get_root_dir();
+fn main_cmd_loop(shell: &mut Shell) -> i32 {
+    let mut status = 0;
 
     loop {
         let mut cmd = String::new();
 
-        println!("{}>", current_dir.name);
+        println!("{}", shell.prompt());
 
-        std::io::stdin()
+        let n = std::io::stdin()
             .read_line(&mut cmd)
             .expect("Failed to read line");
-
-        let cmd = cmd.trim().split(" ").collect::<Vec<&str>>()  ;
-
-        if cmd[0] == "exit" {
-            break;
+        if n == 0 {
+            return status;
         }
 
-        match cmd[0] {
-            "ls" => {
-                current_dir.show();
+        let commands = match shell::parse(&cmd) {
+            Ok(commands) => commands,
+            Err(e) => {
+                eprintln!("{}", e);
+                status = e.exit_code();
+                continue;
             }
-            "cd" => {
-                let mut dir_name = String::new();
-                if cmd.len() > 1 {
-                    dir_name = String::from(cmd[1]);
-                }
-                current_dir = fs.open_dir(&current_dir, dir_name.as_str());
-            }
-            "mkdir" => {
-                let mut dir_name = String::new();
-                if cmd.len() > 1 {
-                    dir_name = String::from(cmd[1]);
-                }
-                fs.create_dir(&mut current_dir, dir_name.as_str());
-            }
-            "rmdir" => {
-                let mut dir_name = String::new();
-                if cmd.len() > 1 {
-                    dir_name = String::from(cmd[1]);
-                }
-                fs.remove_dir(&mut current_dir, dir_name.as_str());
-            }
-            "create" => {
-                let mut file_name = String::new();
-                if cmd.len() > 1 {
-                    file_name = String::from(cmd[1]);
-                }
-                fs.create_file(&mut current_dir, file_name.as_str());
-            }
-            "open" => {
-                let mut file_name = String::new();
-                if cmd.len() > 1 {
-                    file_name = String::from(cmd[1]);
-                }
-                let file = fs.open_file(&mut current_dir, file_name.as_str());
-                println!("{}", file.content);
-            }
-            "write" => {
-                let mut file_name = String::new();
-                if cmd.len() > 1 {
-                    file_name = String::from(cmd[1]);
-                }
-                let mut file = fs.open_file(&mut current_dir, file_name.as_str());
-                let mut content = String::new();
-                if cmd.len() > 2 {
-                    content = String::from(cmd[2]);
-                }
-                fs.write_file(&mut current_dir, &mut file, content.as_bytes());
-                println!("{}", file.content);
-            }
-            "rm" => {
-                let mut file_name = String::new();
-                if cmd.len() > 1 {
-                    file_name = String::from(cmd[1]);
+        };
+
+        for command in commands {
+            match shell.execute(&command.args) {
+                Ok(Flow::Continue) => status = 0,
+                Ok(Flow::Exit(code)) => return code,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    status = e.exit_code();
+                    break;
                 }
-                fs.remove_file(&mut current_dir, file_name.as_str());
-            }
-            _ => {
-                println!("未知命令");
             }
         }
     }
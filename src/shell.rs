@@ -0,0 +1,1032 @@
+use std::fmt;
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::core::dir::Dir;
+use crate::core::error::FsError;
+use crate::core::fs::{split_path, System};
+use crate::core::hardware::BLOCK_SIZE;
+use crate::core::quota::{Limits, QuotaEntry, QuotaKind};
+use crate::core::transfer::TransferReport;
+use crate::core::trash::DEFAULT_TRASH_THRESHOLD;
+
+const SYNC_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub enum ShellError {
+    Parse { line: usize, msg: &'static str },
+    Usage(&'static str),
+    UnknownCommand(String),
+    // 命令本身执行完了，但检查发现了问题（例如 scrub 发现损坏）
+    CheckFailed(usize),
+    Host(String, std::io::Error),
+    Fs(FsError),
+}
+
+impl ShellError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ShellError::Fs(_) | ShellError::Host(..) | ShellError::CheckFailed(_) => 1,
+            _ => 2,
+        }
+    }
+}
+
+impl From<FsError> for ShellError {
+    fn from(e: FsError) -> Self {
+        ShellError::Fs(e)
+    }
+}
+
+impl fmt::Display for ShellError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShellError::Parse { line, msg } => write!(f, "第{}行: 语法错误: {}", line, msg),
+            ShellError::Usage(usage) => write!(f, "用法: {}", usage),
+            ShellError::UnknownCommand(cmd) => write!(f, "{}: 未知命令", cmd),
+            ShellError::CheckFailed(count) => write!(f, "发现 {} 个错误", count),
+            ShellError::Host(path, e) => write!(f, "{}: {}", path, e),
+            ShellError::Fs(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ShellError {}
+
+#[derive(Debug)]
+pub enum Flow {
+    Continue,
+    Exit(i32),
+}
+
+#[derive(Debug)]
+pub struct Command {
+    pub line: usize,
+    pub args: Vec<String>,
+}
+
+pub fn parse(text: &str) -> Result<Vec<Command>, ShellError> {
+    let mut commands = Vec::new();
+    let mut args: Vec<String> = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut line = 1;
+    let mut command_line = 1;
+
+    let mut chars = text.chars().peekable();
+
+    macro_rules! finish_word {
+        () => {
+            if in_word {
+                args.push(std::mem::take(&mut word));
+                in_word = false;
+            }
+        };
+    }
+
+    macro_rules! start_word {
+        () => {
+            if !in_word && args.is_empty() {
+                command_line = line;
+            }
+            in_word = true;
+        };
+    }
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' | ';' => {
+                finish_word!();
+                if !args.is_empty() {
+                    commands.push(Command {
+                        line: command_line,
+                        args: std::mem::take(&mut args),
+                    });
+                }
+                if c == '\n' {
+                    line += 1;
+                }
+            }
+            ' ' | '\t' | '\r' => finish_word!(),
+            '#' if !in_word => while chars.next_if(|x| *x != '\n').is_some() {},
+            '\\' => match chars.next() {
+                Some('\n') => line += 1,
+                Some(next) => {
+                    start_word!();
+                    word.push(next);
+                }
+                None => {
+                    return Err(ShellError::Parse {
+                        line,
+                        msg: "行尾多余的反斜杠",
+                    })
+                }
+            },
+            '\'' => {
+                start_word!();
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(next) => {
+                            if next == '\n' {
+                                line += 1;
+                            }
+                            word.push(next);
+                        }
+                        None => {
+                            return Err(ShellError::Parse {
+                                line,
+                                msg: "单引号未闭合",
+                            })
+                        }
+                    }
+                }
+            }
+            '"' => {
+                start_word!();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some('n') => word.push('\n'),
+                            Some('t') => word.push('\t'),
+                            Some('\n') => line += 1,
+                            Some(next @ ('"' | '\\')) => word.push(next),
+                            Some(next) => {
+                                word.push('\\');
+                                word.push(next);
+                            }
+                            None => {
+                                return Err(ShellError::Parse {
+                                    line,
+                                    msg: "双引号未闭合",
+                                })
+                            }
+                        },
+                        Some(next) => {
+                            if next == '\n' {
+                                line += 1;
+                            }
+                            word.push(next);
+                        }
+                        None => {
+                            return Err(ShellError::Parse {
+                                line,
+                                msg: "双引号未闭合",
+                            })
+                        }
+                    }
+                }
+            }
+            _ => {
+                start_word!();
+                word.push(c);
+            }
+        }
+    }
+
+    if in_word {
+        args.push(word);
+    }
+    if !args.is_empty() {
+        commands.push(Command {
+            line: command_line,
+            args,
+        });
+    }
+
+    Ok(commands)
+}
+
+pub struct Shell {
+    pub fs: System,
+    pub current_dir: Dir,
+    pub cwd: String,
+    last_sync: Instant,
+}
+
+impl Shell {
+    pub fn new(fs: System) -> Result<Self, FsError> {
+        let current_dir = fs.get_root_dir()?;
+        Ok(Self {
+            fs,
+            current_dir,
+            cwd: String::from("/"),
+            last_sync: Instant::now(),
+        })
+    }
+
+    pub fn prompt(&self) -> String {
+        match self.fs.viewing() {
+            Some(snapshot) => format!("{}@{}>", self.current_dir.name, snapshot),
+            None => format!("{}>", self.current_dir.name),
+        }
+    }
+
+    pub fn resolve(&self, path: &str) -> String {
+        let mut parts: Vec<&str> = Vec::new();
+        if !path.starts_with('/') {
+            parts.extend(self.cwd.split('/').filter(|x| !x.is_empty()));
+        }
+        for name in path.split('/') {
+            match name {
+                "" | "." => {}
+                ".." => {
+                    parts.pop();
+                }
+                _ => parts.push(name),
+            }
+        }
+        format!("/{}", parts.join("/"))
+    }
+
+    pub fn execute(&mut self, args: &[String]) -> Result<Flow, ShellError> {
+        let mut result = self.dispatch(args);
+
+        // 每条命令之后检查空闲块，低于阈值时清理回收站；
+        // 清理失败不影响命令本身的结果
+        match self.fs.purge_trash() {
+            Ok(0) => {}
+            Ok(purged) => println!("空闲块不足，已从回收站清理 {} 项", purged),
+            Err(e) => eprintln!("警告: 清理回收站失败: {}", e),
+        }
+
+        if self.last_sync.elapsed() >= SYNC_INTERVAL {
+            self.last_sync = Instant::now();
+            if let Err(e) = self.fs.sync() {
+                result = result.and(Err(ShellError::Fs(e)));
+            }
+        }
+
+        match self.fs.open_dir_path(&self.cwd) {
+            Ok(dir) => self.current_dir = dir,
+            Err(_) => {
+                self.cwd = String::from("/");
+                if let Ok(root) = self.fs.get_root_dir() {
+                    self.current_dir = root;
+                }
+            }
+        }
+
+        result
+    }
+
+    fn dispatch(&mut self, args: &[String]) -> Result<Flow, ShellError> {
+        let cmd = args[0].as_str();
+        let args = &args[1..];
+
+        match cmd {
+            "exit" => {
+                let code = match args.first() {
+                    Some(code) => code
+                        .parse()
+                        .map_err(|_| ShellError::Usage("exit [状态码]"))?,
+                    None => 0,
+                };
+                return Ok(Flow::Exit(code));
+            }
+            "ls" => match args.first() {
+                Some(path) => self.fs.open_dir_path(&self.resolve(path))?.show(),
+                None => self.current_dir.show(),
+            },
+            "cd" => {
+                let path = match args.first() {
+                    Some(path) => self.resolve(path),
+                    None => String::from("/"),
+                };
+                self.current_dir = self.fs.open_dir_path(&path)?;
+                self.cwd = path;
+            }
+            "mkdir" => {
+                let (parents, paths) = flag(args, "-p");
+                for path in required(paths, "mkdir [-p] <目录>...")? {
+                    let path = self.resolve(path);
+                    if parents {
+                        self.fs.create_dir_all(&path)?;
+                    } else {
+                        let (parent, name) = split_path(&path)?;
+                        let mut dir = self.fs.open_dir_path(parent)?;
+                        self.fs.create_dir(&mut dir, name)?;
+                    }
+                }
+            }
+            "rmdir" => {
+                for path in required(args, "rmdir <目录>...")? {
+                    let path = self.resolve(path);
+                    if self.fs.stat(&path)?.typ != "dir" {
+                        return Err(FsError::NotADirectory(path).into());
+                    }
+                    self.fs.delete_path(&path, true)?;
+                }
+            }
+            "create" => {
+                for path in required(args, "create <文件>...")? {
+                    let path = self.resolve(path);
+                    let (parent, name) = split_path(&path)?;
+                    let mut dir = self.fs.open_dir_path(parent)?;
+                    self.fs.create_file(&mut dir, name)?;
+                }
+            }
+            "open" => {
+                let path = self.resolve(&required(args, "open <文件>")?[0]);
+                let (parent, name) = split_path(&path)?;
+                let mut dir = self.fs.open_dir_path(parent)?;
+                let file = self.fs.open_file(&mut dir, name)?;
+                println!("{}", file.content);
+                self.fs.close_file(file);
+            }
+            "write" => {
+                let path = self.resolve(&required(args, "write <文件> [内容]...")?[0]);
+                let content = args[1..].join(" ");
+                self.fs.write_path(&path, content.as_bytes())?;
+                println!("{}", content);
+            }
+            "rm" => {
+                let (recursive, paths) = flag(args, "-r");
+                for path in required(paths, "rm [-r] <路径>...")? {
+                    self.fs.delete_path(&self.resolve(path), recursive)?;
+                }
+            }
+            "cat" => {
+                let mut stdout = std::io::stdout();
+                for path in required(args, "cat <文件>...")? {
+                    let data = self.fs.read_path(&self.resolve(path))?;
+                    stdout
+                        .write_all(&data)
+                        .map_err(|e| ShellError::Host(String::from("stdout"), e))?;
+                }
+            }
+            "put" => {
+                let [host_path, path] = args else {
+                    return Err(ShellError::Usage("put <主机文件> <路径>"));
+                };
+                let data =
+                    std::fs::read(host_path).map_err(|e| ShellError::Host(host_path.clone(), e))?;
+                self.fs.write_path(&self.resolve(path), &data)?;
+            }
+            "get" => {
+                let [path, host_path] = args else {
+                    return Err(ShellError::Usage("get <路径> <主机文件>"));
+                };
+                let data = self.fs.read_path(&self.resolve(path))?;
+                std::fs::write(host_path, data)
+                    .map_err(|e| ShellError::Host(host_path.clone(), e))?;
+            }
+            "mv" => {
+                let [from, to] = args else {
+                    return Err(ShellError::Usage("mv <源路径> <目标路径>"));
+                };
+                self.fs.rename(&self.resolve(from), &self.resolve(to))?;
+            }
+            "cp" => {
+                let (reflink, args) = flag(args, "--reflink");
+                let [src, dst] = args else {
+                    return Err(ShellError::Usage("cp [--reflink] <源文件> <目标路径>"));
+                };
+                if reflink {
+                    self.fs.clone_file(&self.resolve(src), &self.resolve(dst))?;
+                } else {
+                    self.fs.copy_file(&self.resolve(src), &self.resolve(dst))?;
+                }
+            }
+            "stat" => {
+                for path in required(args, "stat <路径>...")? {
+                    let stat = self.fs.stat(&self.resolve(path))?;
+                    println!("  名称: {}", stat.name);
+                    println!("  类型: {}", stat.typ);
+                    println!("  大小: {}", stat.size);
+                    println!("  权限: {:o}", stat.mode);
+                    println!("  链接数: {}", stat.links);
+                    println!("  所有者: {}", stat.uid);
+                    if stat.project != 0 {
+                        println!("  项目: {}", stat.project);
+                    }
+                    if stat.compressed {
+                        println!("  压缩: 是");
+                    }
+                    if stat.encrypted {
+                        let unlocked = self.fs.is_unlocked(stat.inode_index)?;
+                        println!("  加密: {}", if unlocked { "已解锁" } else { "已锁定" });
+                    }
+                    println!("修改时间: {}", format_time(stat.mtime));
+                    println!("变更时间: {}", format_time(stat.ctime));
+                    println!("索引节点: {}", stat.inode_index);
+                    println!("  数据块: {:?}", stat.blocks);
+                    if stat.delayed_blocks > 0 {
+                        println!("  延迟分配: 预留 {} 块，尚未写入", stat.delayed_blocks);
+                    }
+                    if !stat.extents.is_empty() {
+                        let extents: Vec<String> = stat
+                            .extents
+                            .iter()
+                            .map(|x| format!("{}+{}", x.start, x.len))
+                            .collect();
+                        println!("    区段: [{}]", extents.join(", "));
+                    }
+                }
+            }
+            "import" => {
+                let [host_dir, path] = args else {
+                    return Err(ShellError::Usage("import <主机目录> <路径>"));
+                };
+                let report =
+                    self.fs
+                        .import_tree(Path::new(host_dir), &self.resolve(path), &mut |path| {
+                            println!("导入 {}", path)
+                        })?;
+                print_report(&report);
+            }
+            "export" => {
+                let [path, host_dir] = args else {
+                    return Err(ShellError::Usage("export <路径> <主机目录>"));
+                };
+                let report =
+                    self.fs
+                        .export_tree(&self.resolve(path), Path::new(host_dir), &mut |path| {
+                            println!("导出 {}", path)
+                        })?;
+                print_report(&report);
+            }
+            "tar-in" => {
+                let [host_path, path] = args else {
+                    return Err(ShellError::Usage("tar-in <tar文件|-> <路径>"));
+                };
+                let path = self.resolve(path);
+                let mut progress = |path: &str| println!("解包 {}", path);
+                let report = if host_path == "-" {
+                    self.fs
+                        .tar_in(std::io::stdin().lock(), &path, &mut progress)?
+                } else {
+                    let file = std::fs::File::open(host_path)
+                        .map_err(|e| ShellError::Host(host_path.clone(), e))?;
+                    self.fs
+                        .tar_in(std::io::BufReader::new(file), &path, &mut progress)?
+                };
+                print_report(&report);
+            }
+            "tar-out" => {
+                let [path, host_path] = args else {
+                    return Err(ShellError::Usage("tar-out <路径> <tar文件|->"));
+                };
+                let path = self.resolve(path);
+                let mut progress = |path: &str| eprintln!("打包 {}", path);
+                let report = if host_path == "-" {
+                    self.fs
+                        .tar_out(&path, std::io::stdout().lock(), &mut progress)?
+                } else {
+                    let file = std::fs::File::create(host_path)
+                        .map_err(|e| ShellError::Host(host_path.clone(), e))?;
+                    self.fs
+                        .tar_out(&path, std::io::BufWriter::new(file), &mut progress)?
+                };
+                if host_path != "-" {
+                    print_report(&report);
+                }
+            }
+            "ln" => {
+                let (symbolic, args) = flag(args, "-s");
+                let [target, path] = args else {
+                    return Err(ShellError::Usage("ln [-s] <目标> <链接>"));
+                };
+                if symbolic {
+                    self.fs.symlink(target, &self.resolve(path))?;
+                } else {
+                    self.fs.link(&self.resolve(target), &self.resolve(path))?;
+                }
+            }
+            "readlink" => {
+                for path in required(args, "readlink <路径>...")? {
+                    println!("{}", self.fs.read_link(&self.resolve(path))?);
+                }
+            }
+            "snapshot" => {
+                const USAGE: &str =
+                    "snapshot create|delete|rollback <名称> | snapshot list | snapshot browse [名称]";
+                let args: Vec<&str> = args.iter().map(|x| x.as_str()).collect();
+                match args[..] {
+                    ["create", name] => self.fs.create_snapshot(name)?,
+                    ["delete", name] => self.fs.delete_snapshot(name)?,
+                    ["rollback", name] => self.fs.rollback_snapshot(name)?,
+                    ["browse"] | ["browse", _] => {
+                        self.fs.browse_snapshot(args.get(1).copied())?;
+                        self.cwd = String::from("/");
+                    }
+                    ["list"] => {
+                        for snapshot in self.fs.snapshots() {
+                            println!("{}\t{}", snapshot.name, format_time(snapshot.ctime));
+                        }
+                    }
+                    _ => return Err(ShellError::Usage(USAGE)),
+                }
+            }
+            "trash" => {
+                const USAGE: &str = "trash on [百分比] | trash off | trash list | trash restore <编号> [目标路径] | trash empty";
+                let args: Vec<&str> = args.iter().map(|x| x.as_str()).collect();
+                match args[..] {
+                    ["on"] => self.fs.enable_trash(DEFAULT_TRASH_THRESHOLD)?,
+                    ["on", percent] => {
+                        let percent = percent
+                            .trim_end_matches('%')
+                            .parse()
+                            .map_err(|_| ShellError::Usage(USAGE))?;
+                        self.fs.enable_trash(percent)?;
+                    }
+                    ["off"] => self.fs.disable_trash()?,
+                    ["list"] => {
+                        for entry in self.fs.trash_entries()? {
+                            println!(
+                                "{}\t{}\t{}",
+                                entry.id,
+                                format_time(entry.deleted),
+                                entry.path
+                            );
+                        }
+                    }
+                    ["restore", id] | ["restore", id, _] => {
+                        let id = id.parse().map_err(|_| ShellError::Usage(USAGE))?;
+                        let to = args.get(2).map(|x| self.resolve(x));
+                        let path = self.fs.restore_trash(id, to.as_deref())?;
+                        println!("已恢复到 {}", path);
+                    }
+                    ["empty"] => {
+                        let count = self.fs.empty_trash()?;
+                        println!("已清空回收站，删除 {} 项", count);
+                    }
+                    _ => return Err(ShellError::Usage(USAGE)),
+                }
+            }
+            "sync" => {
+                let count = self.fs.sync()?;
+                self.last_sync = Instant::now();
+                println!("已写回 {} 个数据块", count);
+            }
+            "stats" => {
+                let stats = self.fs.cache_stats();
+                let total = stats.hits + stats.misses;
+                let ratio = if total == 0 {
+                    0.0
+                } else {
+                    stats.hits as f64 * 100.0 / total as f64
+                };
+                println!(
+                    "块缓存: 容量 {}, 已缓存 {}, 脏块 {}, 固定 {}",
+                    stats.capacity, stats.cached, stats.dirty, stats.pinned
+                );
+                println!(
+                    "命中 {}, 未命中 {}, 命中率 {:.1}%",
+                    stats.hits, stats.misses, ratio
+                );
+                println!("淘汰 {}, 写回 {}", stats.evictions, stats.writebacks);
+
+                let inodes = self.fs.inode_cache_stats();
+                println!(
+                    "索引节点缓存: 容量 {}, 已缓存 {}, 脏节点 {}, 命中 {}, 未命中 {}",
+                    inodes.capacity, inodes.cached, inodes.dirty, inodes.hits, inodes.misses
+                );
+
+                let dentries = self.fs.dentry_cache_stats();
+                println!(
+                    "目录项缓存: 容量 {}, 已缓存 {} (不存在 {}), 命中 {}, 未命中 {}",
+                    dentries.capacity,
+                    dentries.cached,
+                    dentries.negative,
+                    dentries.hits,
+                    dentries.misses
+                );
+
+                println!(
+                    "块分配器: 预分配窗口 {}, 延迟分配预留 {} 块",
+                    self.fs.allocator.windows(),
+                    self.fs.allocator.reserved()
+                );
+            }
+            "du" => {
+                let path = match args.first() {
+                    Some(path) => self.resolve(path),
+                    None => self.cwd.clone(),
+                };
+                // 先刷新延迟分配的写入，物理大小才准确
+                self.fs.sync()?;
+                self.last_sync = Instant::now();
+
+                let usage = self.fs.disk_usage(&path)?;
+                println!(
+                    "索引节点 {} 个，逻辑大小 {}B，物理大小 {}B",
+                    usage.inodes, usage.logical, usage.physical
+                );
+            }
+            "dedupe" => {
+                let report = self.fs.dedupe()?;
+                println!(
+                    "检查文件 {} 个，数据块 {} 个，合并 {} 个，释放 {} 块（{}B）",
+                    report.files,
+                    report.blocks,
+                    report.merged,
+                    report.freed,
+                    report.freed * BLOCK_SIZE
+                );
+                if report.skipped > 0 {
+                    println!("空闲块不足，跳过文件 {} 个", report.skipped);
+                }
+            }
+            "encrypt" => {
+                for path in required(args, "encrypt <目录>...")? {
+                    self.fs.encrypt_dir(&self.resolve(path))?;
+                }
+            }
+            "chattr" => {
+                let (enabled, paths) = match args.split_first() {
+                    Some((op, paths)) if op == "+c" => (true, paths),
+                    Some((op, paths)) if op == "-c" => (false, paths),
+                    _ => return Err(ShellError::Usage("chattr +c|-c <路径>...")),
+                };
+                if paths.is_empty() {
+                    return Err(ShellError::Usage("chattr +c|-c <路径>..."));
+                }
+                for path in paths {
+                    self.fs.set_compression(&self.resolve(path), enabled)?;
+                }
+            }
+            "quota" => {
+                let (kind, id) = match args {
+                    [] => (QuotaKind::User, self.fs.options.uid),
+                    [kind, id] => match (kind_arg(kind), id.parse()) {
+                        (Some(kind), Ok(id)) => (kind, id),
+                        _ => return Err(ShellError::Usage("quota [-u <用户号>|-p <项目号>]")),
+                    },
+                    _ => return Err(ShellError::Usage("quota [-u <用户号>|-p <项目号>]")),
+                };
+                let entry = self.fs.quota(kind, id)?;
+                print_quota(kind, id, &entry);
+            }
+            "repquota" => {
+                let report = self.fs.quota_report()?;
+                println!("宽限期: {}秒", self.fs.grace_period());
+                for (kind, id, entry) in report.iter() {
+                    print!("  ");
+                    print_quota(*kind, *id, entry);
+                }
+            }
+            "setquota" => {
+                const USAGE: &str = "setquota -u|-p <编号> <块软限制> <块硬限制> <索引节点软限制> <索引节点硬限制>\n       setquota -t <宽限期秒数>";
+                match args {
+                    [flag, seconds] if flag == "-t" => {
+                        let seconds = seconds.parse().map_err(|_| ShellError::Usage(USAGE))?;
+                        self.fs.set_grace_period(seconds)?;
+                    }
+                    [kind, id, limits @ ..] if limits.len() == 4 => {
+                        let kind = kind_arg(kind).ok_or(ShellError::Usage(USAGE))?;
+                        let id = id.parse().map_err(|_| ShellError::Usage(USAGE))?;
+                        let limits: Vec<u32> = limits
+                            .iter()
+                            .map(|x| x.parse())
+                            .collect::<Result<_, _>>()
+                            .map_err(|_| ShellError::Usage(USAGE))?;
+                        let limits = Limits {
+                            block_soft: limits[0],
+                            block_hard: limits[1],
+                            inode_soft: limits[2],
+                            inode_hard: limits[3],
+                        };
+                        self.fs.set_quota(kind, id, limits)?;
+                    }
+                    _ => return Err(ShellError::Usage(USAGE)),
+                }
+            }
+            "chown" => {
+                let Some((uid, paths)) = args.split_first() else {
+                    return Err(ShellError::Usage("chown <用户号> <路径>..."));
+                };
+                let uid = uid
+                    .parse()
+                    .map_err(|_| ShellError::Usage("chown <用户号> <路径>..."))?;
+                for path in required(paths, "chown <用户号> <路径>...")? {
+                    self.fs.set_owner(&self.resolve(path), uid)?;
+                }
+            }
+            "chproj" => {
+                let Some((project, paths)) = args.split_first() else {
+                    return Err(ShellError::Usage("chproj <项目号> <路径>..."));
+                };
+                let project = project
+                    .parse()
+                    .map_err(|_| ShellError::Usage("chproj <项目号> <路径>..."))?;
+                for path in required(paths, "chproj <项目号> <路径>...")? {
+                    self.fs.set_project(&self.resolve(path), project)?;
+                }
+            }
+            "frag" => {
+                let path = match args.first() {
+                    Some(path) => self.resolve(path),
+                    None => String::from("/"),
+                };
+                // 先刷新延迟分配的写入，统计的才是真实的磁盘布局
+                self.fs.sync()?;
+                self.last_sync = Instant::now();
+
+                let report = self.fs.fragmentation(&path)?;
+                let average = if report.files == 0 {
+                    0.0
+                } else {
+                    report.extents as f64 / report.files as f64
+                };
+                println!(
+                    "文件 {} 个，碎片化 {} 个，数据块 {}，区段 {}，平均每个文件 {:.2} 个区段",
+                    report.files, report.fragmented, report.blocks, report.extents, average
+                );
+                println!(
+                    "空闲块 {}，空闲区间 {} 个，最长 {} 块",
+                    report.free_blocks, report.free_runs, report.largest_free_run
+                );
+                for (path, extents) in report.worst.iter() {
+                    println!("  {}: {} 个区段", path, extents);
+                }
+            }
+            "defrag" => {
+                let path = match args.first() {
+                    Some(path) => self.resolve(path),
+                    None => String::from("/"),
+                };
+                let report = self.fs.defrag(&path)?;
+                self.last_sync = Instant::now();
+                for (path, before, after) in report.moved.iter() {
+                    println!("  {}: {} -> {} 个区段", path, before, after);
+                }
+                println!(
+                    "文件 {} 个，整理 {} 个，区段 {} -> {}",
+                    report.files,
+                    report.moved.len(),
+                    report.extents_before,
+                    report.extents_after
+                );
+                if report.skipped > 0 {
+                    println!(
+                        "共享数据块或没有足够长的空闲区间，跳过文件 {} 个",
+                        report.skipped
+                    );
+                }
+            }
+            "scrub" => {
+                // 先写回，检查的才是磁盘上的内容
+                self.fs.sync()?;
+                self.last_sync = Instant::now();
+
+                let report = self.fs.scrub()?;
+                for error in report.errors.iter() {
+                    match error.inode_index {
+                        Some(inode_index) => println!(
+                            "  {} (索引节点 {}): 第{}块，{}",
+                            error.path, inode_index, error.block, error.what
+                        ),
+                        None => println!("  {}: 第{}块，{}", error.path, error.block, error.what),
+                    }
+                }
+                println!(
+                    "检查索引节点 {} 个，数据块 {} 个，发现错误 {} 个",
+                    report.inodes,
+                    report.blocks,
+                    report.errors.len()
+                );
+                if !self.fs.data_checksums() {
+                    println!("未启用数据块校验和，只检查了元数据");
+                }
+                if !report.errors.is_empty() {
+                    return Err(ShellError::CheckFailed(report.errors.len()));
+                }
+            }
+            "df" => {
+                let inodes = match args {
+                    [] => false,
+                    [flag] if flag == "-i" => true,
+                    _ => return Err(ShellError::Usage("df [-i]")),
+                };
+                let statfs = self.fs.statfs();
+                if inodes {
+                    println!(
+                        "索引节点: 总数 {}, 已用 {}, 空闲 {}, 可用 {}",
+                        statfs.total_inodes,
+                        statfs.total_inodes - statfs.free_inodes,
+                        statfs.free_inodes,
+                        statfs.available_inodes
+                    );
+                    if statfs.reserved_percent > 0 {
+                        println!(
+                            "保留索引节点: {} ({}%)，只有 root 可以使用",
+                            statfs.reserved_inodes, statfs.reserved_percent
+                        );
+                    }
+                } else {
+                    println!("块大小: {}B", statfs.block_size);
+                    println!("最长文件名: {}字节", statfs.name_max);
+                    println!(
+                        "数据块: 总数 {}, 已用 {}, 空闲 {}, 可用 {}",
+                        statfs.total_blocks,
+                        statfs.total_blocks - statfs.free_blocks,
+                        statfs.free_blocks,
+                        statfs.available_blocks
+                    );
+                    if statfs.delayed_blocks > 0 {
+                        println!("延迟分配预留: {}", statfs.delayed_blocks);
+                    }
+                    if statfs.reserved_percent > 0 {
+                        println!(
+                            "保留块: {} ({}%)，只有 root 可以使用",
+                            statfs.reserved_blocks, statfs.reserved_percent
+                        );
+                    }
+                }
+                if self.fs.layout.grouped {
+                    for (g, group) in self.fs.group_stats().iter().enumerate() {
+                        if inodes {
+                            println!(
+                                "  块组 {}: 空闲索引节点 {}, 目录 {}",
+                                g, group.free_inodes, group.dirs
+                            );
+                        } else {
+                            println!("  块组 {}: 空闲块 {}", g, group.free_blocks);
+                        }
+                    }
+                }
+                if self.fs.is_read_only() {
+                    println!("挂载方式: 只读");
+                }
+            }
+            "reserve" => {
+                let percent = match args {
+                    [percent] => percent
+                        .trim_end_matches('%')
+                        .parse()
+                        .map_err(|_| ShellError::Usage("reserve <百分比>"))?,
+                    _ => return Err(ShellError::Usage("reserve <百分比>")),
+                };
+                self.fs.set_reserved_percent(percent)?;
+            }
+            "resize" => {
+                let blocks = match args {
+                    [blocks] => blocks
+                        .parse()
+                        .map_err(|_| ShellError::Usage("resize <块数>"))?,
+                    _ => return Err(ShellError::Usage("resize <块数>")),
+                };
+                let total = self.fs.resize(blocks)?;
+                println!(
+                    "已调整为 {} 块（{} 个块组）",
+                    total,
+                    self.fs.layout.groups.len()
+                );
+            }
+            _ => {
+                return Err(ShellError::UnknownCommand(cmd.to_string()));
+            }
+        }
+
+        Ok(Flow::Continue)
+    }
+}
+
+fn required<'a>(args: &'a [String], usage: &'static str) -> Result<&'a [String], ShellError> {
+    if args.is_empty() {
+        return Err(ShellError::Usage(usage));
+    }
+    Ok(args)
+}
+
+fn print_report(report: &TransferReport) {
+    println!(
+        "目录 {} 个，文件 {} 个，共 {}B，跳过 {} 项",
+        report.dirs,
+        report.files,
+        report.bytes,
+        report.skipped.len()
+    );
+    for (path, reason) in report.skipped.iter() {
+        println!("  跳过 {}: {}", path, reason);
+    }
+}
+
+fn kind_arg(arg: &str) -> Option<QuotaKind> {
+    match arg {
+        "-u" => Some(QuotaKind::User),
+        "-p" => Some(QuotaKind::Project),
+        _ => None,
+    }
+}
+
+fn print_quota(kind: QuotaKind, id: u16, entry: &QuotaEntry) {
+    let limit = |x: u32| {
+        if x == 0 {
+            String::from("无")
+        } else {
+            x.to_string()
+        }
+    };
+    let grace = |deadline: i64| match deadline {
+        0 => String::new(),
+        _ if deadline <= chrono::Utc::now().timestamp() => String::from("，宽限期已过"),
+        _ => format!("，宽限期至 {}", format_time(deadline)),
+    };
+    let limits = &entry.limits;
+    println!(
+        "{} {}: 块 {}（软限制 {}，硬限制 {}{}），索引节点 {}（软限制 {}，硬限制 {}{}）",
+        kind.name(),
+        id,
+        entry.blocks,
+        limit(limits.block_soft),
+        limit(limits.block_hard),
+        grace(entry.block_grace),
+        entry.inodes,
+        limit(limits.inode_soft),
+        limit(limits.inode_hard),
+        grace(entry.inode_grace)
+    );
+}
+
+fn format_time(secs: i64) -> String {
+    match chrono::DateTime::from_timestamp(secs, 0) {
+        Some(time) => time
+            .with_timezone(&chrono::Local)
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string(),
+        None => secs.to_string(),
+    }
+}
+
+fn flag<'a>(args: &'a [String], name: &str) -> (bool, &'a [String]) {
+    match args.first() {
+        Some(first) if first == name => (true, &args[1..]),
+        _ => (false, args),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::fs::FormatOptions;
+    use crate::core::hardware::Hardware;
+
+    fn words(text: &str) -> Vec<Vec<String>> {
+        parse(text).unwrap().into_iter().map(|x| x.args).collect()
+    }
+
+    #[test]
+    fn parse_quotes_and_escapes() {
+        assert_eq!(words("echo 'a  b' \"c\\\"d\""), [["echo", "a  b", "c\"d"]]);
+        assert_eq!(
+            words(r#"echo "x\ty\n" 'no\n'"#),
+            [["echo", "x\ty\n", "no\\n"]]
+        );
+        assert_eq!(words(r"touch a\ b"), [["touch", "a b"]]);
+        assert_eq!(words("echo ''"), [["echo", ""]]);
+        assert_eq!(words("echo a''b"), [["echo", "ab"]]);
+    }
+
+    #[test]
+    fn parse_comments_and_separators() {
+        let commands = parse("# 注释\nmkdir a; cd a # 行尾注释\n\nls \\\n  -l\necho a#b").unwrap();
+        let args: Vec<_> = commands.iter().map(|x| x.args.clone()).collect();
+        assert_eq!(
+            args,
+            [
+                vec!["mkdir", "a"],
+                vec!["cd", "a"],
+                vec!["ls", "-l"],
+                vec!["echo", "a#b"]
+            ]
+        );
+        let lines: Vec<_> = commands.iter().map(|x| x.line).collect();
+        assert_eq!(lines, [2, 2, 4, 6]);
+        assert!(parse(" ;; \n").unwrap().is_empty());
+    }
+
+    #[test]
+    fn parse_errors() {
+        for (text, line) in [("echo 'a", 1), ("\necho \"a", 2), ("echo a\\", 1)] {
+            match parse(text) {
+                Err(e @ ShellError::Parse { line: l, .. }) => {
+                    assert_eq!(l, line);
+                    assert_eq!(e.exit_code(), 2);
+                }
+                other => panic!("{:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn exit_codes() {
+        let fs = System::format(Hardware::new(), FormatOptions::default()).unwrap();
+        let mut shell = Shell::new(fs).unwrap();
+        let mut run = |line: &str| {
+            let args = parse(line).unwrap().remove(0).args;
+            shell.execute(&args)
+        };
+
+        assert!(matches!(run("mkdir d"), Ok(Flow::Continue)));
+        let e = run("cat /missing").unwrap_err();
+        assert!(matches!(e, ShellError::Fs(FsError::NotFound(_))));
+        assert_eq!(e.exit_code(), 1);
+        assert_eq!(run("frobnicate").unwrap_err().exit_code(), 2);
+        assert_eq!(run("exit x").unwrap_err().exit_code(), 2);
+        assert!(matches!(run("exit 3"), Ok(Flow::Exit(3))));
+    }
+}